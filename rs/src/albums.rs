@@ -0,0 +1,143 @@
+use super::error::*;
+use super::image_table::{ImageTable, Row};
+use super::query::Query;
+use serde::{Deserialize, Serialize};
+
+/// An album is a collection of photos that is independent of directories. A manual album holds
+/// an ordered list of photos, identified by MD5 so that the album survives photos being moved.
+/// A smart album holds a saved query, and contains the photos that match it.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub enum Album {
+    Manual(Vec<u128>),
+    Smart(String),
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum GalleryKind {
+    Directory,
+    Album,
+    SmartAlbum,
+}
+
+/// An entry in the list of galleries, which has both directory galleries and albums.
+#[derive(Serialize)]
+pub struct GalleryEntry<'a> {
+    pub name: &'a str,
+    pub kind: GalleryKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub query: Option<&'a str>,
+}
+
+impl Album {
+    pub fn kind(&self) -> GalleryKind {
+        return match self {
+            Album::Manual(_) => GalleryKind::Album,
+            Album::Smart(_) => GalleryKind::SmartAlbum,
+        };
+    }
+}
+
+/// The photos in an album. Photos in a manual album that are no longer in the catalog are
+/// skipped: they reappear if the photo is added again. Smart albums are in chronological order.
+pub fn contents<'a>(
+    image_table: &'a ImageTable,
+    album: &Album,
+) -> Result<Vec<&'a Row>, CommandError> {
+    match album {
+        Album::Manual(hashes) => {
+            return Ok(hashes
+                .iter()
+                .filter_map(|hash| image_table.get_by_hash(*hash))
+                .collect());
+        }
+        Album::Smart(query) => {
+            let query = Query::parse(query).map_err(error)?;
+            let mut rows: Vec<&Row> = image_table
+                .rows()
+                .iter()
+                .filter(|row| query.matches(row, &image_table.annotations(row.md5())))
+                .collect();
+            rows.sort_by_key(|row| (row.timestamp(), row.md5()));
+            return Ok(rows);
+        }
+    }
+}
+
+pub fn create(
+    image_table: &mut ImageTable,
+    name: &str,
+    query: Option<&str>,
+) -> Result<(), CommandError> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(error("album name is empty"));
+    }
+    if image_table.albums.contains_key(name) {
+        return Err(error(format!("album {} already exists", name)));
+    }
+    let album = match query {
+        None => Album::Manual(vec![]),
+        Some(query) => {
+            Query::parse(query).map_err(error)?;
+            Album::Smart(query.to_string())
+        }
+    };
+    image_table.albums.insert(name.to_string(), album);
+    return Ok(());
+}
+
+pub fn delete(image_table: &mut ImageTable, name: &str) -> Result<(), CommandError> {
+    return image_table
+        .albums
+        .remove(name)
+        .map(|_| ())
+        .ok_or_else(|| error(format!("no album named {}", name)));
+}
+
+fn manual_album<'a>(
+    image_table: &'a mut ImageTable,
+    name: &str,
+) -> Result<&'a mut Vec<u128>, CommandError> {
+    match image_table.albums.get_mut(name) {
+        None => return Err(error(format!("no album named {}", name))),
+        Some(Album::Smart(_)) => {
+            return Err(error(format!(
+                "{} is a smart album, and its photos are chosen by its query",
+                name
+            )));
+        }
+        Some(Album::Manual(hashes)) => return Ok(hashes),
+    }
+}
+
+/// Appends photos to the end of a manual album. Photos that are already in the album stay where
+/// they are.
+pub fn add_photos(
+    image_table: &mut ImageTable,
+    name: &str,
+    photos: &[u128],
+) -> Result<(), CommandError> {
+    for hash in photos.iter() {
+        if image_table.get_by_hash(*hash).is_none() {
+            return Err(error(format!("{:x} is not in database", hash)));
+        }
+    }
+    let hashes = manual_album(image_table, name)?;
+    for hash in photos.iter() {
+        if hashes.contains(hash) == false {
+            hashes.push(*hash);
+        }
+    }
+    return Ok(());
+}
+
+pub fn remove_photos(
+    image_table: &mut ImageTable,
+    name: &str,
+    photos: &[u128],
+) -> Result<(), CommandError> {
+    let hashes = manual_album(image_table, name)?;
+    hashes.retain(|hash| photos.contains(hash) == false);
+    return Ok(());
+}
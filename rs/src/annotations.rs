@@ -0,0 +1,106 @@
+use super::xmp::Description;
+use serde::{Deserialize, Serialize};
+
+/// Tags, rating, favorite flag and caption that users attach to photos in spg. SPG never writes
+/// to originals, so these live in the catalog.
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq)]
+pub struct Annotations {
+    pub tags: Vec<String>,
+    /// From 0 (unrated) to 5.
+    pub rating: u8,
+    pub favorite: bool,
+    pub caption: Option<String>,
+}
+
+/// A change to the annotations of a photo, as sent to the API. Fields that are missing are left
+/// unchanged. An empty caption removes the caption.
+#[derive(Deserialize, Default)]
+pub struct AnnotationEdit {
+    pub tags: Option<Vec<String>>,
+    pub add_tags: Option<Vec<String>>,
+    pub remove_tags: Option<Vec<String>>,
+    pub rating: Option<u8>,
+    pub favorite: Option<bool>,
+    pub caption: Option<String>,
+}
+
+pub const MAX_RATING: u8 = 5;
+
+impl Annotations {
+    pub fn is_empty(&self) -> bool {
+        return *self == Annotations::default();
+    }
+
+    pub fn add_tag(&mut self, tag: &str) {
+        let tag = tag.trim();
+        if tag.is_empty() == false && self.tags.iter().any(|t| t == tag) == false {
+            self.tags.push(tag.to_string());
+        }
+    }
+
+    pub fn remove_tag(&mut self, tag: &str) {
+        self.tags.retain(|t| t != tag.trim());
+    }
+
+    /// Applies `edit`, or returns an error message if the edit is invalid. Nothing is changed
+    /// if the edit is invalid.
+    pub fn apply(&mut self, edit: AnnotationEdit) -> Result<(), String> {
+        if let Some(rating) = edit.rating {
+            if rating > MAX_RATING {
+                return Err(format!("rating must be between 0 and {}", MAX_RATING));
+            }
+            self.rating = rating;
+        }
+        if let Some(tags) = edit.tags {
+            self.tags.clear();
+            for tag in tags.iter() {
+                self.add_tag(tag);
+            }
+        }
+        for tag in edit.add_tags.unwrap_or_default().iter() {
+            self.add_tag(tag);
+        }
+        for tag in edit.remove_tags.unwrap_or_default().iter() {
+            self.remove_tag(tag);
+        }
+        if let Some(favorite) = edit.favorite {
+            self.favorite = favorite;
+        }
+        if let Some(caption) = edit.caption {
+            let caption = caption.trim();
+            self.caption = if caption.is_empty() {
+                None
+            } else {
+                Some(caption.to_string())
+            };
+        }
+        return Ok(());
+    }
+
+    /// The rating of a photo in spg or, when it has none, the rating that an editor such as
+    /// Lightroom gave it in `description`. Photos that Lightroom rejected (-1) are unrated.
+    pub fn rating_or(&self, description: &Description) -> u8 {
+        if self.rating > 0 {
+            return self.rating;
+        }
+        return description.rating.unwrap_or(0).clamp(0, MAX_RATING as i8) as u8;
+    }
+
+    /// Describes the annotations that are set, one per line.
+    pub fn describe(&self) -> Vec<String> {
+        let mut lines = vec![];
+        if self.tags.is_empty() == false {
+            lines.push(format!("Tags: {}", self.tags.join(", ")));
+        }
+        if self.rating > 0 {
+            lines.push(format!("Stars: {}", self.rating));
+        }
+        if self.favorite {
+            lines.push(String::from("Favorite"));
+        }
+        if let Some(caption) = &self.caption {
+            lines.push(format!("User caption: {}", caption));
+        }
+        return lines;
+    }
+}
@@ -0,0 +1,502 @@
+use super::config::Config;
+use super::error::*;
+use super::image_table::Row;
+use super::share::Share;
+use rand::Rng;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use std::process;
+use std::sync::{Arc, OnceLock};
+
+/// The name of the cookie that holds the session token.
+pub const SESSION_COOKIE: &'static str = "spg_session";
+
+#[derive(Serialize, Deserialize)]
+pub struct User {
+    /// An Argon2 hash in PHC string format, which includes its salt and parameters.
+    pub password_hash: String,
+}
+
+/// The users who may log in to the web server, and what they may see, in `users.json` in the
+/// data directory. When there are no users, the web server does not require logging in.
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct Users {
+    pub users: BTreeMap<String, User>,
+    /// The members of each group.
+    pub groups: BTreeMap<String, BTreeSet<String>>,
+    /// The users and groups (written `@group`) who may see the photos in each directory, by
+    /// absolute path. The longest directory that contains a photo decides who may see it, and
+    /// every user may see photos that are not in any of these directories.
+    pub acls: BTreeMap<String, BTreeSet<String>>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Session {
+    /// Identifies the session on the command line. Unlike the token, it is not a secret.
+    pub id: String,
+    pub user: String,
+    /// Seconds since the Unix epoch.
+    pub created: i64,
+    pub expires: i64,
+}
+
+/// Sessions by token, in `sessions.json` in the data directory. Both the web server and the
+/// command line modify this file.
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct Sessions {
+    pub sessions: BTreeMap<String, Session>,
+}
+
+pub(crate) fn random_string(bytes: usize) -> String {
+    let bytes: Vec<u8> = (0..bytes).map(|_| rand::thread_rng().gen()).collect();
+    return base64::encode_config(bytes, base64::URL_SAFE_NO_PAD);
+}
+
+pub(crate) fn now() -> i64 {
+    return chrono::Utc::now().timestamp();
+}
+
+fn hash_password(password: &str) -> Result<String, CommandError> {
+    let salt: [u8; 16] = rand::thread_rng().gen();
+    let config = argon2::Config {
+        variant: argon2::Variant::Argon2id,
+        ..argon2::Config::default()
+    };
+    return argon2::hash_encoded(password.as_bytes(), &salt, &config)
+        .map_err(|err| error(format!("hashing password: {}", err)));
+}
+
+/// The hash of a random password, with the same parameters as the hashes of real passwords.
+fn dummy_hash() -> &'static str {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    return DUMMY_HASH.get_or_init(|| {
+        return hash_password(&random_string(16)).expect("hashing the dummy password");
+    });
+}
+
+/// Reads a JSON file. A missing file is the same as an empty one.
+pub(crate) fn open_json<T: DeserializeOwned + Default>(path: &str) -> Result<T, CommandError> {
+    if Path::new(path).exists() == false {
+        return Ok(T::default());
+    }
+    let bytes = std::fs::read(path)?;
+    return serde_json::from_slice(&bytes)
+        .map_err(|err| error(format!("Could not read {}.\n{}", path, err)));
+}
+
+/// Writes a JSON file by renaming a temporary file, so that the server never reads a partially
+/// written file.
+pub(crate) fn save_json<T: Serialize>(path: &str, value: &T) -> Result<(), CommandError> {
+    let temp_path = format!("{}.tmp", path);
+    std::fs::write(&temp_path, serde_json::to_string_pretty(value).unwrap())?;
+    std::fs::rename(&temp_path, path)?;
+    return Ok(());
+}
+
+/// An advisory lock on a JSON file in the data directory, such as `sessions.json`. The command
+/// line and the server take it before they read the file to change it, and hold it until they save
+/// it, just as they do with the `CatalogLock`. Dropping the lock releases it.
+pub struct JsonLock {
+    _file: std::fs::File,
+}
+
+impl JsonLock {
+    /// Waits for the lock on the file at `path`, which is held on `{path}.lock`.
+    pub fn acquire(path: &str) -> Result<JsonLock, CommandError> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(format!("{}.lock", path))
+            .map_err(trace(format!("opening the lock of {}", path)))?;
+        file.lock()?;
+        return Ok(JsonLock { _file: file });
+    }
+}
+
+/// What a request to the web server may see.
+#[derive(Clone)]
+pub enum Access {
+    /// A user who is logged in, or anyone when there are no users.
+    Everything,
+    /// Someone who opened a share link. They may only read what it shares.
+    Share(Share),
+    /// A user whom an ACL keeps from seeing some photos. Like a share, they may only read the
+    /// photos that they may see.
+    User(String, Arc<Users>),
+}
+
+impl Access {
+    pub fn allows(&self, row: &Row) -> bool {
+        return match self {
+            Access::Everything => true,
+            Access::Share(share) => share.scope.includes(row),
+            Access::User(user, users) => users.allows(user, &row.original_path),
+        };
+    }
+
+    pub fn allows_originals(&self) -> bool {
+        return match self {
+            Access::Everything => true,
+            Access::Share(share) => share.originals,
+            Access::User(_, _) => true,
+        };
+    }
+}
+
+impl Users {
+    pub fn open(path: &str) -> Result<Users, CommandError> {
+        return open_json(path);
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), CommandError> {
+        return save_json(path, self);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        return self.users.is_empty() == false;
+    }
+
+    /// Adds a user, or changes the password of an existing user.
+    pub fn set_password(&mut self, name: &str, password: &str) -> Result<(), CommandError> {
+        let password_hash = hash_password(password)?;
+        self.users.insert(name.to_string(), User { password_hash });
+        return Ok(());
+    }
+
+    /// The user's name, and the names of their groups prefixed with `@`.
+    fn principals(&self, user: &str) -> BTreeSet<String> {
+        let mut principals: BTreeSet<String> = self
+            .groups
+            .iter()
+            .filter(|(_, members)| members.contains(user))
+            .map(|(group, _)| format!("@{}", group))
+            .collect();
+        principals.insert(user.to_string());
+        return principals;
+    }
+
+    /// Whether `user` may see the photo at `path`.
+    pub fn allows(&self, user: &str, path: &str) -> bool {
+        let acl = self
+            .acls
+            .iter()
+            .filter(|(directory, _)| Path::new(path).starts_with(directory))
+            .max_by_key(|(directory, _)| directory.len());
+        return match acl {
+            None => true,
+            Some((_, allowed)) => self.principals(user).is_disjoint(allowed) == false,
+        };
+    }
+
+    /// Whether no ACL keeps `user` from seeing any photo.
+    pub fn sees_everything(&self, user: &str) -> bool {
+        let principals = self.principals(user);
+        return self
+            .acls
+            .values()
+            .all(|allowed| principals.is_disjoint(allowed) == false);
+    }
+
+    /// Whether `password` is the password of the user `name`. This is slow on purpose, so the
+    /// server must not call it on the runtime. We check the password of a user who does not exist
+    /// against a dummy hash, so that it takes just as long, and does not reveal who exists.
+    pub fn verify(&self, name: &str, password: &str) -> bool {
+        let (password_hash, exists) = match self.users.get(name) {
+            Some(user) => (user.password_hash.as_str(), true),
+            None => (dummy_hash(), false),
+        };
+        let matches = argon2::verify_encoded(password_hash, password.as_bytes()).unwrap_or(false);
+        return exists && matches;
+    }
+}
+
+impl Sessions {
+    pub fn open(path: &str) -> Result<Sessions, CommandError> {
+        return open_json(path);
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), CommandError> {
+        return save_json(path, self);
+    }
+
+    /// Starts a session for `user` that lasts `days` days, and returns its token. We also forget
+    /// sessions that have expired, so that the file does not grow forever.
+    pub fn create(&mut self, user: &str, days: u32) -> String {
+        let now = now();
+        self.sessions.retain(|_, session| session.expires > now);
+        let token = random_string(32);
+        let session = Session {
+            id: random_string(6),
+            user: user.to_string(),
+            created: now,
+            expires: now + i64::from(days) * 24 * 60 * 60,
+        };
+        self.sessions.insert(token.clone(), session);
+        return token;
+    }
+
+    /// The session with this token, unless it has expired.
+    pub fn get(&self, token: &str) -> Option<&Session> {
+        return self
+            .sessions
+            .get(token)
+            .filter(|session| session.expires > now());
+    }
+
+    pub fn remove(&mut self, token: &str) {
+        self.sessions.remove(token);
+    }
+
+    /// Removes the sessions for which `f` returns true, and returns how many it removed.
+    pub fn revoke(&mut self, f: impl Fn(&Session) -> bool) -> usize {
+        let before = self.sessions.len();
+        self.sessions.retain(|_, session| f(session) == false);
+        return before - self.sessions.len();
+    }
+}
+
+/// Changes to users from the command line.
+pub enum UserCommand {
+    /// Adds a user, or changes their password. Reads the password from standard input when it
+    /// is not given.
+    Add(String, Option<String>),
+    /// Removes a user, and ends their sessions.
+    Remove(String),
+    List,
+}
+
+/// Changes to groups from the command line.
+pub enum GroupCommand {
+    /// Adds users to a group, creating it if necessary.
+    Add(String, Vec<String>),
+    /// Removes users from a group, or the whole group when no users are given.
+    Remove(String, Vec<String>),
+    List,
+}
+
+/// Changes to ACLs from the command line. Principals are user names, or group names prefixed
+/// with `@`.
+pub enum AclCommand {
+    /// Allows principals to see the photos in a directory.
+    Add(String, Vec<String>),
+    /// Removes principals from the ACL of a directory, or the whole ACL when no principals are
+    /// given.
+    Remove(String, Vec<String>),
+    List,
+}
+
+/// Which sessions to revoke from the command line.
+pub enum Revoke {
+    Id(String),
+    User(String),
+    All,
+}
+
+pub enum SessionCommand {
+    List,
+    Revoke(Revoke),
+}
+
+fn read_password() -> Result<String, CommandError> {
+    let mut password = String::new();
+    std::io::stdin()
+        .read_line(&mut password)
+        .map_err(trace("reading password"))?;
+    let password = password.trim_end_matches(&['\r', '\n'][..]).to_string();
+    if password.is_empty() {
+        return Err(error("the password is empty"));
+    }
+    return Ok(password);
+}
+
+fn user_command_(config: &Config, command: UserCommand) -> Result<(), CommandError> {
+    let mut users = Users::open(&config.users_path)?;
+    match command {
+        UserCommand::Add(name, password) => {
+            let password = match password {
+                Some(password) => password,
+                None => read_password()?,
+            };
+            users.set_password(&name, &password)?;
+            users.save(&config.users_path)?;
+        }
+        UserCommand::Remove(name) => {
+            if users.users.remove(&name).is_none() {
+                return Err(error(format!("no user named {}", name)));
+            }
+            for members in users.groups.values_mut() {
+                members.remove(&name);
+            }
+            for allowed in users.acls.values_mut() {
+                allowed.remove(&name);
+            }
+            users.save(&config.users_path)?;
+            let _lock = JsonLock::acquire(&config.sessions_path)?;
+            let mut sessions = Sessions::open(&config.sessions_path)?;
+            sessions.revoke(|session| session.user == name);
+            sessions.save(&config.sessions_path)?;
+        }
+        UserCommand::List => {
+            for name in users.users.keys() {
+                println!("{}", name);
+            }
+        }
+    }
+    return Ok(());
+}
+
+pub fn user_command(config: &Config, command: UserCommand) {
+    if let Err(err) = user_command_(config, command) {
+        eprintln!("{}\n\nError updating users", err);
+        process::exit(1);
+    }
+}
+
+fn group_command_(config: &Config, command: GroupCommand) -> Result<(), CommandError> {
+    let mut users = Users::open(&config.users_path)?;
+    match command {
+        GroupCommand::Add(group, members) => {
+            if let Some(member) = members
+                .iter()
+                .find(|m| users.users.contains_key(*m) == false)
+            {
+                return Err(error(format!("no user named {}", member)));
+            }
+            users.groups.entry(group).or_default().extend(members);
+        }
+        GroupCommand::Remove(group, members) => {
+            let group_members = users
+                .groups
+                .get_mut(&group)
+                .ok_or_else(|| error(format!("no group named {}", group)))?;
+            if members.is_empty() {
+                users.groups.remove(&group);
+            } else {
+                for member in members {
+                    group_members.remove(&member);
+                }
+            }
+        }
+        GroupCommand::List => {
+            for (group, members) in users.groups.iter() {
+                let members: Vec<&str> = members.iter().map(|m| m.as_str()).collect();
+                println!("{}\t{}", group, members.join(" "));
+            }
+            return Ok(());
+        }
+    }
+    users.save(&config.users_path)?;
+    return Ok(());
+}
+
+pub fn group_command(config: &Config, command: GroupCommand) {
+    if let Err(err) = group_command_(config, command) {
+        eprintln!("{}\n\nError updating groups", err);
+        process::exit(1);
+    }
+}
+
+/// ACLs name directories by absolute path, so that they match the paths of photos.
+fn acl_directory(directory: &str) -> Result<String, CommandError> {
+    let path = Path::new(directory)
+        .canonicalize()
+        .map_err(trace(format!("finding {}", directory)))?;
+    return Ok(path.to_string_lossy().to_string());
+}
+
+fn acl_command_(config: &Config, command: AclCommand) -> Result<(), CommandError> {
+    let mut users = Users::open(&config.users_path)?;
+    match command {
+        AclCommand::Add(directory, principals) => {
+            for principal in principals.iter() {
+                let exists = match principal.strip_prefix('@') {
+                    Some(group) => users.groups.contains_key(group),
+                    None => users.users.contains_key(principal),
+                };
+                if exists == false {
+                    return Err(error(format!("no user or group named {}", principal)));
+                }
+            }
+            let directory = acl_directory(&directory)?;
+            users.acls.entry(directory).or_default().extend(principals);
+        }
+        AclCommand::Remove(directory, principals) => {
+            // The directory may have been deleted since.
+            let directory = acl_directory(&directory).unwrap_or(directory);
+            let allowed = users
+                .acls
+                .get_mut(&directory)
+                .ok_or_else(|| error(format!("no ACL for {}", directory)))?;
+            if principals.is_empty() {
+                users.acls.remove(&directory);
+            } else {
+                for principal in principals {
+                    allowed.remove(&principal);
+                }
+            }
+        }
+        AclCommand::List => {
+            for (directory, allowed) in users.acls.iter() {
+                let allowed: Vec<&str> = allowed.iter().map(|p| p.as_str()).collect();
+                println!("{}\t{}", directory, allowed.join(" "));
+            }
+            return Ok(());
+        }
+    }
+    users.save(&config.users_path)?;
+    return Ok(());
+}
+
+pub fn acl_command(config: &Config, command: AclCommand) {
+    if let Err(err) = acl_command_(config, command) {
+        eprintln!("{}\n\nError updating ACLs", err);
+        process::exit(1);
+    }
+}
+
+fn session_command_(config: &Config, command: SessionCommand) -> Result<(), CommandError> {
+    let _lock = JsonLock::acquire(&config.sessions_path)?;
+    let mut sessions = Sessions::open(&config.sessions_path)?;
+    match command {
+        SessionCommand::List => {
+            let now = now();
+            for session in sessions.sessions.values() {
+                if session.expires <= now {
+                    continue;
+                }
+                let expires = chrono::DateTime::from_timestamp(session.expires, 0)
+                    .map(|expires| expires.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+                    .unwrap_or_default();
+                println!("{}\t{}\texpires {}", session.id, session.user, expires);
+            }
+        }
+        SessionCommand::Revoke(revoke) => {
+            let count = match revoke {
+                Revoke::Id(id) => {
+                    let count = sessions.revoke(|session| session.id == id);
+                    if count == 0 {
+                        return Err(error(format!("no session with ID {}", id)));
+                    }
+                    count
+                }
+                Revoke::User(user) => sessions.revoke(|session| session.user == user),
+                Revoke::All => sessions.revoke(|_| true),
+            };
+            sessions.save(&config.sessions_path)?;
+            println!("Revoked {} session(s)", count);
+        }
+    }
+    return Ok(());
+}
+
+pub fn session_command(config: &Config, command: SessionCommand) {
+    if let Err(err) = session_command_(config, command) {
+        eprintln!("{}\n\nError updating sessions", err);
+        process::exit(1);
+    }
+}
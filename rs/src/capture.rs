@@ -0,0 +1,213 @@
+use chrono::{NaiveDate, NaiveDateTime};
+use exif::{Exif, In, Tag, Value};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// When a photo was taken, as recorded by the camera. Cameras record the local time, and newer
+/// cameras also record the offset of the local time zone from UTC.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CaptureTime {
+    pub local: NaiveDateTime,
+    /// Offset from UTC in minutes, if the camera recorded it.
+    pub offset_minutes: Option<i16>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct GpsPosition {
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Meters above sea level.
+    pub altitude: Option<f64>,
+}
+
+/// The capture metadata in the EXIF data of an image. Every field is optional, since cameras
+/// differ in what they record, and edited or scanned images often have no EXIF data at all.
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct CaptureMetadata {
+    pub taken: Option<CaptureTime>,
+    pub make: Option<String>,
+    pub model: Option<String>,
+    pub lens: Option<String>,
+    /// Focal length in millimeters.
+    pub focal_length: Option<f64>,
+    /// The f-number.
+    pub aperture: Option<f64>,
+    /// Shutter speed in seconds.
+    pub exposure_time: Option<f64>,
+    pub iso: Option<u32>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub gps: Option<GpsPosition>,
+}
+
+impl CaptureTime {
+    /// Seconds since the Unix epoch. When the camera did not record a time zone, we treat the
+    /// local time as UTC, which is good enough to order photos.
+    pub fn timestamp(&self) -> i64 {
+        let offset_seconds = self.offset_minutes.unwrap_or(0) as i64 * 60;
+        return self.local.and_utc().timestamp() - offset_seconds;
+    }
+}
+
+impl fmt::Display for CaptureTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.local.format("%Y-%m-%d %H:%M:%S"))?;
+        if let Some(offset) = self.offset_minutes {
+            let sign = if offset < 0 { '-' } else { '+' };
+            let offset = offset.abs();
+            write!(f, " {}{:02}:{:02}", sign, offset / 60, offset % 60)?;
+        }
+        return Ok(());
+    }
+}
+
+fn ascii(exif: &Exif, tag: Tag) -> Option<String> {
+    match exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Ascii(ref v) if !v.is_empty() => {
+            let s = String::from_utf8_lossy(&v[0]);
+            let s = s.trim_end_matches('\0').trim();
+            if s.is_empty() {
+                return None;
+            }
+            return Some(s.to_string());
+        }
+        _ => return None,
+    }
+}
+
+fn rational(exif: &Exif, tag: Tag, index: usize) -> Option<f64> {
+    match exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Rational(ref v) if v.len() > index && v[index].denom != 0 => {
+            return Some(v[index].to_f64());
+        }
+        _ => return None,
+    }
+}
+
+fn uint(exif: &Exif, tag: Tag) -> Option<u32> {
+    return exif.get_field(tag, In::PRIMARY)?.value.get_uint(0);
+}
+
+/// Reads a date and time, along with its time zone offset if there is one.
+fn date_time(exif: &Exif, tag: Tag, offset_tag: Tag) -> Option<CaptureTime> {
+    let date_time = match exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Ascii(ref v) if !v.is_empty() => exif::DateTime::from_ascii(&v[0]).ok()?,
+        _ => return None,
+    };
+    let local = NaiveDate::from_ymd_opt(
+        date_time.year as i32,
+        date_time.month as u32,
+        date_time.day as u32,
+    )?
+    .and_hms_opt(
+        date_time.hour as u32,
+        date_time.minute as u32,
+        date_time.second as u32,
+    )?;
+    let offset_minutes = match exif.get_field(offset_tag, In::PRIMARY).map(|f| &f.value) {
+        Some(Value::Ascii(ref v)) if !v.is_empty() => {
+            let mut date_time = date_time;
+            date_time.parse_offset(&v[0]).ok().and(date_time.offset)
+        }
+        _ => None,
+    };
+    return Some(CaptureTime {
+        local,
+        offset_minutes,
+    });
+}
+
+/// Degrees, minutes and seconds to decimal degrees, which are negative in the southern and
+/// western hemispheres.
+fn gps_coordinate(exif: &Exif, tag: Tag, ref_tag: Tag, negative_ref: &str) -> Option<f64> {
+    let degrees = rational(exif, tag, 0)?;
+    let minutes = rational(exif, tag, 1).unwrap_or(0.0);
+    let seconds = rational(exif, tag, 2).unwrap_or(0.0);
+    let value = degrees + minutes / 60.0 + seconds / 3600.0;
+    if ascii(exif, ref_tag).as_deref() == Some(negative_ref) {
+        return Some(-value);
+    }
+    return Some(value);
+}
+
+fn gps_position(exif: &Exif) -> Option<GpsPosition> {
+    let latitude = gps_coordinate(exif, Tag::GPSLatitude, Tag::GPSLatitudeRef, "S")?;
+    let longitude = gps_coordinate(exif, Tag::GPSLongitude, Tag::GPSLongitudeRef, "W")?;
+    // An altitude reference of 1 means below sea level.
+    let altitude = rational(exif, Tag::GPSAltitude, 0).map(|altitude| {
+        if uint(exif, Tag::GPSAltitudeRef) == Some(1) {
+            -altitude
+        } else {
+            altitude
+        }
+    });
+    return Some(GpsPosition {
+        latitude,
+        longitude,
+        altitude,
+    });
+}
+
+impl CaptureMetadata {
+    pub fn new(exif: &Exif) -> Self {
+        let taken = date_time(exif, Tag::DateTimeOriginal, Tag::OffsetTimeOriginal)
+            .or_else(|| date_time(exif, Tag::DateTime, Tag::OffsetTime));
+        return CaptureMetadata {
+            taken,
+            make: ascii(exif, Tag::Make),
+            model: ascii(exif, Tag::Model),
+            lens: ascii(exif, Tag::LensModel),
+            focal_length: rational(exif, Tag::FocalLength, 0),
+            aperture: rational(exif, Tag::FNumber, 0),
+            exposure_time: rational(exif, Tag::ExposureTime, 0),
+            iso: uint(exif, Tag::PhotographicSensitivity),
+            width: uint(exif, Tag::PixelXDimension).or_else(|| uint(exif, Tag::ImageWidth)),
+            height: uint(exif, Tag::PixelYDimension).or_else(|| uint(exif, Tag::ImageLength)),
+            gps: gps_position(exif),
+        };
+    }
+
+    /// Describes the metadata that is present, one field per line.
+    pub fn describe(&self) -> Vec<String> {
+        let mut lines = vec![];
+        if let Some(taken) = &self.taken {
+            lines.push(format!("Taken: {}", taken));
+        }
+        let camera: Vec<&str> = vec![&self.make, &self.model]
+            .into_iter()
+            .filter_map(|s| s.as_deref())
+            .collect();
+        if !camera.is_empty() {
+            lines.push(format!("Camera: {}", camera.join(" ")));
+        }
+        if let Some(lens) = &self.lens {
+            lines.push(format!("Lens: {}", lens));
+        }
+        if let Some(focal_length) = self.focal_length {
+            lines.push(format!("Focal length: {} mm", focal_length));
+        }
+        if let Some(aperture) = self.aperture {
+            lines.push(format!("Aperture: f/{:.1}", aperture));
+        }
+        if let Some(exposure_time) = self.exposure_time {
+            if exposure_time > 0.0 && exposure_time < 1.0 {
+                lines.push(format!(
+                    "Shutter speed: 1/{} s",
+                    (1.0 / exposure_time).round()
+                ));
+            } else {
+                lines.push(format!("Shutter speed: {} s", exposure_time));
+            }
+        }
+        if let Some(iso) = self.iso {
+            lines.push(format!("ISO: {}", iso));
+        }
+        if let (Some(width), Some(height)) = (self.width, self.height) {
+            lines.push(format!("Dimensions: {}x{}", width, height));
+        }
+        if let Some(gps) = &self.gps {
+            lines.push(format!("GPS: {:.6}, {:.6}", gps.latitude, gps.longitude));
+        }
+        return lines;
+    }
+}
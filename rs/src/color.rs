@@ -0,0 +1,60 @@
+use image::DynamicImage;
+use img_parts::jpeg::Jpeg;
+use img_parts::ImageICC;
+use qcms::{DataType, Intent, Profile, Transform};
+use std::fs;
+use std::path::Path;
+
+/// Reads the ICC profile embedded in a JPEG, if there is one. We only need to support JPEGs:
+/// HEIC images are converted to JPEG by heif-convert, which copies the profile of the HEIC
+/// into the JPEG that it produces.
+fn read_icc_profile(p: impl AsRef<Path>) -> Option<Vec<u8>> {
+    let bytes = fs::read(p).ok()?;
+    let jpeg = Jpeg::from_bytes(bytes.into()).ok()?;
+    return jpeg.icc_profile().map(|icc| icc.to_vec());
+}
+
+/// Cameras and iPhones often produce images in Display P3 or Adobe RGB, and embed an ICC profile
+/// that says so. The image library ignores the profile, and our derivatives are saved without one,
+/// so browsers assume sRGB and the colors look wrong. This function converts the pixels of `image`
+/// to sRGB using the profile embedded in the file at `p`. If there is no profile, or the profile
+/// is already sRGB, the image is returned unchanged.
+pub fn convert_to_srgb(image: DynamicImage, p: impl AsRef<Path>) -> DynamicImage {
+    let icc = match read_icc_profile(&p) {
+        None => return image,
+        Some(icc) => icc,
+    };
+    let source_profile = match Profile::new_from_slice(&icc, false) {
+        None => {
+            eprintln!(
+                "Ignoring unreadable ICC profile in {}",
+                p.as_ref().display()
+            );
+            return image;
+        }
+        Some(profile) => profile,
+    };
+    if source_profile.is_sRGB() {
+        return image;
+    }
+    let mut srgb_profile = Profile::new_sRGB();
+    srgb_profile.precache_output_transform();
+    let transform = match Transform::new(
+        &source_profile,
+        &srgb_profile,
+        DataType::RGB8,
+        Intent::default(),
+    ) {
+        None => {
+            eprintln!(
+                "Cannot convert the ICC profile in {} to sRGB",
+                p.as_ref().display()
+            );
+            return image;
+        }
+        Some(transform) => transform,
+    };
+    let mut rgb = image.to_rgb8();
+    transform.apply(&mut rgb);
+    return DynamicImage::ImageRgb8(rgb);
+}
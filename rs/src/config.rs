@@ -1,14 +1,93 @@
+use super::metadata::MetadataPolicy;
+use serde::{Deserialize, Serialize};
+use std::process;
+
+/// Settings that the user may edit in `settings.json` in the data directory. Every setting has a
+/// default, so the file may be missing or only mention some of them.
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// The metadata that derivatives keep from their original. The photo map is only served with
+    /// `KeepAll`, since it shows where photos were taken.
+    pub metadata_policy: MetadataPolicy,
+    /// Apply `metadata_policy` to originals downloaded from the web server too, which is the
+    /// default. The original file is never modified.
+    pub strip_originals: bool,
+    /// How long a web server login lasts, in days.
+    pub session_days: u32,
+    /// Only send the session cookie over HTTPS. Disable this to log in to a server that is only
+    /// reachable over plain HTTP.
+    pub secure_cookies: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        return Settings {
+            metadata_policy: MetadataPolicy::default(),
+            strip_originals: true,
+            session_days: 30,
+            secure_cookies: true,
+        };
+    }
+}
+
 pub struct Config {
     pub data_dir: String,
     pub image_table_path: String,
+    pub catalog_lock_path: String,
+    pub users_path: String,
+    pub sessions_path: String,
+    pub shares_path: String,
+    pub settings: Settings,
+}
+
+impl Settings {
+    pub fn try_open(path: &str) -> Result<Settings, String> {
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(_) => return Ok(Settings::default()),
+        };
+        return serde_json::from_slice(&bytes)
+            .map_err(|err| format!("Could not read {}.\n{}", path, err));
+    }
+
+    pub fn open(path: &str) -> Settings {
+        match Settings::try_open(path) {
+            Ok(settings) => return settings,
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+        }
+    }
 }
 
 impl Config {
     pub fn new(data_dir: String) -> Config {
+        let settings = Settings::open(&format!("{}/settings.json", &data_dir));
+        return Config::with_settings(data_dir, settings);
+    }
+
+    /// Like `new`, but returns an error instead of exiting when the settings are invalid.
+    pub fn try_new(data_dir: String) -> Result<Config, String> {
+        let settings = Settings::try_open(&format!("{}/settings.json", &data_dir))?;
+        return Ok(Config::with_settings(data_dir, settings));
+    }
+
+    fn with_settings(data_dir: String, settings: Settings) -> Config {
         let image_table_path = format!("{}/image_table.bincode", &data_dir);
+        let catalog_lock_path = format!("{}/image_table.lock", &data_dir);
+        let users_path = format!("{}/users.json", &data_dir);
+        let sessions_path = format!("{}/sessions.json", &data_dir);
+        let shares_path = format!("{}/shares.json", &data_dir);
         return Config {
             data_dir,
             image_table_path,
+            catalog_lock_path,
+            users_path,
+            sessions_path,
+            shares_path,
+            settings,
         };
     }
 }
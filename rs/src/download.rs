@@ -0,0 +1,190 @@
+use futures::prelude::*;
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncReadExt;
+use warp::hyper::body::{Body, Bytes};
+use warp::reply::Response;
+
+/// The bytes that we send in each chunk of a file.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The characters that may appear unencoded in an RFC 5987 `filename*` parameter.
+const ATTR_CHAR: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'!')
+    .remove(b'#')
+    .remove(b'$')
+    .remove(b'&')
+    .remove(b'+')
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'^')
+    .remove(b'_')
+    .remove(b'`')
+    .remove(b'|')
+    .remove(b'~');
+
+/// Where the bytes of a download come from.
+pub enum Source {
+    /// A file, which we stream from disk.
+    File(PathBuf),
+    /// Bytes in memory, e.g., a copy of an original that was stripped of metadata.
+    Memory(Vec<u8>),
+}
+
+/// A file for the browser to save, rather than show.
+pub struct Download {
+    pub source: Source,
+    /// The name to save the file as, which also determines its MIME type. We only send its
+    /// basename.
+    pub filename: String,
+    /// A strong ETag, which changes whenever the bytes do.
+    pub etag: String,
+}
+
+/// The part of a download that a `Range` header asks for.
+enum Range {
+    /// No header, or one that we ignore, as RFC 7233 allows for several ranges.
+    Full,
+    /// The first and last byte, inclusive.
+    Partial(u64, u64),
+    /// A range that starts after the end of the download.
+    Unsatisfiable,
+}
+
+/// Parses a `Range` header for a download of `length` bytes.
+fn parse_range(header: Option<&str>, length: u64) -> Range {
+    let spec = match header.and_then(|header| header.trim().strip_prefix("bytes=")) {
+        Some(spec) if spec.contains(',') == false => spec.trim(),
+        _ => return Range::Full,
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(bounds) => bounds,
+        None => return Range::Full,
+    };
+    let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(length.saturating_sub(1))),
+        (Ok(start), Err(_)) if end.is_empty() => (start, length.saturating_sub(1)),
+        // The last `end` bytes.
+        (Err(_), Ok(end)) if start.is_empty() && end > 0 => {
+            (length.saturating_sub(end), length.saturating_sub(1))
+        }
+        _ => return Range::Full,
+    };
+    if start >= length {
+        return Range::Unsatisfiable;
+    }
+    return Range::Partial(start, end);
+}
+
+/// A `Content-Disposition` header that saves the download under the basename of `filename`.
+/// Old browsers read `filename`, which only has ASCII, and the others read `filename*`, which
+/// RFC 6266 lets us encode.
+fn content_disposition(filename: &str) -> String {
+    let name = Path::new(filename)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| "download".to_string());
+    let fallback: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_graphic() && c != '"' && c != '\\' || c == ' ' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let encoded = percent_encoding::utf8_percent_encode(&name, ATTR_CHAR);
+    return format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback, encoded
+    );
+}
+
+/// Streams `length` bytes of `file`, a chunk at a time.
+fn stream_file(
+    file: tokio::fs::File,
+    length: u64,
+) -> impl Stream<Item = Result<Bytes, std::io::Error>> + Send {
+    return stream::try_unfold((file, length), |(mut file, remaining)| async move {
+        if remaining == 0 {
+            return Ok(None);
+        }
+        let mut buffer = vec![0; CHUNK_SIZE.min(remaining as usize)];
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            // The file was truncated while we sent it.
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        buffer.truncate(read);
+        return Ok(Some((Bytes::from(buffer), (file, remaining - read as u64))));
+    });
+}
+
+/// Sends a download, or the part that the `Range` header asks for. Following `If-Range`, we only
+/// send part of a download if the browser already has the rest of the same bytes.
+pub async fn reply(
+    download: Download,
+    range: Option<String>,
+    if_range: Option<String>,
+) -> Result<Response, std::io::Error> {
+    let length = match &download.source {
+        Source::File(path) => tokio::fs::metadata(path).await?.len(),
+        Source::Memory(bytes) => bytes.len() as u64,
+    };
+    let range = match if_range {
+        Some(if_range) if if_range.trim() != download.etag => Range::Full,
+        _ => parse_range(range.as_deref(), length),
+    };
+    let (status, start, end) = match range {
+        Range::Full => (http::StatusCode::OK, 0, length),
+        Range::Partial(first, last) => (http::StatusCode::PARTIAL_CONTENT, first, last + 1),
+        Range::Unsatisfiable => {
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = http::StatusCode::RANGE_NOT_SATISFIABLE;
+            response.headers_mut().insert(
+                http::header::CONTENT_RANGE,
+                http::HeaderValue::from_str(&format!("bytes */{}", length)).unwrap(),
+            );
+            return Ok(response);
+        }
+    };
+    let body = match download.source {
+        Source::File(path) => {
+            let mut file = tokio::fs::File::open(path).await?;
+            file.seek(SeekFrom::Start(start)).await?;
+            Body::wrap_stream(stream_file(file, end - start))
+        }
+        Source::Memory(mut bytes) => {
+            bytes.truncate(end as usize);
+            bytes.drain(..start as usize);
+            Body::from(bytes)
+        }
+    };
+
+    let mime = mime_guess::from_path(&download.filename).first_or_octet_stream();
+    let mut response = Response::new(body);
+    *response.status_mut() = status;
+    let headers = response.headers_mut();
+    let mut insert = |name: http::header::HeaderName, value: String| {
+        if let Ok(value) = http::HeaderValue::from_str(&value) {
+            headers.insert(name, value);
+        }
+    };
+    insert(http::header::CONTENT_TYPE, mime.to_string());
+    insert(http::header::CONTENT_LENGTH, (end - start).to_string());
+    insert(http::header::ACCEPT_RANGES, "bytes".to_string());
+    insert(http::header::ETAG, download.etag.clone());
+    insert(
+        http::header::CONTENT_DISPOSITION,
+        content_disposition(&download.filename),
+    );
+    if status == http::StatusCode::PARTIAL_CONTENT {
+        insert(
+            http::header::CONTENT_RANGE,
+            format!("bytes {}-{}/{}", start, end - 1, length),
+        );
+    }
+    return Ok(response);
+}
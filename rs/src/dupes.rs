@@ -0,0 +1,176 @@
+use super::image_table::{ImageTable, Row, RowView};
+use image::imageops::FilterType;
+use image::DynamicImage;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// The default largest Hamming distance between the perceptual hashes of two photos that we
+/// consider near-duplicates. Resized copies and re-exports are usually within a few bits.
+pub const DEFAULT_DISTANCE: u32 = 6;
+
+/// The largest distance that we search. Most photos are within 32 bits of each other, and
+/// searching further would compare every photo to every other.
+pub const MAX_DISTANCE: u32 = 16;
+
+#[derive(Serialize)]
+pub struct DuplicateEntry<'a> {
+    pub width: u32,
+    pub height: u32,
+    #[serde(flatten)]
+    pub row: RowView<'a>,
+}
+
+/// Photos that look alike, with the highest-resolution copy first.
+#[derive(Serialize)]
+pub struct DuplicateGroup<'a> {
+    pub photos: Vec<DuplicateEntry<'a>>,
+}
+
+/// A difference hash: we shrink the image to 9x8 grayscale pixels, and set one bit for each pair
+/// of horizontally adjacent pixels, depending on which is brighter. The hash depends on the
+/// structure of the image, and not on its size, format or compression, so copies of a photo
+/// have hashes that differ in only a few bits.
+pub fn perceptual_hash(image: &DynamicImage) -> u64 {
+    let small = image.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            let left = small.get_pixel(x, y)[0];
+            let right = small.get_pixel(x + 1, y)[0];
+            hash = (hash << 1) | ((left > right) as u64);
+        }
+    }
+    return hash;
+}
+
+pub fn distance(a: u64, b: u64) -> u32 {
+    return (a ^ b).count_ones();
+}
+
+/// A BK-tree of perceptual hashes, which finds the hashes near a hash without comparing it to
+/// every other hash. The children of a node are keyed by their distance from it, so by the
+/// triangle inequality, a hash within `max_distance` of the query can only be under the children
+/// whose key is within `max_distance` of the distance between the node and the query.
+struct BkTree {
+    nodes: Vec<BkNode>,
+}
+
+struct BkNode {
+    hash: u64,
+    // The rows with this hash
+    rows: Vec<usize>,
+    children: BTreeMap<u32, usize>,
+}
+
+impl BkTree {
+    fn new() -> Self {
+        return BkTree { nodes: vec![] };
+    }
+
+    fn insert(&mut self, hash: u64, row: usize) {
+        let mut node = 0;
+        while node < self.nodes.len() {
+            let d = distance(self.nodes[node].hash, hash);
+            if d == 0 {
+                self.nodes[node].rows.push(row);
+                return;
+            }
+            match self.nodes[node].children.get(&d) {
+                Some(&child) => node = child,
+                None => {
+                    let child = self.nodes.len();
+                    self.nodes[node].children.insert(d, child);
+                    break;
+                }
+            }
+        }
+        self.nodes.push(BkNode {
+            hash,
+            rows: vec![row],
+            children: BTreeMap::new(),
+        });
+    }
+
+    /// The rows whose hashes are within `max_distance` of `hash`.
+    fn find(&self, hash: u64, max_distance: u32) -> Vec<usize> {
+        let mut found = vec![];
+        let mut stack = vec![];
+        if self.nodes.is_empty() == false {
+            stack.push(0);
+        }
+        while let Some(node) = stack.pop() {
+            let node = &self.nodes[node];
+            let d = distance(node.hash, hash);
+            if d <= max_distance {
+                found.extend(node.rows.iter().copied());
+            }
+            let nearby = node
+                .children
+                .range(d.saturating_sub(max_distance)..=d + max_distance);
+            stack.extend(nearby.map(|(_, &child)| child));
+        }
+        return found;
+    }
+}
+
+fn find(parents: &mut [usize], i: usize) -> usize {
+    let mut root = i;
+    while parents[root] != root {
+        root = parents[root];
+    }
+    parents[i] = root;
+    return root;
+}
+
+/// Clusters photos whose perceptual hashes are within `max_distance` bits of each other. A
+/// photo that is near a photo in a cluster joins the cluster, so a cluster may hold photos that
+/// are further apart than `max_distance`, which is at most `MAX_DISTANCE`. Only clusters of two or
+/// more photos are returned. Photos that we have not decoded have no perceptual hash, and are
+/// never duplicates.
+pub fn groups(image_table: &ImageTable, max_distance: u32) -> Vec<DuplicateGroup<'_>> {
+    let max_distance = max_distance.min(MAX_DISTANCE);
+    let rows = image_table.rows();
+    let mut parents: Vec<usize> = (0..rows.len()).collect();
+    let mut tree = BkTree::new();
+    for (i, row) in rows.iter().enumerate() {
+        if row.is_decoded() == false {
+            continue;
+        }
+        for j in tree.find(row.phash(), max_distance) {
+            let (a, b) = (find(&mut parents, i), find(&mut parents, j));
+            parents[a] = b;
+        }
+        tree.insert(row.phash(), i);
+    }
+    let mut clusters: Vec<Vec<&Row>> = vec![vec![]; rows.len()];
+    for (i, row) in rows.iter().enumerate() {
+        let root = find(&mut parents, i);
+        clusters[root].push(row);
+    }
+    let mut clusters: Vec<Vec<&Row>> = clusters
+        .into_iter()
+        .filter(|cluster| cluster.len() > 1)
+        .collect();
+    for cluster in clusters.iter_mut() {
+        cluster.sort_by(|a, b| {
+            let pixels = |row: &Row| row.width() as u64 * row.height() as u64;
+            return pixels(b)
+                .cmp(&pixels(a))
+                .then_with(|| a.original_path.cmp(&b.original_path));
+        });
+    }
+    clusters.sort_by(|a, b| a[0].original_path.cmp(&b[0].original_path));
+    return clusters
+        .into_iter()
+        .map(|cluster| DuplicateGroup {
+            photos: cluster
+                .into_iter()
+                .map(|row| DuplicateEntry {
+                    width: row.width(),
+                    height: row.height(),
+                    row: row.view(),
+                })
+                .collect(),
+        })
+        .collect();
+}
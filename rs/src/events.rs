@@ -0,0 +1,98 @@
+use super::albums::Album;
+use super::image_table::{ImageTable, Row};
+use serde::Serialize;
+use std::collections::{BTreeSet, HashSet};
+
+/// A photo that an event refers to.
+#[derive(Serialize, Clone, Debug)]
+pub struct PhotoRef {
+    pub original_path: String,
+    pub md5: String,
+    pub gallery: String,
+    pub thumbnail_path: String,
+    pub webview_path: String,
+}
+
+/// A change to the catalog, which the server pushes to browsers.
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CatalogEvent {
+    Added(PhotoRef),
+    /// The photo at a path changed, or its annotations or description did.
+    Updated(PhotoRef),
+    Removed(PhotoRef),
+    /// The contents of a directory gallery or an album changed.
+    GalleryChanged {
+        name: String,
+    },
+    /// Some events were dropped because the browser fell behind, so it should fetch everything
+    /// that it shows again.
+    Resync,
+}
+
+impl PhotoRef {
+    fn new(row: &Row) -> Self {
+        return PhotoRef {
+            original_path: row.original_path.clone(),
+            md5: format!("{:x}", row.md5()),
+            gallery: row.gallery().to_string(),
+            thumbnail_path: row.thumbnail_path().to_string(),
+            webview_path: row.webview_path().to_string(),
+        };
+    }
+}
+
+fn is_updated(old_table: &ImageTable, old: &Row, new_table: &ImageTable, new: &Row) -> bool {
+    return old.md5() != new.md5()
+        || old.modified() != new.modified()
+        || old.description() != new.description()
+        || old_table.annotations(old.md5()) != new_table.annotations(new.md5());
+}
+
+/// The events that turn `old` into `new`. Photos are identified by path.
+pub fn diff(old: &ImageTable, new: &ImageTable) -> Vec<CatalogEvent> {
+    let mut events = vec![];
+    // MD5s of photos that changed in any way, to find the albums that they affect.
+    let mut changed_hashes: HashSet<u128> = HashSet::new();
+    let mut changed_galleries: BTreeSet<String> = BTreeSet::new();
+    for row in new.rows() {
+        let event = match old.get_by_original_path(&row.original_path) {
+            None => CatalogEvent::Added(PhotoRef::new(row)),
+            Some(old_row) if is_updated(old, old_row, new, row) => {
+                changed_hashes.insert(old_row.md5());
+                CatalogEvent::Updated(PhotoRef::new(row))
+            }
+            Some(_) => continue,
+        };
+        changed_hashes.insert(row.md5());
+        changed_galleries.insert(row.gallery().to_string());
+        events.push(event);
+    }
+    for row in old.rows() {
+        if new.get_by_original_path(&row.original_path).is_none() {
+            changed_hashes.insert(row.md5());
+            changed_galleries.insert(row.gallery().to_string());
+            events.push(CatalogEvent::Removed(PhotoRef::new(row)));
+        }
+    }
+    let album_names: BTreeSet<&String> = old.albums.keys().chain(new.albums.keys()).collect();
+    for name in album_names {
+        let is_changed = match (old.albums.get(name), new.albums.get(name)) {
+            (Some(old_album), Some(new_album)) if old_album == new_album => match new_album {
+                Album::Manual(hashes) => hashes.iter().any(|hash| changed_hashes.contains(hash)),
+                // Any change may change which photos match the query.
+                Album::Smart(_) => changed_hashes.is_empty() == false,
+            },
+            _ => true,
+        };
+        if is_changed {
+            changed_galleries.insert(name.clone());
+        }
+    }
+    events.extend(
+        changed_galleries
+            .into_iter()
+            .map(|name| CatalogEvent::GalleryChanged { name }),
+    );
+    return events;
+}
@@ -1,16 +1,26 @@
+use super::albums::{self, Album, GalleryEntry, GalleryKind};
+use super::annotations::{AnnotationEdit, Annotations};
+use super::capture::CaptureMetadata;
+use super::color;
 use super::config::Config;
+use super::dupes;
 use super::error::*;
-use exif;
+use super::metadata;
+use super::query::{self, Query};
+use super::sort::{self, Direction, SortKey};
+use super::stacks;
+use super::xmp::{self, Description};
+use chrono::{DateTime, NaiveDate};
 use image::imageops::FilterType;
 use image::DynamicImage;
-use image::ImageFormat;
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs;
 use std::path::Path;
 use std::process;
 use std::process::Command;
 use std::process::Stdio;
+use std::time::{Duration, Instant};
 use walkdir::WalkDir;
 
 #[derive(Serialize)]
@@ -21,6 +31,22 @@ pub struct RowView<'a> {
     pub md5: String,
 }
 
+/// Everything we know about a single image.
+#[derive(Serialize)]
+pub struct RowDetails<'a> {
+    pub original_path: &'a str,
+    pub md5: String,
+    pub gallery: &'a str,
+    pub title: &'a str,
+    pub thumbnail_path: &'a str,
+    pub webview_path: &'a str,
+    pub capture: CaptureMetadata,
+    pub width: u32,
+    pub height: u32,
+    pub description: &'a Description,
+    pub annotations: Annotations,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Row {
     // Path to the original image
@@ -29,6 +55,8 @@ pub struct Row {
     md5: u128,
     // modified time in milliseconds since Unix epoch
     modified: u128,
+    // Size of the original in bytes
+    size: u64,
     // Name of the gallery (derived from original_path)
     gallery: String,
     // Title of the image (derived from original_path)
@@ -37,21 +65,95 @@ pub struct Row {
     thumbnail_path: String,
     // Path to the web-sized (JPEG) that we build
     webview_path: String,
+    // Capture metadata from the EXIF data of the original
+    capture: CaptureMetadata,
+    // Pixel dimensions of the original, after rotating it upright
+    width: u32,
+    height: u32,
+    // Perceptual hash of the original, which is close for copies that differ in size or format
+    phash: u64,
+    // Keywords, title, caption and rating from XMP sidecars and embedded XMP or IPTC
+    description: Description,
+    // Modified time of the XMP sidecar in milliseconds since Unix epoch, if there is one
+    sidecar_modified: Option<u128>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct ImageTable {
+    // Add, update and remove rows with push_row, update_row and remove_row, which keep the
+    // indexes below up to date.
     rows: Vec<Row>,
+    // Annotations by the MD5 of the image they describe. We key annotations by content rather
+    // than attach them to rows, so that they survive when a photo is moved: sync removes the row
+    // at the old path and adds a row at the new path, and both have the same MD5.
+    pub annotations: HashMap<u128, Annotations>,
+    // Albums by name
+    pub albums: BTreeMap<String, Album>,
+    // MD5s of the photos that users chose as the covers of their stacks of burst shots
+    pub stack_covers: HashSet<u128>,
+    // Indices into rows by path, by MD5 and by gallery. The indexes are derived from rows, so we
+    // rebuild them when we open the table instead of storing them.
+    #[serde(skip)]
+    path_index: HashMap<String, usize>,
+    #[serde(skip)]
+    hash_index: HashMap<u128, BTreeSet<usize>>,
+    #[serde(skip)]
+    gallery_index: HashMap<String, BTreeSet<usize>>,
+}
+
+/// Catalogs start with this magic number, followed by the version of their format. The first
+/// version of SPG wrote catalogs without a header, which start with the number of photos instead,
+/// and that is never this large.
+const CATALOG_MAGIC: u64 = u64::from_le_bytes(*b"spgtable");
+
+/// The version of the catalog format that we write. When we add to or change the format, we bump
+/// the version, and upgrade catalogs in older formats when we open them.
+const CATALOG_VERSION: u32 = 1;
+
+/// A row in a catalog that the first version of SPG wrote.
+#[derive(Deserialize)]
+struct LegacyRow {
+    original_path: String,
+    md5: u128,
+    modified: u128,
+    gallery: String,
+    title: String,
+    thumbnail_path: String,
+    webview_path: String,
+}
+
+/// A catalog that the first version of SPG wrote, which is nothing but rows.
+#[derive(Deserialize)]
+struct LegacyImageTable {
+    rows: Vec<LegacyRow>,
+}
+
+/// Changes to albums from the command line. Photos are named by filename.
+pub enum AlbumCommand {
+    Create(String, Option<String>),
+    Delete(String),
+    Add(String, Vec<String>),
+    Remove(String, Vec<String>),
+    List(String),
 }
 
 pub struct SimplePhotoGallery {
     pub image_table: ImageTable,
     pub config: Config,
+    // Held by a gallery that was opened to change the catalog.
+    _lock: Option<CatalogLock>,
+}
+
+/// An advisory lock on the catalog. The command line, the watcher and the server take it before
+/// they read the catalog to change it, and hold it until they save it, so that none of them
+/// overwrites the changes of another. Dropping the lock releases it.
+pub struct CatalogLock {
+    _file: fs::File,
 }
 
 static KNOWN_EXTENSIONS: [&'static str; 6] = ["heic", "HEIC", "jpg", "JPG", "jpeg", "JPEG"];
 
-fn is_recognized_filename(path: &Path) -> bool {
+pub fn is_recognized_filename(path: &Path) -> bool {
     let filename = path.file_name().and_then(|os_str| os_str.to_str());
     let ext = path.extension().and_then(|os_str| os_str.to_str());
     match (filename, ext) {
@@ -122,6 +224,20 @@ fn file_md5(p: impl AsRef<Path>) -> Result<u128, std::io::Error> {
     return Ok(i);
 }
 
+/// Modified time in milliseconds since the Unix epoch.
+fn file_timestamp(p: impl AsRef<Path>) -> Result<u128, std::io::Error> {
+    let metadata = fs::metadata(p)?;
+    let modified_time = metadata.modified()?;
+    let duration_since_epoch = modified_time
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("file modification time is bogus");
+    return Ok(duration_since_epoch.as_millis());
+}
+
+fn sidecar_timestamp(original_path: impl AsRef<Path>) -> Option<u128> {
+    return xmp::sidecar_path(original_path).and_then(|path| file_timestamp(path).ok());
+}
+
 fn image_orientation(p: impl AsRef<Path>) -> Result<usize, std::io::Error> {
     let file = fs::File::open(p)?;
     let mut buf_reader = std::io::BufReader::new(&file);
@@ -167,13 +283,135 @@ fn open_with_exif_rotation(p: impl AsRef<Path>) -> Result<DynamicImage, CommandE
     return Ok(rotated_image);
 }
 
+/// Opens an image for generating derivatives: the image is rotated upright and its colors are
+/// converted to sRGB, since that is what browsers assume for untagged JPEGs.
+fn open_for_derivatives(p: impl AsRef<Path>) -> Result<DynamicImage, CommandError> {
+    let image = open_with_exif_rotation(&p)?;
+    return Ok(color::convert_to_srgb(image, &p));
+}
+
+fn read_capture_metadata(p: impl AsRef<Path>) -> CaptureMetadata {
+    return metadata::read_exif(p)
+        .map(|exif| CaptureMetadata::new(&exif))
+        .unwrap_or_default();
+}
+
 impl Row {
+    pub fn md5(&self) -> u128 {
+        return self.md5;
+    }
+
+    pub fn modified(&self) -> u128 {
+        return self.modified;
+    }
+
+    pub fn size(&self) -> u64 {
+        return self.size;
+    }
+
+    pub fn gallery(&self) -> &str {
+        return &self.gallery;
+    }
+
+    pub fn title(&self) -> &str {
+        return &self.title;
+    }
+
+    pub fn thumbnail_path(&self) -> &str {
+        return &self.thumbnail_path;
+    }
+
+    pub fn webview_path(&self) -> &str {
+        return &self.webview_path;
+    }
+
+    pub fn capture(&self) -> &CaptureMetadata {
+        return &self.capture;
+    }
+
+    pub fn width(&self) -> u32 {
+        return self.width;
+    }
+
+    pub fn height(&self) -> u32 {
+        return self.height;
+    }
+
+    pub fn phash(&self) -> u64 {
+        return self.phash;
+    }
+
+    /// Whether we have decoded the photo, and so know its dimensions and perceptual hash. Rows
+    /// upgraded from the legacy format are not decoded until the next sync.
+    pub fn is_decoded(&self) -> bool {
+        return self.width > 0;
+    }
+
+    pub fn description(&self) -> &Description {
+        return &self.description;
+    }
+
+    pub fn view(&self) -> RowView<'_> {
+        return RowView {
+            thumbnail_path: self.thumbnail_path.as_str(),
+            webview_path: self.webview_path.as_str(),
+            original_path: self.original_path.as_str(),
+            md5: format!("{:x}", self.md5),
+        };
+    }
+
+    /// When the photo was taken, in seconds since the Unix epoch. If the photo does not record
+    /// when it was taken, we use the modified time of the file.
+    pub fn timestamp(&self) -> i64 {
+        return match &self.capture.taken {
+            Some(taken) => taken.timestamp(),
+            None => (self.modified / 1000) as i64,
+        };
+    }
+
+    /// The calendar day on which the photo was taken, in the local time of the camera.
+    pub fn date(&self) -> NaiveDate {
+        return match &self.capture.taken {
+            Some(taken) => taken.local.date(),
+            None => DateTime::from_timestamp((self.modified / 1000) as i64, 0)
+                .unwrap_or_default()
+                .date_naive(),
+        };
+    }
+
+    /// The details of this row. Unless `include_gps` is set, the GPS position is omitted, so that
+    /// the API does not publish what the metadata policy strips from derivatives.
+    pub fn details(&self, annotations: Annotations, include_gps: bool) -> RowDetails<'_> {
+        let mut capture = self.capture.clone();
+        if include_gps == false {
+            capture.gps = None;
+        }
+        return RowDetails {
+            original_path: &self.original_path,
+            md5: format!("{:x}", self.md5),
+            gallery: &self.gallery,
+            title: &self.title,
+            thumbnail_path: &self.thumbnail_path,
+            webview_path: &self.webview_path,
+            capture,
+            width: self.width,
+            height: self.height,
+            description: &self.description,
+            annotations,
+        };
+    }
+
     fn new(config: &Config, original_path: impl Into<String>) -> Result<Self, CommandError> {
         let original_path_str: String = original_path.into();
 
         let original_path: &Path = original_path_str.as_ref();
 
         let md5 = file_md5(&original_path).map_err(trace("calculating MD5 of file"))?;
+        let modified =
+            file_timestamp(&original_path).map_err(trace("reading modified time of file"))?;
+        let size = fs::metadata(&original_path)
+            .map_err(trace("reading size of file"))?
+            .len();
 
         let title: &Path = original_path.file_name().unwrap().as_ref();
         let title = String::from(title.file_stem().unwrap().to_string_lossy());
@@ -189,15 +427,25 @@ impl Row {
 
         let thumbnail_path = format!("{:x}-thumbnail.jpg", md5);
         let webview_path = format!("{:x}-webview.jpg", md5);
+        let capture = read_capture_metadata(original_path);
+        let description = xmp::read_description(original_path);
+        let sidecar_modified = sidecar_timestamp(original_path);
 
-        let new_row = Row {
+        let mut new_row = Row {
             original_path: original_path_str,
             md5,
-            modified: 0,
+            modified,
+            size,
             title,
             gallery,
             thumbnail_path,
             webview_path,
+            capture,
+            width: 0,
+            height: 0,
+            phash: 0,
+            description,
+            sidecar_modified,
         };
         new_row.generate_jpegs(config)?;
         return Ok(new_row);
@@ -205,23 +453,55 @@ impl Row {
 
     fn update(&mut self, config: &Config) -> Result<(), CommandError> {
         let current_md5 = file_md5(&self.original_path)?;
-        if self.md5 == current_md5 {
+        self.modified = file_timestamp(&self.original_path)?;
+        self.size = fs::metadata(&self.original_path)?.len();
+        let sidecar_modified = sidecar_timestamp(&self.original_path);
+        // A row that we upgraded from the first catalog format has no dimensions, since we only
+        // learn them by decoding the image. We read everything again, as if the image had changed.
+        if self.md5 == current_md5 && self.width > 0 {
+            // An edited sidecar changes the description, but not the image.
+            if self.sidecar_modified != sidecar_modified {
+                self.sidecar_modified = sidecar_modified;
+                self.description = xmp::read_description(&self.original_path);
+                println!("{} updated", self.original_path);
+            }
             return Ok(());
         }
         self.md5 = current_md5;
+        self.capture = read_capture_metadata(&self.original_path);
+        self.sidecar_modified = sidecar_modified;
+        self.description = xmp::read_description(&self.original_path);
         self.generate_jpegs(config)?;
         println!("{} updated", self.original_path);
         return Ok(());
     }
 
+    /// The format of the original. We only recognize HEIC and JPEG files.
+    pub fn media_type(&self) -> &'static str {
+        if self.is_heic() {
+            return "heic";
+        }
+        return "jpeg";
+    }
+
+    pub fn is_heic(&self) -> bool {
+        return Path::new(&self.original_path)
+            .extension()
+            .is_some_and(|ext| ext.to_string_lossy().to_lowercase() == "heic");
+    }
+
+    /// Path to the full-size JPEG that heif-convert produces from a HEIC original.
+    pub fn converted_path(&self, dir: &str) -> String {
+        return format!("{}/converted/{:x}-converted.jpg", dir, self.md5);
+    }
+
     fn open_original(&self, dir: &str) -> Result<DynamicImage, CommandError> {
         let path = Path::new(&self.original_path);
-        let ext = path
-            .extension()
-            .ok_or(error("filename has no extension"))?
-            .to_string_lossy();
-        if ext.to_lowercase() == "heic" {
-            let output_path_str = format!("{}/converted/{:x}-converted.jpg", dir, self.md5);
+        if path.extension().is_none() {
+            return Err(error("filename has no extension"));
+        }
+        if self.is_heic() {
+            let output_path_str = self.converted_path(dir);
             let output_path = Path::new(&output_path_str);
             if output_path.exists() {
                 fs::remove_file(output_path).map_err(trace("deleting existing converted JPEG"))?;
@@ -250,107 +530,416 @@ impl Row {
                 );
                 return Err(error("could not convert HEIC to JPEG."));
             }
-            return open_with_exif_rotation(output_path);
+            return open_for_derivatives(output_path);
         }
-        return open_with_exif_rotation(path);
+        return open_for_derivatives(path);
     }
 
-    fn generate_jpegs(&self, config: &Config) -> Result<(), CommandError> {
+    fn generate_jpegs(&mut self, config: &Config) -> Result<(), CommandError> {
+        use image::GenericImageView;
         let original_image = self
             .open_original(&config.data_dir)
             .map_err(trace("reading image"))?;
+        let (width, height) = original_image.dimensions();
+        self.width = width;
+        self.height = height;
+        self.phash = dupes::perceptual_hash(&original_image);
+        let original_exif = metadata::read_exif(&self.original_path);
+        let policy = config.settings.metadata_policy;
         let thumbnail = generate_thumbnail(&original_image);
-        thumbnail
-            .save_with_format(
-                &format!("{}/www/photos/{}", config.data_dir, self.thumbnail_path),
-                ImageFormat::Jpeg,
-            )
-            .map_err(trace("saving thumbnail"))?;
+        metadata::save_jpeg(
+            &thumbnail,
+            format!("{}/www/photos/{}", config.data_dir, self.thumbnail_path),
+            original_exif.as_ref(),
+            policy,
+        )
+        .map_err(trace("saving thumbnail"))?;
         let webview = original_image.resize(1024, 1024, FilterType::Gaussian);
-        webview
-            .save_with_format(
-                &format!("{}/www/photos/{}", config.data_dir, &self.webview_path),
-                ImageFormat::Jpeg,
-            )
-            .map_err(trace("saving web-view image"))?;
+        metadata::save_jpeg(
+            &webview,
+            format!("{}/www/photos/{}", config.data_dir, &self.webview_path),
+            original_exif.as_ref(),
+            policy,
+        )
+        .map_err(trace("saving web-view image"))?;
         return Ok(());
     }
 }
 
+impl LegacyRow {
+    /// A row in the current format. We only learn the size of the file here: we have to decode
+    /// the image to learn anything else, so that is left to the next sync.
+    fn upgrade(self) -> Row {
+        let size = fs::metadata(&self.original_path)
+            .map(|metadata| metadata.len())
+            .unwrap_or(0);
+        return Row {
+            original_path: self.original_path,
+            md5: self.md5,
+            modified: self.modified,
+            size,
+            gallery: self.gallery,
+            title: self.title,
+            thumbnail_path: self.thumbnail_path,
+            webview_path: self.webview_path,
+            capture: CaptureMetadata::default(),
+            width: 0,
+            height: 0,
+            phash: 0,
+            description: Description::default(),
+            sidecar_modified: None,
+        };
+    }
+}
+
 impl ImageTable {
     pub fn new() -> Self {
-        return ImageTable { rows: vec![] };
+        return ImageTable {
+            rows: vec![],
+            annotations: HashMap::new(),
+            albums: BTreeMap::new(),
+            stack_covers: HashSet::new(),
+            path_index: HashMap::new(),
+            hash_index: HashMap::new(),
+            gallery_index: HashMap::new(),
+        };
     }
 
     pub fn open(path: impl AsRef<Path>) -> Self {
+        match ImageTable::try_open(path) {
+            Ok(image_table) => return image_table,
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+        }
+    }
+
+    /// Opens a catalog, and upgrades it in memory if it is in an older format. The upgrade is
+    /// saved with the next change to the catalog.
+    pub fn try_open(path: impl AsRef<Path>) -> Result<Self, CommandError> {
         let path = path.as_ref();
-        let bytes = std::fs::read(path).unwrap();
-        return bincode::deserialize(&bytes).unwrap();
+        let bytes = std::fs::read(path).map_err(trace(format!("Could not read {:?}.", path)))?;
+        let unreadable = |err: bincode::Error| {
+            return error(format!(
+                "Could not read {:?}. The catalog may be damaged. To rebuild it, move it away, \
+                 run 'spg init' in a new directory and 'spg sync' your photos.\n{}",
+                path, err
+            ));
+        };
+        let magic: u64 = bincode::deserialize(&bytes).map_err(unreadable)?;
+        let mut image_table = if magic != CATALOG_MAGIC {
+            let legacy: LegacyImageTable = bincode::deserialize(&bytes).map_err(unreadable)?;
+            eprintln!(
+                "Upgraded {:?} from the format of an older version of SPG. Run 'spg sync' to \
+                 read the dimensions and metadata of the photos in it.",
+                path
+            );
+            let mut image_table = ImageTable::new();
+            image_table.rows = legacy.rows.into_iter().map(LegacyRow::upgrade).collect();
+            image_table
+        } else {
+            let (_, version): (u64, u32) = bincode::deserialize(&bytes).map_err(unreadable)?;
+            if version > CATALOG_VERSION {
+                return Err(error(format!(
+                    "Could not read {:?}, which a newer version of SPG wrote (format {}).",
+                    path, version
+                )));
+            }
+            let (_, _, image_table): (u64, u32, ImageTable) =
+                bincode::deserialize(&bytes).map_err(unreadable)?;
+            image_table
+        };
+        image_table.rebuild_index();
+        return Ok(image_table);
+    }
+
+    fn rebuild_index(&mut self) {
+        self.path_index.clear();
+        self.hash_index.clear();
+        self.gallery_index.clear();
+        for index in 0..self.rows.len() {
+            self.index_row(index);
+        }
+    }
+
+    fn index_row(&mut self, index: usize) {
+        let row = &self.rows[index];
+        self.path_index.insert(row.original_path.clone(), index);
+        self.hash_index.entry(row.md5).or_default().insert(index);
+        self.gallery_index
+            .entry(row.gallery.clone())
+            .or_default()
+            .insert(index);
+    }
+
+    fn unindex_row(&mut self, index: usize) {
+        let row = &self.rows[index];
+        self.path_index.remove(&row.original_path);
+        if let Some(indices) = self.hash_index.get_mut(&row.md5) {
+            indices.remove(&index);
+            if indices.is_empty() {
+                self.hash_index.remove(&row.md5);
+            }
+        }
+        if let Some(indices) = self.gallery_index.get_mut(&row.gallery) {
+            indices.remove(&index);
+            if indices.is_empty() {
+                self.gallery_index.remove(&row.gallery);
+            }
+        }
+    }
+
+    pub fn rows(&self) -> &[Row] {
+        return &self.rows;
+    }
+
+    pub fn push_row(&mut self, row: Row) {
+        self.rows.push(row);
+        self.index_row(self.rows.len() - 1);
+    }
+
+    /// Removes the row at `index`, and moves the last row into its place.
+    pub fn remove_row(&mut self, index: usize) -> Row {
+        let last = self.rows.len() - 1;
+        self.unindex_row(index);
+        if index != last {
+            self.unindex_row(last);
+        }
+        let row = self.rows.swap_remove(index);
+        if index != last {
+            self.index_row(index);
+        }
+        return row;
+    }
+
+    /// Applies `f` to the row at `index`, which may change its hash.
+    pub fn update_row<T>(&mut self, index: usize, f: impl FnOnce(&mut Row) -> T) -> T {
+        self.unindex_row(index);
+        let result = f(&mut self.rows[index]);
+        self.index_row(index);
+        return result;
+    }
+
+    /// The photos in a directory gallery, in no particular order.
+    pub fn gallery_rows(&self, gallery: &str) -> Vec<&Row> {
+        return match self.gallery_index.get(gallery) {
+            None => vec![],
+            Some(indices) => indices.iter().map(|&index| &self.rows[index]).collect(),
+        };
     }
 
+    /// Saves the table. We write a temporary file and rename it, so that the server, which
+    /// reloads the table when it changes, never reads a partially written table.
     pub fn save(&self, path: impl AsRef<Path>) {
         let path: &Path = path.as_ref();
-        let bytes = bincode::serialize(self).unwrap();
-        std::fs::write(path, bytes).expect(&format!("Could not write to {:?}", path));
+        let bytes = bincode::serialize(&(CATALOG_MAGIC, CATALOG_VERSION, self)).unwrap();
+        let temp_path = path.with_extension("bincode.tmp");
+        std::fs::write(&temp_path, bytes)
+            .and_then(|()| std::fs::rename(&temp_path, path))
+            .unwrap_or_else(|_| panic!("Could not write to {:?}", path));
     }
 
-    fn get_by_original_path(&mut self, p: &str) -> Option<&mut Row> {
-        return self.rows.iter_mut().find(|row| row.original_path == p);
+    pub fn index_of_original_path(&self, p: &str) -> Option<usize> {
+        return self.path_index.get(p).copied();
     }
 
+    pub fn get_by_original_path(&self, p: &str) -> Option<&Row> {
+        return self
+            .index_of_original_path(p)
+            .map(|index| &self.rows[index]);
+    }
+
+    /// One of the photos with hash `hash`. There are several when the same photo is at several
+    /// paths.
     pub fn get_by_hash(&self, hash: u128) -> Option<&Row> {
-        return self.rows.iter().find(|row| row.md5 == hash);
+        let index = self.hash_index.get(&hash)?.iter().next()?;
+        return Some(&self.rows[*index]);
     }
 
-    pub fn gallery_list(&self) -> Vec<&str> {
-        let galleries: HashSet<_> = self.rows.iter().map(|row| row.gallery.as_str()).collect();
-        let mut galleries: Vec<_> = galleries.into_iter().collect();
-        galleries.sort();
-        return galleries;
+    pub fn annotations(&self, hash: u128) -> Annotations {
+        return self.annotations.get(&hash).cloned().unwrap_or_default();
     }
 
-    pub fn gallery_contents(&self, gallery: &str) -> Vec<RowView> {
-        return self
-            .rows
-            .iter()
-            .filter(|row| row.gallery == gallery)
-            .map(|row| RowView {
-                thumbnail_path: row.thumbnail_path.as_str(),
-                webview_path: row.webview_path.as_str(),
-                original_path: row.original_path.as_str(),
-                md5: format!("{:x}", row.md5),
-            })
+    /// Applies `edit` to the annotations of the image with hash `hash`.
+    pub fn annotate(
+        &mut self,
+        hash: u128,
+        edit: AnnotationEdit,
+    ) -> Result<Annotations, CommandError> {
+        if self.get_by_hash(hash).is_none() {
+            return Err(error("image is not in database"));
+        }
+        let annotations = self.annotations.entry(hash).or_default();
+        annotations.apply(edit).map_err(error)?;
+        let annotations = annotations.clone();
+        if annotations.is_empty() {
+            self.annotations.remove(&hash);
+        }
+        return Ok(annotations);
+    }
+
+    /// When an image is edited, its MD5 changes, but its annotations should stay with it.
+    fn copy_annotations(&mut self, old_hash: u128, new_hash: u128) {
+        if old_hash == new_hash || self.annotations.contains_key(&new_hash) {
+            return;
+        }
+        if let Some(annotations) = self.annotations.get(&old_hash).cloned() {
+            self.annotations.insert(new_hash, annotations);
+        }
+    }
+
+    /// Directory galleries, followed by albums.
+    pub fn gallery_list(&self) -> Vec<GalleryEntry<'_>> {
+        let mut galleries: Vec<_> = self
+            .gallery_index
+            .keys()
+            .map(|name| name.as_str())
             .collect();
+        galleries.sort();
+        let directories = galleries.into_iter().map(|name| GalleryEntry {
+            name,
+            kind: GalleryKind::Directory,
+            query: None,
+        });
+        let albums = self.albums.iter().map(|(name, album)| GalleryEntry {
+            name,
+            kind: album.kind(),
+            query: match album {
+                Album::Smart(query) => Some(query.as_str()),
+                Album::Manual(_) => None,
+            },
+        });
+        return directories.chain(albums).collect();
+    }
+
+    pub fn album_contents(&self, name: &str) -> Result<Vec<RowView<'_>>, CommandError> {
+        let album = self
+            .albums
+            .get(name)
+            .ok_or_else(|| error(format!("no album named {}", name)))?;
+        let rows = albums::contents(self, album)?;
+        return Ok(rows.into_iter().map(|row| row.view()).collect());
+    }
+
+    pub fn gallery_contents(
+        &self,
+        gallery: &str,
+        key: SortKey,
+        direction: Direction,
+    ) -> Vec<RowView<'_>> {
+        let mut rows = self.gallery_rows(gallery);
+        sort::sort_rows(self, &mut rows, key, direction);
+        return rows.into_iter().map(|row| row.view()).collect();
+    }
+}
+
+impl CatalogLock {
+    fn open_file(config: &Config) -> Result<fs::File, CommandError> {
+        return fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&config.catalog_lock_path)
+            .map_err(trace("opening the catalog lock"));
+    }
+
+    /// Waits for the lock for as long as it takes.
+    pub fn acquire(config: &Config) -> Result<CatalogLock, CommandError> {
+        let file = CatalogLock::open_file(config)?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(fs::TryLockError::WouldBlock) => {
+                eprintln!("Waiting for another command to finish changing the catalog.");
+                file.lock()?;
+            }
+            Err(fs::TryLockError::Error(err)) => return Err(err.into()),
+        }
+        return Ok(CatalogLock { _file: file });
+    }
+
+    /// Waits for the lock for up to `timeout`, and returns `None` if it is still held, e.g., by a
+    /// long sync.
+    pub fn try_acquire(
+        config: &Config,
+        timeout: Duration,
+    ) -> Result<Option<CatalogLock>, CommandError> {
+        let file = CatalogLock::open_file(config)?;
+        let start = Instant::now();
+        loop {
+            match file.try_lock() {
+                Ok(()) => return Ok(Some(CatalogLock { _file: file })),
+                Err(fs::TryLockError::WouldBlock) if start.elapsed() < timeout => {
+                    std::thread::sleep(Duration::from_millis(100));
+                }
+                Err(fs::TryLockError::WouldBlock) => return Ok(None),
+                Err(fs::TryLockError::Error(err)) => return Err(err.into()),
+            }
+        }
     }
 }
 
 impl SimplePhotoGallery {
+    /// Opens the gallery to read it.
     pub fn new(data_dir: impl AsRef<Path>) -> Self {
+        return SimplePhotoGallery::open(data_dir, false);
+    }
+
+    /// Opens the gallery to change it. The gallery holds the catalog lock until it is dropped.
+    pub fn lock(data_dir: impl AsRef<Path>) -> Self {
+        return SimplePhotoGallery::open(data_dir, true);
+    }
+
+    fn open(data_dir: impl AsRef<Path>, lock: bool) -> Self {
         let data_dir = data_dir.as_ref();
         if !data_dir.is_dir() {
             eprintln!("Data directory not found. Run \'spg init\'.");
             process::exit(1);
         }
         let config = Config::new(data_dir.to_string_lossy().to_string());
+        let lock = if lock {
+            let lock = CatalogLock::acquire(&config).unwrap_or_else(|err| {
+                eprintln!("{}\n\nError locking the catalog", err);
+                process::exit(1);
+            });
+            Some(lock)
+        } else {
+            None
+        };
         let image_table = ImageTable::open(&config.image_table_path);
         return Self {
             config,
             image_table,
+            _lock: lock,
         };
     }
 
-    fn add_(&mut self, original_path: impl AsRef<Path>) -> Result<(), CommandError> {
+    /// Saves the catalog. The methods whose names end in `_` change the catalog without saving
+    /// it, so that a batch of changes is saved once.
+    pub fn save(&self) {
+        self.image_table.save(&self.config.image_table_path);
+    }
+
+    pub fn add_(&mut self, original_path: impl AsRef<Path>) -> Result<(), CommandError> {
         let full_path = original_path.as_ref().canonicalize()?;
         let original_path = full_path.to_string_lossy().to_string();
-        match self.image_table.get_by_original_path(&original_path) {
+        match self.image_table.index_of_original_path(&original_path) {
             None => {
                 self.image_table
-                    .rows
-                    .push(Row::new(&self.config, &original_path)?);
+                    .push_row(Row::new(&self.config, &original_path)?);
                 println!("{} added", &original_path);
                 return Ok(());
             }
-            Some(row) => row.update(&self.config),
+            Some(index) => {
+                let config = &self.config;
+                let (old_md5, new_md5) = self.image_table.update_row(index, |row| {
+                    let old_md5 = row.md5;
+                    row.update(config)?;
+                    return Ok::<_, CommandError>((old_md5, row.md5));
+                })?;
+                self.image_table.copy_annotations(old_md5, new_md5);
+                return Ok(());
+            }
         }
     }
 
@@ -378,21 +967,63 @@ impl SimplePhotoGallery {
                 } else {
                     println!("The image is in the gallery.");
                 }
+                for line in self.image_table.annotations(row.md5).describe() {
+                    println!("{}", line);
+                }
+                for line in row.description.describe() {
+                    println!("{}", line);
+                }
+                for line in row.capture.describe() {
+                    println!("{}", line);
+                }
             }
         }
     }
 
-    fn rm_(&mut self, path: impl AsRef<Path>) -> Result<(), CommandError> {
+    fn annotate_(
+        &mut self,
+        path: impl AsRef<Path>,
+        edit: AnnotationEdit,
+    ) -> Result<(), CommandError> {
+        let absolute_path = path.as_ref().canonicalize()?;
+        let absolute_path = absolute_path.to_string_lossy();
+        let hash = self
+            .image_table
+            .get_by_original_path(&absolute_path)
+            .ok_or_else(|| error("file is not in database"))?
+            .md5;
+        self.image_table.annotate(hash, edit)?;
+        return Ok(());
+    }
+
+    pub fn annotate(&mut self, path: String, edit: AnnotationEdit) {
+        if let Err(err) = self.annotate_(&path, edit) {
+            eprintln!("{}\n\nError annotating {}", err, &path);
+            process::exit(1);
+        }
+        self.image_table.save(&self.config.image_table_path);
+    }
+
+    pub fn rm_(&mut self, path: impl AsRef<Path>) -> Result<(), CommandError> {
         let path = path.as_ref();
-        let absolute_path = path.canonicalize()?;
+        // We cannot canonicalize the path of a file that was deleted or moved away. But, sync
+        // only removes paths from the table, which are already absolute.
+        let absolute_path = if path.is_absolute() && path.exists() == false {
+            path.to_path_buf()
+        } else {
+            path.canonicalize()?
+        };
         let absolute_path = absolute_path.to_string_lossy();
         let row_index = self
             .image_table
-            .rows
-            .iter()
-            .position(|row| row.original_path == absolute_path)
+            .index_of_original_path(&absolute_path)
             .ok_or_else(|| error("file is not in database"))?;
-        let row = self.image_table.rows.remove(row_index);
+        let row = self.image_table.remove_row(row_index);
+        // When a photo is moved, sync adds the new path before it removes the old one. Both rows
+        // share derivatives, since derivatives are named by MD5.
+        if self.image_table.get_by_hash(row.md5).is_some() {
+            return Ok(());
+        }
         fs::remove_file(format!(
             "{}/www/photos/{}",
             self.config.data_dir, row.thumbnail_path
@@ -411,8 +1042,115 @@ impl SimplePhotoGallery {
         self.image_table.save(&self.config.image_table_path);
     }
 
+    fn hashes_of(&self, filenames: &[String]) -> Result<Vec<u128>, CommandError> {
+        let mut hashes = vec![];
+        for filename in filenames.iter() {
+            let absolute_path = Path::new(filename).canonicalize()?;
+            let absolute_path = absolute_path.to_string_lossy();
+            let row = self
+                .image_table
+                .get_by_original_path(&absolute_path)
+                .ok_or_else(|| error(format!("{} is not in database", filename)))?;
+            hashes.push(row.md5);
+        }
+        return Ok(hashes);
+    }
+
+    fn album_(&mut self, command: AlbumCommand) -> Result<(), CommandError> {
+        match command {
+            AlbumCommand::Create(name, query) => {
+                albums::create(&mut self.image_table, &name, query.as_deref())?;
+            }
+            AlbumCommand::Delete(name) => albums::delete(&mut self.image_table, &name)?,
+            AlbumCommand::Add(name, filenames) => {
+                let hashes = self.hashes_of(&filenames)?;
+                albums::add_photos(&mut self.image_table, &name, &hashes)?;
+            }
+            AlbumCommand::Remove(name, filenames) => {
+                let hashes = self.hashes_of(&filenames)?;
+                albums::remove_photos(&mut self.image_table, &name, &hashes)?;
+            }
+            AlbumCommand::List(name) => {
+                for row in self.image_table.album_contents(&name)? {
+                    println!("{}", row.original_path);
+                }
+                return Ok(());
+            }
+        }
+        self.image_table.save(&self.config.image_table_path);
+        return Ok(());
+    }
+
+    fn cover_(&mut self, filename: String) -> Result<(), CommandError> {
+        let hash = self.hashes_of(&[filename])?[0];
+        stacks::set_cover(&mut self.image_table, hash)?;
+        self.image_table.save(&self.config.image_table_path);
+        return Ok(());
+    }
+
+    pub fn cover(&mut self, filename: String) {
+        if let Err(err) = self.cover_(filename) {
+            eprintln!("{}\n\nError choosing cover", err);
+            process::exit(1);
+        }
+    }
+
+    pub fn album(&mut self, command: AlbumCommand) {
+        if let Err(err) = self.album_(command) {
+            eprintln!("{}\n\nError updating album", err);
+            process::exit(1);
+        }
+    }
+
+    pub fn search(
+        &self,
+        query: &str,
+        key: SortKey,
+        direction: Direction,
+        offset: usize,
+        limit: usize,
+    ) {
+        let query = Query::parse(query).unwrap_or_else(|err| {
+            eprintln!("{}\n\nError parsing query", err);
+            process::exit(1);
+        });
+        let results = query::search(&self.image_table, &query, key, direction, offset, limit);
+        for row in results.results {
+            println!("{}", row.original_path);
+        }
+    }
+
+    /// Prints groups of near-duplicate photos, separated by blank lines. The first photo in
+    /// each group has the highest resolution.
+    pub fn dupes(&self, max_distance: u32) {
+        for group in dupes::groups(&self.image_table, max_distance) {
+            for photo in group.photos {
+                println!(
+                    "{} ({}x{})",
+                    photo.row.original_path, photo.width, photo.height
+                );
+            }
+            println!();
+        }
+    }
+
+    pub fn list(&self, gallery: &str, key: SortKey, direction: Direction) {
+        for row in self.image_table.gallery_contents(gallery, key, direction) {
+            println!("{}", row.original_path);
+        }
+    }
+
     pub fn add_remove_path(&mut self, root: &str) -> Result<(), CommandError> {
-        let images: Vec<_> = WalkDir::new(root)
+        return self.scan(Path::new(root), true);
+    }
+
+    /// Adds the photos in `root` that are not in the catalog, and removes those that are no
+    /// longer there. With `save_progress`, we save after every change, so that an interrupted
+    /// sync keeps what it did.
+    fn scan(&mut self, root: &Path, save_progress: bool) -> Result<(), CommandError> {
+        // The table holds canonical paths, so we must compare them to canonical paths.
+        let root = root.canonicalize()?;
+        let images: Vec<_> = WalkDir::new(&root)
             .into_iter()
             // Skips all read errors
             .filter_map(|entry| entry.ok())
@@ -422,10 +1160,15 @@ impl SimplePhotoGallery {
         println!("Found {} images.\n", len);
         let mut images_on_disk: HashSet<_> = HashSet::new();
         for (n, image) in images.iter().enumerate() {
+            // An image that we fail to update is still on disk, so we must not remove it.
+            if let Ok(path) = image.path().canonicalize() {
+                images_on_disk.insert(path.to_string_lossy().to_string());
+            }
             match self.add_(image.path()) {
                 Ok(()) => {
-                    images_on_disk.insert(image.path().to_string_lossy().to_string());
-                    self.image_table.save(&self.config.image_table_path);
+                    if save_progress {
+                        self.save();
+                    }
                 }
                 Err(err) => {
                     println!("Error adding {}: {}", image.path().display(), err);
@@ -437,15 +1180,17 @@ impl SimplePhotoGallery {
         }
         let images_in_table: HashSet<_> = self
             .image_table
-            .rows
+            .rows()
             .iter()
-            .filter(|row| row.original_path.starts_with(root))
+            .filter(|row| Path::new(&row.original_path).starts_with(&root))
             .map(|row| row.original_path.to_string())
             .collect();
         for original_path in images_in_table.into_iter() {
-            if images_on_disk.contains(&original_path) == false {
-                if self.rm_(original_path).is_ok() {
-                    self.image_table.save(&self.config.image_table_path);
+            if images_on_disk.contains(&original_path) == false && self.rm_(&original_path).is_ok()
+            {
+                println!("{} removed", &original_path);
+                if save_progress {
+                    self.save();
                 }
             }
         }
@@ -453,6 +1198,27 @@ impl SimplePhotoGallery {
         return Ok(());
     }
 
+    /// Removes every photo in a directory, which may no longer exist.
+    pub fn rm_directory_(&mut self, directory: &Path) {
+        let paths: Vec<String> = self
+            .image_table
+            .rows()
+            .iter()
+            .filter(|row| Path::new(&row.original_path).starts_with(directory))
+            .map(|row| row.original_path.clone())
+            .collect();
+        for path in paths {
+            match self.rm_(&path) {
+                Ok(()) => println!("{} removed", &path),
+                Err(err) => eprintln!("{}\n\nError removing {}", err, &path),
+            }
+        }
+    }
+
+    pub fn sync_(&mut self, directory: &Path) -> Result<(), CommandError> {
+        return self.scan(directory, false);
+    }
+
     pub fn sync(&mut self, directory: String) {
         if let Err(err) = self.add_remove_path(&directory) {
             eprintln!("{}\n\nError synchronizing directory.", err);
@@ -0,0 +1,233 @@
+use super::error::*;
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net as unix;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::OnceLock;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UnixListener};
+
+/// A connection from any kind of listener, over TLS or not.
+pub trait Connection: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Connection for T {}
+
+/// A connection that a listener accepted, and the address of the client, which connections to a
+/// Unix socket do not have.
+pub struct Accepted {
+    pub connection: Box<dyn Connection>,
+    pub remote_addr: Option<SocketAddr>,
+}
+
+impl AsyncRead for Accepted {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        return Pin::new(&mut self.connection).poll_read(cx, buf);
+    }
+}
+
+impl AsyncWrite for Accepted {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        return Pin::new(&mut self.connection).poll_write(cx, buf);
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        return Pin::new(&mut self.connection).poll_flush(cx);
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        return Pin::new(&mut self.connection).poll_shutdown(cx);
+    }
+}
+
+/// Accepts a connection, and the address of the client, if it has one.
+type Accept<'a, C> = BoxFuture<'a, std::io::Result<(C, Option<SocketAddr>)>>;
+
+/// The connections that a listener accepts, in the form that we serve.
+pub type Incoming = Pin<Box<dyn Stream<Item = Result<Accepted, std::io::Error>> + Send>>;
+
+/// An address to listen on, from the command line.
+pub enum Bind {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+/// A socket that accepts connections.
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// The first file descriptor that systemd passes to a socket-activated service.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// How many sockets systemd passed to us, which `take_systemd_env` reads from the environment.
+static SYSTEMD_FDS: OnceLock<RawFd> = OnceLock::new();
+
+/// How long we wait after failing to accept a connection, e.g., because we have run out of file
+/// descriptors, before we try again.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Reads the variables that systemd sets for socket activation, and removes them from the
+/// environment, so that they do not reach the programs that we run, such as heif-convert. This
+/// must run before the runtime starts its threads, since changing the environment while another
+/// thread may read it is undefined behavior.
+pub fn take_systemd_env() {
+    let for_us = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| pid == std::process::id());
+    let count = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse::<RawFd>().ok())
+        .unwrap_or(0);
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+    let _ = SYSTEMD_FDS.set(if for_us { count } else { 0 });
+}
+
+/// Parses `unix:PATH`, or a host and port such as `127.0.0.1:8080`, `[::1]:8080` or
+/// `localhost:8080`. A host name may resolve to several addresses, and we listen on all of them.
+pub fn parse(text: &str) -> Result<Vec<Bind>, CommandError> {
+    if let Some(path) = text.strip_prefix("unix:") {
+        return Ok(vec![Bind::Unix(PathBuf::from(path))]);
+    }
+    let addrs = text
+        .to_socket_addrs()
+        .map_err(trace(format!("resolving {}", text)))?;
+    return Ok(addrs.map(Bind::Tcp).collect());
+}
+
+/// Resolves a host, which may be an IPv4 or IPv6 address or a host name, and a port.
+pub fn resolve(host: &str, port: u16) -> Result<Vec<Bind>, CommandError> {
+    let addrs = (host, port)
+        .to_socket_addrs()
+        .map_err(trace(format!("resolving {}", host)))?;
+    return Ok(addrs.map(Bind::Tcp).collect());
+}
+
+/// Accepts connections forever. Errors are reported and retried, since warp stops serving a
+/// stream of connections at its first error.
+fn accept_forever<L, C>(listener: L, accept: for<'a> fn(&'a mut L) -> Accept<'a, C>) -> Incoming
+where
+    L: Send + 'static,
+    C: Connection + 'static,
+{
+    let connections = stream::unfold(listener, move |mut listener| async move {
+        loop {
+            match accept(&mut listener).await {
+                Ok((connection, remote_addr)) => {
+                    let connection = Accepted {
+                        connection: Box::new(connection),
+                        remote_addr,
+                    };
+                    return Some((Ok(connection), listener));
+                }
+                Err(err) => {
+                    eprintln!("Error accepting connection: {}", err);
+                    tokio::time::delay_for(ACCEPT_RETRY_DELAY).await;
+                }
+            }
+        }
+    });
+    return Box::pin(connections);
+}
+
+impl Listener {
+    pub async fn bind(bind: &Bind) -> Result<Listener, CommandError> {
+        match bind {
+            Bind::Tcp(addr) => {
+                let listener = TcpListener::bind(addr)
+                    .await
+                    .map_err(trace(format!("binding {}", addr)))?;
+                return Ok(Listener::Tcp(listener));
+            }
+            Bind::Unix(path) => {
+                // A socket left behind by a previous run would keep us from binding. Nothing
+                // accepts connections on such a socket, whereas a server that is still running
+                // does, and we leave its socket alone.
+                if let Ok(metadata) = std::fs::symlink_metadata(path) {
+                    use std::os::unix::fs::FileTypeExt;
+                    if metadata.file_type().is_socket() {
+                        match unix::UnixStream::connect(path) {
+                            Ok(_) => {
+                                return Err(error(format!(
+                                    "another server is listening on {}",
+                                    path.to_string_lossy()
+                                )));
+                            }
+                            Err(err) if err.kind() == std::io::ErrorKind::ConnectionRefused => {
+                                std::fs::remove_file(path)?;
+                            }
+                            Err(_) => {}
+                        }
+                    }
+                }
+                let listener = UnixListener::bind(path)
+                    .map_err(trace(format!("binding {}", path.to_string_lossy())))?;
+                return Ok(Listener::Unix(listener));
+            }
+        }
+    }
+
+    /// The sockets that systemd passes to us with socket activation, if any.
+    pub fn systemd() -> Result<Vec<Listener>, CommandError> {
+        let count = SYSTEMD_FDS.get().copied().unwrap_or(0);
+        let mut listeners = vec![];
+        for fd in LISTEN_FDS_START..(LISTEN_FDS_START + count) {
+            // A TCP listener has a socket address, and a Unix listener does not.
+            let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+            if listener.local_addr().is_ok() {
+                listener.set_nonblocking(true)?;
+                let listener = TcpListener::from_std(listener)
+                    .map_err(trace(format!("using file descriptor {}", fd)))?;
+                listeners.push(Listener::Tcp(listener));
+                continue;
+            }
+            let listener = unsafe { unix::UnixListener::from_raw_fd(listener.into_raw_fd()) };
+            listener.set_nonblocking(true)?;
+            let listener = UnixListener::from_std(listener)
+                .map_err(trace(format!("using file descriptor {}", fd)))?;
+            listeners.push(Listener::Unix(listener));
+        }
+        return Ok(listeners);
+    }
+
+    /// The TCP address that we listen on, if any.
+    pub fn tcp_addr(&self) -> Option<SocketAddr> {
+        return match self {
+            Listener::Tcp(listener) => listener.local_addr().ok(),
+            Listener::Unix(_) => None,
+        };
+    }
+
+    pub fn incoming(self) -> Incoming {
+        return match self {
+            Listener::Tcp(listener) => accept_forever(listener, |listener| {
+                listener
+                    .accept()
+                    .map_ok(|(connection, addr)| (connection, Some(addr)))
+                    .boxed()
+            }),
+            Listener::Unix(listener) => accept_forever(listener, |listener| {
+                listener
+                    .accept()
+                    .map_ok(|(connection, _)| (connection, None))
+                    .boxed()
+            }),
+        };
+    }
+}
@@ -1,15 +1,43 @@
+// The codebase deliberately uses explicit returns and comparisons against false.
+#![allow(
+    clippy::needless_return,
+    clippy::bool_comparison,
+    clippy::redundant_static_lifetimes,
+    clippy::needless_borrows_for_generic_args
+)]
+
+mod albums;
+mod annotations;
+mod auth;
+mod capture;
+mod color;
 mod config;
+mod download;
+mod dupes;
 mod error;
+mod events;
 mod image_table;
+mod listen;
+mod map;
+mod metadata;
 mod monitor_fs;
+mod query;
 mod resources;
 mod server;
+mod share;
+mod sort;
+mod stacks;
 #[cfg(test)]
 mod tests;
+mod timeline;
+mod tls;
+mod watch;
+mod xmp;
 
 use clap::Clap;
+use error::CommandError;
 use futures::prelude::*;
-use std::net::SocketAddrV4;
+use image_table::AlbumCommand;
 
 #[derive(Clap)]
 #[clap(version = "1.0", author = "Arjun Guha")]
@@ -26,17 +54,61 @@ enum SubCommand {
     Rm(Rm),
     Sync(Sync),
     Stat(Stat),
+    Tag(Tag),
+    Rate(Rate),
+    Album(Album),
+    List(List),
+    Search(Search),
+    Dupes(Dupes),
+    Cover(Cover),
     Serve(Serve),
+    Watch(Watch),
+    User(User),
+    Session(Session),
+    Group(Group),
+    Acl(Acl),
+    Share(Share),
     Init,
 }
 
 #[derive(Clap)]
 struct Serve {
-    /// Port to listen on
+    /// Port to listen on, at --bind-address
     #[clap(long, short)]
-    port: u16,
+    port: Option<u16>,
+    /// IPv4 or IPv6 address or host name to listen on with --port
     #[clap(long, short, default_value = "127.0.0.1")]
     bind_address: String,
+    /// Path under which to serve the web interface, e.g., /photos behind a reverse proxy
+    #[clap(long, default_value = "/")]
+    base_path: String,
+    /// Build absolute links from the X-Forwarded-Proto, -Host and -Prefix headers, which only a
+    /// reverse proxy in front of the server should send
+    #[clap(long)]
+    trust_proxy: bool,
+    /// Another address to listen on, such as 0.0.0.0:8080, [::1]:8080 or unix:/run/spg.sock.
+    /// May be repeated.
+    #[clap(long)]
+    bind: Vec<String>,
+    /// Source directory to watch, as `spg watch` does. May be repeated.
+    #[clap(long, short)]
+    watch: Vec<String>,
+    /// PEM file with the certificate chain to serve HTTPS with. Reloaded when it changes.
+    #[clap(long, requires = "tls-key")]
+    tls_cert: Option<String>,
+    /// PEM file with the private key of the certificate
+    #[clap(long, requires = "tls-cert")]
+    tls_key: Option<String>,
+    /// Port on which to redirect plain HTTP requests to HTTPS, at --bind-address
+    #[clap(long, requires = "tls-cert")]
+    redirect_http: Option<u16>,
+}
+
+/// Watches source directories, and adds, updates and removes photos as they change
+#[derive(Clap)]
+struct Watch {
+    #[clap(required = true)]
+    directories: Vec<String>,
 }
 
 #[derive(Clap)]
@@ -59,16 +131,291 @@ struct Stat {
     filename: String,
 }
 
-// fn file_timestamp(p : impl AsRef<Path>) -> Result<u128, std::io::Error> {
-//     let metadata = fs::metadata(p)?;
-//     let modified_time = metadata.modified()?;
-//     let duration_since_epoch = modified_time.duration_since(std::time::UNIX_EPOCH)
-//         .expect("file modification time is bogus");
-//     return Ok(duration_since_epoch.as_millis());
-// }
+/// Adds tags to a photo, or removes them, and sets its caption
+#[derive(Clap)]
+struct Tag {
+    filename: String,
+    tags: Vec<String>,
+    /// Remove the tags instead of adding them
+    #[clap(long, short)]
+    remove: bool,
+    /// Caption for the photo. An empty caption removes the caption.
+    #[clap(long, short)]
+    caption: Option<String>,
+}
+
+/// Rates a photo from 0 to 5 stars, and marks or unmarks it as a favorite
+#[derive(Clap)]
+struct Rate {
+    filename: String,
+    rating: Option<u8>,
+    #[clap(long)]
+    favorite: bool,
+    #[clap(long, conflicts_with = "favorite")]
+    unfavorite: bool,
+}
+
+/// Manages albums, which collect photos from any directory
+#[derive(Clap)]
+struct Album {
+    #[clap(subcommand)]
+    subcmd: AlbumSubCommand,
+}
+
+#[derive(Clap)]
+enum AlbumSubCommand {
+    /// Creates an album. With --query, creates a smart album of the photos that match the query.
+    Create(AlbumCreate),
+    /// Deletes an album, but not its photos
+    Delete(AlbumName),
+    /// Adds photos to the end of an album
+    Add(AlbumPhotos),
+    /// Removes photos from an album
+    Remove(AlbumPhotos),
+    /// Lists the photos in an album
+    List(AlbumName),
+}
+
+#[derive(Clap)]
+struct AlbumCreate {
+    name: String,
+    #[clap(long, short)]
+    query: Option<String>,
+}
+
+#[derive(Clap)]
+struct AlbumName {
+    name: String,
+}
+
+#[derive(Clap)]
+struct AlbumPhotos {
+    name: String,
+    filenames: Vec<String>,
+}
+
+/// Prints the paths of the photos in a directory gallery
+#[derive(Clap)]
+struct List {
+    gallery: String,
+    /// One of name, date, modified, rating or size
+    #[clap(long, short, default_value = "name")]
+    sort: sort::SortKey,
+    /// Either asc or desc
+    #[clap(long, default_value = "asc")]
+    order: sort::Direction,
+}
+
+/// Prints the paths of the photos that match a query
+#[derive(Clap)]
+struct Search {
+    /// Put a query that starts with a negated term after --, e.g. spg search -- -tag:beach
+    query: String,
+    /// One of name, date, modified, rating or size
+    #[clap(long, short, default_value = "date")]
+    sort: sort::SortKey,
+    /// Either asc or desc
+    #[clap(long, default_value = "desc")]
+    order: sort::Direction,
+    #[clap(long, default_value = "0")]
+    offset: usize,
+    #[clap(long, short)]
+    limit: Option<usize>,
+}
+
+/// Prints groups of photos that look alike, such as resized copies, highest resolution first
+#[derive(Clap)]
+struct Dupes {
+    /// The largest number of bits in which the perceptual hashes of two photos may differ, up
+    /// to 16
+    #[clap(long, short)]
+    distance: Option<u32>,
+}
+
+/// Chooses a photo as the cover of its stack of burst shots
+#[derive(Clap)]
+struct Cover {
+    filename: String,
+}
+
+/// Manages the users who may log in to the web server. Logging in is only required once there
+/// is at least one user.
+#[derive(Clap)]
+struct User {
+    #[clap(subcommand)]
+    subcmd: UserSubCommand,
+}
+
+#[derive(Clap)]
+enum UserSubCommand {
+    /// Adds a user, or changes their password. Reads the password from standard input unless
+    /// --password is given.
+    Add(UserAdd),
+    /// Removes a user, and logs them out
+    Remove(UserName),
+    /// Lists users
+    List,
+}
 
-#[tokio::main]
-async fn main() {
+#[derive(Clap)]
+struct UserAdd {
+    name: String,
+    #[clap(long)]
+    password: Option<String>,
+}
+
+#[derive(Clap)]
+struct UserName {
+    name: String,
+}
+
+/// Manages groups of users, which ACLs may name as @GROUP
+#[derive(Clap)]
+struct Group {
+    #[clap(subcommand)]
+    subcmd: GroupSubCommand,
+}
+
+#[derive(Clap)]
+enum GroupSubCommand {
+    /// Adds users to a group, creating it if necessary
+    Add(GroupMembers),
+    /// Removes users from a group, or the whole group when no users are given
+    Remove(GroupMembers),
+    /// Lists groups and their members
+    List,
+}
+
+#[derive(Clap)]
+struct GroupMembers {
+    group: String,
+    users: Vec<String>,
+}
+
+/// Manages who may see the photos in a directory on the web server. The longest directory with
+/// an ACL that contains a photo decides who may see it. Photos outside every such directory are
+/// visible to every user.
+#[derive(Clap)]
+struct Acl {
+    #[clap(subcommand)]
+    subcmd: AclSubCommand,
+}
+
+#[derive(Clap)]
+enum AclSubCommand {
+    /// Allows users, or groups written @GROUP, to see the photos in a directory
+    Add(AclPrincipals),
+    /// Removes users or groups from the ACL of a directory, or the whole ACL when none are given
+    Remove(AclPrincipals),
+    /// Lists ACLs
+    List,
+}
+
+#[derive(Clap)]
+struct AclPrincipals {
+    directory: String,
+    principals: Vec<String>,
+}
+
+/// Manages the sessions of users who are logged in to the web server
+#[derive(Clap)]
+struct Session {
+    #[clap(subcommand)]
+    subcmd: SessionSubCommand,
+}
+
+#[derive(Clap)]
+enum SessionSubCommand {
+    /// Lists sessions that have not expired
+    List,
+    /// Logs out a session, every session of a user, or every session
+    Revoke(SessionRevoke),
+}
+
+#[derive(Clap)]
+struct SessionRevoke {
+    #[clap(required_unless_present_any(&["user", "all"]))]
+    id: Option<String>,
+    #[clap(long, short, conflicts_with = "id")]
+    user: Option<String>,
+    #[clap(long, conflicts_with_all = &["id", "user"])]
+    all: bool,
+}
+
+/// Prints a link that gives read-only access to a gallery or photo without logging in
+#[derive(Clap)]
+struct Share {
+    #[clap(required_unless_present_any(&["photo", "list", "revoke"]))]
+    gallery: Option<String>,
+    /// Shares a single photo instead of a gallery
+    #[clap(long, conflicts_with = "gallery")]
+    photo: Option<String>,
+    /// How long the link works, e.g., 12h, 7d or 2w
+    #[clap(long, short, default_value = "7d")]
+    expires: String,
+    /// Allows downloading originals too
+    #[clap(long)]
+    originals: bool,
+    /// Lists shares that have not expired
+    #[clap(long, conflicts_with_all = &["gallery", "photo", "revoke"])]
+    list: bool,
+    /// Revokes the share with this ID
+    #[clap(long, conflicts_with_all = &["gallery", "photo"])]
+    revoke: Option<String>,
+}
+
+fn watch_or_exit(data_dir: &str, directories: &[String]) {
+    let result = watch::Watcher::new(data_dir, directories).and_then(|mut watcher| watcher.run());
+    if let Err(err) = result {
+        eprintln!("{}\n\nError watching source directories", err);
+        std::process::exit(1);
+    }
+}
+
+/// Binds the addresses given on the command line, and adds any sockets passed by systemd.
+async fn listen(serve: &Serve) -> Result<Vec<listen::Listener>, CommandError> {
+    let mut binds = vec![];
+    if let Some(port) = serve.port {
+        binds.extend(listen::resolve(&serve.bind_address, port)?);
+    }
+    for bind in &serve.bind {
+        binds.extend(listen::parse(bind)?);
+    }
+    let mut listeners = listen::Listener::systemd()?;
+    for bind in &binds {
+        listeners.push(listen::Listener::bind(bind).await?);
+    }
+    if listeners.is_empty() {
+        return Err(error::error(
+            "nothing to listen on, give --port or --bind, or use systemd socket activation",
+        ));
+    }
+    return Ok(listeners);
+}
+
+/// Binds the port that redirects to HTTPS, at the same addresses as --port.
+async fn listen_for_redirect(
+    serve: &Serve,
+    http_port: u16,
+) -> Result<Vec<listen::Listener>, CommandError> {
+    let mut listeners = vec![];
+    for bind in listen::resolve(&serve.bind_address, http_port)? {
+        listeners.push(listen::Listener::bind(&bind).await?);
+    }
+    return Ok(listeners);
+}
+
+fn main() {
+    listen::take_systemd_env();
+    let mut runtime = tokio::runtime::Builder::new()
+        .threaded_scheduler()
+        .enable_all()
+        .build()
+        .expect("starting the runtime");
+    runtime.block_on(run());
+}
+
+async fn run() {
     let opts = Opts::parse();
 
     let data_dir = resources::get_data_dir_or_exit(opts.config_path);
@@ -78,33 +425,188 @@ async fn main() {
             resources::init(data_dir);
         }
         SubCommand::Add(add) => {
-            let mut spg = image_table::SimplePhotoGallery::new(data_dir);
+            let mut spg = image_table::SimplePhotoGallery::lock(data_dir);
             spg.add(add.filename);
         }
         SubCommand::Rm(add) => {
-            let mut spg = image_table::SimplePhotoGallery::new(data_dir);
+            let mut spg = image_table::SimplePhotoGallery::lock(data_dir);
             spg.rm(add.filename);
         }
         SubCommand::Sync(sync) => {
-            let mut spg = image_table::SimplePhotoGallery::new(data_dir);
+            let mut spg = image_table::SimplePhotoGallery::lock(data_dir);
             spg.sync(sync.directory);
         }
         SubCommand::Stat(stat) => {
             let mut spg = image_table::SimplePhotoGallery::new(data_dir);
             spg.stat(stat.filename);
         }
+        SubCommand::Tag(tag) => {
+            let mut spg = image_table::SimplePhotoGallery::lock(data_dir);
+            let mut edit = annotations::AnnotationEdit {
+                caption: tag.caption,
+                ..Default::default()
+            };
+            if tag.remove {
+                edit.remove_tags = Some(tag.tags);
+            } else {
+                edit.add_tags = Some(tag.tags);
+            }
+            spg.annotate(tag.filename, edit);
+        }
+        SubCommand::Rate(rate) => {
+            let mut spg = image_table::SimplePhotoGallery::lock(data_dir);
+            let favorite = if rate.favorite {
+                Some(true)
+            } else if rate.unfavorite {
+                Some(false)
+            } else {
+                None
+            };
+            let edit = annotations::AnnotationEdit {
+                rating: rate.rating,
+                favorite,
+                ..Default::default()
+            };
+            spg.annotate(rate.filename, edit);
+        }
+        SubCommand::Album(album) => {
+            let mut spg = image_table::SimplePhotoGallery::lock(data_dir);
+            let command = match album.subcmd {
+                AlbumSubCommand::Create(create) => AlbumCommand::Create(create.name, create.query),
+                AlbumSubCommand::Delete(delete) => AlbumCommand::Delete(delete.name),
+                AlbumSubCommand::Add(add) => AlbumCommand::Add(add.name, add.filenames),
+                AlbumSubCommand::Remove(rm) => AlbumCommand::Remove(rm.name, rm.filenames),
+                AlbumSubCommand::List(list) => AlbumCommand::List(list.name),
+            };
+            spg.album(command);
+        }
+        SubCommand::List(list) => {
+            let spg = image_table::SimplePhotoGallery::new(data_dir);
+            spg.list(&list.gallery, list.sort, list.order);
+        }
+        SubCommand::Search(search) => {
+            let spg = image_table::SimplePhotoGallery::new(data_dir);
+            spg.search(
+                &search.query,
+                search.sort,
+                search.order,
+                search.offset,
+                search.limit.unwrap_or(usize::MAX),
+            );
+        }
+        SubCommand::Dupes(dupes) => {
+            let spg = image_table::SimplePhotoGallery::new(data_dir);
+            spg.dupes(dupes.distance.unwrap_or(dupes::DEFAULT_DISTANCE));
+        }
+        SubCommand::Cover(cover) => {
+            let mut spg = image_table::SimplePhotoGallery::lock(data_dir);
+            spg.cover(cover.filename);
+        }
+        SubCommand::Watch(watch) => {
+            watch_or_exit(&data_dir, &watch.directories);
+        }
+        SubCommand::User(user) => {
+            let config = config::Config::new(data_dir);
+            let command = match user.subcmd {
+                UserSubCommand::Add(add) => auth::UserCommand::Add(add.name, add.password),
+                UserSubCommand::Remove(remove) => auth::UserCommand::Remove(remove.name),
+                UserSubCommand::List => auth::UserCommand::List,
+            };
+            auth::user_command(&config, command);
+        }
+        SubCommand::Group(group) => {
+            let config = config::Config::new(data_dir);
+            let command = match group.subcmd {
+                GroupSubCommand::Add(add) => auth::GroupCommand::Add(add.group, add.users),
+                GroupSubCommand::Remove(rm) => auth::GroupCommand::Remove(rm.group, rm.users),
+                GroupSubCommand::List => auth::GroupCommand::List,
+            };
+            auth::group_command(&config, command);
+        }
+        SubCommand::Acl(acl) => {
+            let config = config::Config::new(data_dir);
+            let command = match acl.subcmd {
+                AclSubCommand::Add(add) => auth::AclCommand::Add(add.directory, add.principals),
+                AclSubCommand::Remove(rm) => auth::AclCommand::Remove(rm.directory, rm.principals),
+                AclSubCommand::List => auth::AclCommand::List,
+            };
+            auth::acl_command(&config, command);
+        }
+        SubCommand::Session(session) => {
+            let config = config::Config::new(data_dir);
+            let command = match session.subcmd {
+                SessionSubCommand::List => auth::SessionCommand::List,
+                SessionSubCommand::Revoke(revoke) => {
+                    let revoke = match (revoke.id, revoke.user, revoke.all) {
+                        (Some(id), _, _) => auth::Revoke::Id(id),
+                        (None, Some(user), _) => auth::Revoke::User(user),
+                        (None, None, _) => auth::Revoke::All,
+                    };
+                    auth::SessionCommand::Revoke(revoke)
+                }
+            };
+            auth::session_command(&config, command);
+        }
+        SubCommand::Share(share) => {
+            let spg = image_table::SimplePhotoGallery::new(data_dir);
+            let command = if share.list {
+                share::ShareCommand::List
+            } else if let Some(id) = share.revoke {
+                share::ShareCommand::Revoke(id)
+            } else {
+                let seconds = share::parse_duration(&share.expires).unwrap_or_else(|err| {
+                    eprintln!("{}", err);
+                    std::process::exit(1);
+                });
+                let scope = match (share.gallery, share.photo) {
+                    (_, Some(photo)) => share::Scope::Photo(photo),
+                    (gallery, None) => share::Scope::Gallery(gallery.unwrap_or_default()),
+                };
+                share::ShareCommand::Create(scope, share.originals, seconds)
+            };
+            share::share_command(&spg.config, &spg.image_table, command);
+        }
         SubCommand::Serve(serve) => {
-            let mut changes = monitor_fs::monitor_changes(&data_dir);
-            loop {
-                let until = changes.next().await.expect("receive");
-                let spg = image_table::SimplePhotoGallery::new(&data_dir);
-                let sock_addr = SocketAddrV4::new(
-                    serve.bind_address.parse().expect("invalid address"),
-                    serve.port,
-                );
-                server::serve(sock_addr, until.map(|_| ()), spg.config, spg.image_table).await;
-                eprintln!("Restarting server");
+            if serve.watch.is_empty() == false {
+                let data_dir = data_dir.clone();
+                let directories = serve.watch.clone();
+                std::thread::spawn(move || watch_or_exit(&data_dir, &directories));
+            }
+            let spg = image_table::SimplePhotoGallery::new(&data_dir);
+            let listeners = listen(&serve).await.unwrap_or_else(|err| {
+                eprintln!("{}\n\nError starting server", err);
+                std::process::exit(1);
+            });
+            if let Some(http_port) = serve.redirect_http {
+                let https_port = listeners.iter().find_map(|listener| listener.tcp_addr());
+                let https_port = https_port.map(|addr| addr.port()).unwrap_or(443);
+                let redirects = listen_for_redirect(&serve, http_port).await;
+                let redirects = redirects.unwrap_or_else(|err| {
+                    eprintln!("{}\n\nError starting HTTP redirect", err);
+                    std::process::exit(1);
+                });
+                for redirect in redirects {
+                    tokio::spawn(tls::redirect_http(redirect, https_port));
+                }
             }
+            let tls = match (serve.tls_cert, serve.tls_key) {
+                (Some(cert_path), Some(key_path)) => Some(tls::TlsFiles {
+                    cert_path: cert_path.into(),
+                    key_path: key_path.into(),
+                }),
+                _ => None,
+            };
+            let until = future::pending();
+            server::serve(
+                listeners,
+                &serve.base_path,
+                serve.trust_proxy,
+                tls,
+                until,
+                spg.config,
+                spg.image_table,
+            )
+            .await;
         }
     };
 }
@@ -0,0 +1,201 @@
+use super::image_table::{ImageTable, Row};
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// At this zoom level and above, every photo is its own point.
+const MAX_CLUSTER_ZOOM: u32 = 17;
+/// Points that are closer than this on screen (assuming 256 pixel map tiles) are clustered.
+const CLUSTER_RADIUS_PIXELS: f64 = 60.0;
+
+#[derive(Deserialize)]
+pub struct MapQuery {
+    /// The visible area as `west,south,east,north` in degrees. When west is greater than east,
+    /// the box crosses the antimeridian.
+    pub bbox: Option<String>,
+    pub zoom: Option<u32>,
+    pub gallery: Option<String>,
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+}
+
+#[derive(Serialize)]
+pub struct Geometry {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    /// Longitude, then latitude, as GeoJSON requires.
+    pub coordinates: [f64; 2],
+}
+
+#[derive(Serialize)]
+pub struct Properties<'a> {
+    /// The number of photos at this point. It is greater than one for clusters.
+    pub count: usize,
+    /// A photo that represents the point: the newest photo in a cluster.
+    pub md5: String,
+    pub thumbnail_path: &'a str,
+    pub webview_path: &'a str,
+    pub title: &'a str,
+}
+
+#[derive(Serialize)]
+pub struct Feature<'a> {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub geometry: Geometry,
+    pub properties: Properties<'a>,
+}
+
+#[derive(Serialize)]
+pub struct FeatureCollection<'a> {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub features: Vec<Feature<'a>>,
+}
+
+struct BoundingBox {
+    west: f64,
+    south: f64,
+    east: f64,
+    north: f64,
+}
+
+impl BoundingBox {
+    fn parse(s: &str) -> Option<BoundingBox> {
+        let parts: Vec<f64> = s
+            .split(',')
+            .map(|part| part.trim().parse().ok())
+            .collect::<Option<_>>()?;
+        if parts.len() != 4 {
+            return None;
+        }
+        return Some(BoundingBox {
+            west: parts[0],
+            south: parts[1],
+            east: parts[2],
+            north: parts[3],
+        });
+    }
+
+    fn contains(&self, latitude: f64, longitude: f64) -> bool {
+        if latitude < self.south || latitude > self.north {
+            return false;
+        }
+        if self.west <= self.east {
+            return longitude >= self.west && longitude <= self.east;
+        }
+        return longitude >= self.west || longitude <= self.east;
+    }
+}
+
+struct Cluster<'a> {
+    latitude_sum: f64,
+    longitude_sum: f64,
+    representative: &'a Row,
+    count: usize,
+}
+
+/// The key of a cluster: a cell of the grid, or a single row when we do not cluster.
+#[derive(PartialEq, Eq, Hash)]
+enum Cell {
+    Grid(i64, i64),
+    Row(usize),
+}
+
+/// Returns the geotagged photos that match `query` as GeoJSON points. Below `MAX_CLUSTER_ZOOM`,
+/// photos are clustered on a grid whose cells are about `CLUSTER_RADIUS_PIXELS` wide on screen,
+/// and each cluster is placed at the mean position of its photos. Returns `None` if the
+/// bounding box is malformed.
+pub fn features<'a>(
+    image_table: &'a ImageTable,
+    query: &MapQuery,
+) -> Option<FeatureCollection<'a>> {
+    let bbox = match &query.bbox {
+        None => None,
+        Some(bbox) => Some(BoundingBox::parse(bbox)?),
+    };
+    let zoom = query.zoom.unwrap_or(MAX_CLUSTER_ZOOM).min(MAX_CLUSTER_ZOOM);
+    let cell_degrees = 360.0 / 2f64.powi(zoom as i32) * CLUSTER_RADIUS_PIXELS / 256.0;
+
+    let mut clusters: HashMap<Cell, Cluster> = HashMap::new();
+    for (index, row) in image_table.rows().iter().enumerate() {
+        let gps = match row.capture().gps {
+            Some(gps) => gps,
+            None => continue,
+        };
+        if bbox
+            .as_ref()
+            .is_some_and(|bbox| bbox.contains(gps.latitude, gps.longitude) == false)
+        {
+            continue;
+        }
+        if query.gallery.as_ref().is_some_and(|g| *g != row.gallery()) {
+            continue;
+        }
+        let date = row.date();
+        if query.from.is_some_and(|from| date < from) || query.to.is_some_and(|to| date > to) {
+            continue;
+        }
+        let cell = if zoom >= MAX_CLUSTER_ZOOM {
+            Cell::Row(index)
+        } else {
+            Cell::Grid(
+                (gps.latitude / cell_degrees).floor() as i64,
+                (gps.longitude / cell_degrees).floor() as i64,
+            )
+        };
+        let cluster = clusters.entry(cell).or_insert(Cluster {
+            latitude_sum: 0.0,
+            longitude_sum: 0.0,
+            representative: row,
+            count: 0,
+        });
+        cluster.latitude_sum += gps.latitude;
+        cluster.longitude_sum += gps.longitude;
+        cluster.count += 1;
+        if (row.timestamp(), row.md5())
+            > (
+                cluster.representative.timestamp(),
+                cluster.representative.md5(),
+            )
+        {
+            cluster.representative = row;
+        }
+    }
+
+    let mut clusters: Vec<Cluster> = clusters.into_values().collect();
+    // Largest clusters first, so that clients that draw in order put small clusters on top.
+    clusters.sort_by_key(|cluster| {
+        (
+            std::cmp::Reverse(cluster.count),
+            cluster.representative.md5(),
+        )
+    });
+    let features = clusters
+        .into_iter()
+        .map(|cluster| {
+            let row = cluster.representative;
+            Feature {
+                kind: "Feature",
+                geometry: Geometry {
+                    kind: "Point",
+                    coordinates: [
+                        cluster.longitude_sum / cluster.count as f64,
+                        cluster.latitude_sum / cluster.count as f64,
+                    ],
+                },
+                properties: Properties {
+                    count: cluster.count,
+                    md5: format!("{:x}", row.md5()),
+                    thumbnail_path: row.thumbnail_path(),
+                    webview_path: row.webview_path(),
+                    title: row.title(),
+                },
+            }
+        })
+        .collect();
+    return Some(FeatureCollection {
+        kind: "FeatureCollection",
+        features,
+    });
+}
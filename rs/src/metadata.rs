@@ -0,0 +1,125 @@
+use super::error::*;
+use super::xmp::{IPTC_PREFIX, XMP_PREFIX};
+use exif::experimental::Writer;
+use exif::{Context, Exif, In, Tag};
+use image::{DynamicImage, ImageOutputFormat};
+use img_parts::jpeg::{markers, Jpeg};
+use img_parts::ImageEXIF;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+
+/// What metadata we are willing to publish, in derivatives and (optionally) in originals that
+/// are downloaded from the web server.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum MetadataPolicy {
+    KeepAll,
+    StripGps,
+    StripAll,
+}
+
+impl Default for MetadataPolicy {
+    fn default() -> Self {
+        return MetadataPolicy::StripGps;
+    }
+}
+
+/// Reads the EXIF data of an image. The reader supports both JPEG and HEIC containers.
+pub fn read_exif(p: impl AsRef<Path>) -> Option<Exif> {
+    let file = fs::File::open(p).ok()?;
+    let mut buf_reader = std::io::BufReader::new(&file);
+    return exif::Reader::new()
+        .read_from_container(&mut buf_reader)
+        .ok();
+}
+
+/// Serializes the fields of `exif` that `policy` allows. We only keep the primary IFD: the
+/// second IFD holds an embedded thumbnail, which may not agree with the image we produce.
+///
+/// Derivatives are rotated upright when they are generated, so they must not carry the
+/// orientation tag of the original, or viewers would rotate them a second time.
+///
+/// If the EXIF data cannot be re-encoded, we return `None` and drop the EXIF data entirely,
+/// since it is better to lose metadata than to leak it.
+fn rewrite_exif(exif: &Exif, policy: MetadataPolicy, is_derivative: bool) -> Option<Vec<u8>> {
+    if policy == MetadataPolicy::StripAll {
+        return None;
+    }
+    let mut writer = Writer::new();
+    let mut is_empty = true;
+    for field in exif.fields() {
+        if field.ifd_num != In::PRIMARY {
+            continue;
+        }
+        if policy == MetadataPolicy::StripGps && field.tag.0 == Context::Gps {
+            continue;
+        }
+        if is_derivative && field.tag == Tag::Orientation {
+            continue;
+        }
+        writer.push_field(field);
+        is_empty = false;
+    }
+    if is_empty {
+        return None;
+    }
+    let mut buf = Cursor::new(Vec::new());
+    writer.write(&mut buf, exif.little_endian()).ok()?;
+    return Some(buf.into_inner());
+}
+
+/// Removes the XMP and IPTC segments of a JPEG that `policy` does not allow. XMP may repeat the
+/// GPS coordinates in the EXIF data, so we drop any XMP packet that mentions GPS.
+fn strip_xmp_and_iptc(jpeg: &mut Jpeg, policy: MetadataPolicy) {
+    jpeg.segments_mut().retain(|segment| {
+        let contents = segment.contents();
+        match policy {
+            MetadataPolicy::KeepAll => true,
+            MetadataPolicy::StripGps => {
+                !(segment.marker() == markers::APP1
+                    && contents.starts_with(XMP_PREFIX)
+                    && contents.windows(3).any(|w| w == b"GPS"))
+            }
+            MetadataPolicy::StripAll => {
+                !(segment.marker() == markers::APP1 && contents.starts_with(XMP_PREFIX)
+                    || segment.marker() == markers::APP13 && contents.starts_with(IPTC_PREFIX))
+            }
+        }
+    });
+}
+
+/// Saves a derivative as a JPEG, with the metadata of the original that `policy` allows.
+pub fn save_jpeg(
+    image: &DynamicImage,
+    path: impl AsRef<Path>,
+    original_exif: Option<&Exif>,
+    policy: MetadataPolicy,
+) -> Result<(), CommandError> {
+    let mut bytes = Vec::new();
+    image.write_to(&mut bytes, ImageOutputFormat::Jpeg(75))?;
+    let exif = original_exif.and_then(|exif| rewrite_exif(exif, policy, true));
+    if exif.is_some() {
+        let mut jpeg = Jpeg::from_bytes(bytes.into()).map_err(|err| error(format!("{}", err)))?;
+        jpeg.set_exif(exif.map(|exif| exif.into()));
+        bytes = jpeg.encoder().bytes().to_vec();
+    }
+    fs::write(path, bytes)?;
+    return Ok(());
+}
+
+/// Applies `policy` to the bytes of a JPEG, without touching the file that they came from.
+pub fn strip_jpeg(bytes: Vec<u8>, policy: MetadataPolicy) -> Result<Vec<u8>, CommandError> {
+    if policy == MetadataPolicy::KeepAll {
+        return Ok(bytes);
+    }
+    let mut jpeg = Jpeg::from_bytes(bytes.into()).map_err(|err| error(format!("{}", err)))?;
+    let exif = jpeg
+        .exif()
+        .and_then(|tiff| exif::Reader::new().read_raw(tiff.to_vec()).ok())
+        .and_then(|exif| rewrite_exif(&exif, policy, false));
+    jpeg.set_exif(exif.map(|exif| exif.into()));
+    strip_xmp_and_iptc(&mut jpeg, policy);
+    return Ok(jpeg.encoder().bytes().to_vec());
+}
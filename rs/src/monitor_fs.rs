@@ -1,32 +1,48 @@
-use futures::channel::mpsc;
-use futures::channel::oneshot;
 use futures::prelude::*;
 use inotify::Inotify;
-use tokio::task;
+use std::path::Path;
 
-/// Monitor the provided path for changes.
-pub fn monitor_changes(path: &str) -> mpsc::Receiver<oneshot::Receiver<()>> {
-    let (mut send, recv) = mpsc::channel(1);
+/// Files in the data directory that the server reloads when they change.
+const WATCHED_FILES: [&str; 5] = [
+    "image_table.bincode",
+    "settings.json",
+    "users.json",
+    "sessions.json",
+    "shares.json",
+];
+
+/// Monitor the provided data directory, and yield the name of each watched file that is
+/// written.
+pub fn monitor_changes(path: &str) -> impl Stream<Item = String> + Unpin {
+    let names = WATCHED_FILES.iter().map(|name| name.to_string()).collect();
+    return monitor_files(Path::new(path), names);
+}
+
+/// Monitor a directory, and yield the name of each of `names` in it that is written. We watch
+/// for writes that complete, and for files renamed into place, since the catalog is saved by
+/// renaming a temporary file over it.
+pub fn monitor_files(directory: &Path, names: Vec<String>) -> impl Stream<Item = String> + Unpin {
     let mut inotify = Inotify::init().expect("initializing INotify");
     inotify
-        .add_watch(path, inotify::WatchMask::MODIFY)
+        .add_watch(
+            directory,
+            inotify::WatchMask::CLOSE_WRITE | inotify::WatchMask::MOVED_TO,
+        )
         .expect("watching path");
     // NOTE(arjun): It is surprising that I need to create this buffer here. Why doesn't the
     // API create it itself, and let me pick its size?
     let buffer = [0; 1024];
-    let mut modify_events = inotify
+    let events = inotify
         .event_stream(buffer)
         .expect("receiving INotify events");
-
-    task::spawn(async move {
-        loop {
-            let (o_send, o_recv) = oneshot::channel();
-            if let Err(_) = send.send(o_recv).await {
-                break;
-            }
-            modify_events.next().await;
-            o_send.send(()).unwrap();
-        }
+    return events.filter_map(move |event| {
+        let name = match event {
+            Ok(event) => event
+                .name
+                .map(|name| name.to_string_lossy().to_string())
+                .filter(|name| names.contains(name)),
+            Err(_) => None,
+        };
+        return future::ready(name);
     });
-    return recv;
 }
@@ -0,0 +1,301 @@
+use super::annotations::Annotations;
+use super::image_table::{ImageTable, Row, RowView};
+use super::sort::{self, Direction, SortKey};
+use chrono::NaiveDate;
+use serde::Serialize;
+
+/// A query is a list of terms separated by spaces, all of which must match. A term is either a
+/// word, which matches the filename, path, title or captions of a photo, or `field:value`.
+/// Values that contain spaces are quoted, e.g., `tag:"new york"`. A term that starts with `-` is
+/// negated.
+///
+/// Fields:
+///
+/// - `tag:T` matches photos tagged with T, either in spg or in their XMP or IPTC keywords
+/// - `gallery:G` matches photos in the directory gallery G
+/// - `path:P` matches photos whose path contains P
+/// - `title:T` matches photos whose filename or XMP title contains T
+/// - `rating:N`, `rating:>=N`, etc. compare the star rating of a photo in spg, or else in its XMP
+/// - `is:favorite` matches favorite photos
+/// - `date:2021`, `date:2021-07` and `date:2021-07-04` match photos taken in that year, month or
+///   day. `date:2021-01..2021-06` matches a range, and `date:>=2021-07`, etc. compare dates.
+/// - `camera:C` matches photos whose camera make and model contain C
+/// - `width:N` and `height:N` compare pixel dimensions, with the same operators as `rating`
+/// - `type:jpeg` and `type:heic` match the format of the original
+///
+/// Words and the values of `tag`, `path`, `title` and `camera` are case-insensitive.
+pub struct Query {
+    terms: Vec<(bool, Term)>,
+}
+
+enum Term {
+    Text(String),
+    Tag(String),
+    Gallery(String),
+    Path(String),
+    Title(String),
+    Rating(Comparison, u8),
+    Favorite,
+    /// Taken on or after the first date, and before the second.
+    Date(NaiveDate, NaiveDate),
+    Camera(String),
+    Width(Comparison, u32),
+    Height(Comparison, u32),
+    MediaType(&'static str),
+}
+
+#[derive(Serialize)]
+pub struct SearchResults<'a> {
+    /// The number of photos that match, of which `results` is a page.
+    pub total: usize,
+    pub offset: usize,
+    pub results: Vec<RowView<'a>>,
+}
+
+#[derive(Clone, Copy)]
+enum Comparison {
+    Lt,
+    Le,
+    Eq,
+    Ge,
+    Gt,
+}
+
+impl Comparison {
+    /// Splits a comparison operator off the front of `value`. No operator means equality.
+    fn parse(value: &str) -> (Comparison, &str) {
+        for (prefix, comparison) in [
+            ("<=", Comparison::Le),
+            (">=", Comparison::Ge),
+            ("<", Comparison::Lt),
+            (">", Comparison::Gt),
+            ("=", Comparison::Eq),
+        ] {
+            if let Some(rest) = value.strip_prefix(prefix) {
+                return (comparison, rest);
+            }
+        }
+        return (Comparison::Eq, value);
+    }
+
+    fn test<T: PartialOrd>(self, lhs: T, rhs: T) -> bool {
+        return match self {
+            Comparison::Lt => lhs < rhs,
+            Comparison::Le => lhs <= rhs,
+            Comparison::Eq => lhs == rhs,
+            Comparison::Ge => lhs >= rhs,
+            Comparison::Gt => lhs > rhs,
+        };
+    }
+}
+
+/// Splits a query into terms at spaces that are not in quotes, and removes the quotes.
+fn tokenize(s: &str) -> Result<Vec<String>, String> {
+    let mut tokens = vec![];
+    let mut token = String::new();
+    let mut in_quotes = false;
+    for c in s.chars() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            c if c.is_whitespace() && in_quotes == false => {
+                if token.is_empty() == false {
+                    tokens.push(std::mem::take(&mut token));
+                }
+            }
+            c => token.push(c),
+        }
+    }
+    if in_quotes {
+        return Err(String::from("unterminated quote"));
+    }
+    if token.is_empty() == false {
+        tokens.push(token);
+    }
+    return Ok(tokens);
+}
+
+/// Parses `2021`, `2021-07` or `2021-07-04` into the first day of the period and the first day
+/// after it.
+fn parse_period(s: &str) -> Result<(NaiveDate, NaiveDate), String> {
+    let invalid = || format!("invalid date: {}", s);
+    let parts: Vec<&str> = s.split('-').collect();
+    let numbers: Vec<u32> = parts
+        .iter()
+        .map(|part| part.parse().map_err(|_| invalid()))
+        .collect::<Result<_, _>>()?;
+    let year = numbers[0] as i32;
+    let (start, end) = match numbers.len() {
+        1 => (
+            NaiveDate::from_ymd_opt(year, 1, 1),
+            NaiveDate::from_ymd_opt(year + 1, 1, 1),
+        ),
+        2 => {
+            let start = NaiveDate::from_ymd_opt(year, numbers[1], 1);
+            let end = start.and_then(|start| start.checked_add_months(chrono::Months::new(1)));
+            (start, end)
+        }
+        3 => {
+            let start = NaiveDate::from_ymd_opt(year, numbers[1], numbers[2]);
+            (start, start.and_then(|start| start.succ_opt()))
+        }
+        _ => (None, None),
+    };
+    return start.zip(end).ok_or_else(invalid);
+}
+
+fn parse_date_term(value: &str) -> Result<Term, String> {
+    if let Some((from, to)) = value.split_once("..") {
+        return Ok(Term::Date(parse_period(from)?.0, parse_period(to)?.1));
+    }
+    let (comparison, value) = Comparison::parse(value);
+    let (start, end) = parse_period(value)?;
+    return Ok(match comparison {
+        Comparison::Eq => Term::Date(start, end),
+        Comparison::Ge => Term::Date(start, NaiveDate::MAX),
+        Comparison::Gt => Term::Date(end, NaiveDate::MAX),
+        Comparison::Lt => Term::Date(NaiveDate::MIN, start),
+        Comparison::Le => Term::Date(NaiveDate::MIN, end),
+    });
+}
+
+fn parse_number<T: std::str::FromStr>(field: &str, value: &str) -> Result<(Comparison, T), String> {
+    let (comparison, value) = Comparison::parse(value);
+    let number = value
+        .parse()
+        .map_err(|_| format!("invalid {}: {}", field, value))?;
+    return Ok((comparison, number));
+}
+
+fn parse_term(token: &str) -> Result<Term, String> {
+    let (field, value) = match token.split_once(':') {
+        None => return Ok(Term::Text(token.to_lowercase())),
+        Some(pair) => pair,
+    };
+    match field.to_lowercase().as_str() {
+        "tag" => return Ok(Term::Tag(value.to_lowercase())),
+        "gallery" => return Ok(Term::Gallery(value.to_string())),
+        "path" => return Ok(Term::Path(value.to_lowercase())),
+        "title" => return Ok(Term::Title(value.to_lowercase())),
+        "rating" => {
+            let (comparison, rating) = parse_number("rating", value)?;
+            return Ok(Term::Rating(comparison, rating));
+        }
+        "is" if value.eq_ignore_ascii_case("favorite") => return Ok(Term::Favorite),
+        "date" => return parse_date_term(value),
+        "camera" => return Ok(Term::Camera(value.to_lowercase())),
+        "width" => {
+            let (comparison, width) = parse_number("width", value)?;
+            return Ok(Term::Width(comparison, width));
+        }
+        "height" => {
+            let (comparison, height) = parse_number("height", value)?;
+            return Ok(Term::Height(comparison, height));
+        }
+        "type" => match value.to_lowercase().as_str() {
+            "jpeg" | "jpg" => return Ok(Term::MediaType("jpeg")),
+            "heic" => return Ok(Term::MediaType("heic")),
+            _ => return Err(format!("unknown media type: {}", value)),
+        },
+        _ => return Err(format!("unknown search term: {}", token)),
+    }
+}
+
+impl Query {
+    pub fn parse(s: &str) -> Result<Query, String> {
+        let mut terms = vec![];
+        for token in tokenize(s)? {
+            let (negated, token) = match token.strip_prefix('-') {
+                Some(rest) if rest.is_empty() == false => (true, rest.to_string()),
+                _ => (false, token),
+            };
+            terms.push((negated, parse_term(&token)?));
+        }
+        return Ok(Query { terms });
+    }
+
+    pub fn matches(&self, row: &Row, annotations: &Annotations) -> bool {
+        return self
+            .terms
+            .iter()
+            .all(|(negated, term)| term.matches(row, annotations) != *negated);
+    }
+}
+
+impl Term {
+    fn matches(&self, row: &Row, annotations: &Annotations) -> bool {
+        match self {
+            Term::Text(text) => {
+                return std::iter::once(row.title())
+                    .chain(std::iter::once(row.original_path.as_str()))
+                    .chain(row.description().title.as_deref())
+                    .chain(row.description().caption.as_deref())
+                    .chain(annotations.caption.as_deref())
+                    .any(|t| t.to_lowercase().contains(text));
+            }
+            Term::Tag(tag) => {
+                return annotations
+                    .tags
+                    .iter()
+                    .chain(row.description().keywords.iter())
+                    .any(|t| t.to_lowercase() == *tag);
+            }
+            Term::Gallery(gallery) => return row.gallery() == *gallery,
+            Term::Path(path) => return row.original_path.to_lowercase().contains(path),
+            Term::Title(title) => {
+                return row
+                    .description()
+                    .title
+                    .as_deref()
+                    .into_iter()
+                    .chain(std::iter::once(row.title()))
+                    .any(|t| t.to_lowercase().contains(title));
+            }
+            Term::Rating(comparison, rating) => {
+                return comparison.test(annotations.rating_or(row.description()), *rating);
+            }
+            Term::Favorite => return annotations.favorite,
+            Term::Date(start, end) => {
+                let date = row.date();
+                return *start <= date && date < *end;
+            }
+            Term::Camera(camera) => {
+                let make_and_model = format!(
+                    "{} {}",
+                    row.capture().make.as_deref().unwrap_or(""),
+                    row.capture().model.as_deref().unwrap_or("")
+                );
+                return make_and_model.to_lowercase().contains(camera);
+            }
+            Term::Width(comparison, width) => return comparison.test(row.width(), *width),
+            Term::Height(comparison, height) => return comparison.test(row.height(), *height),
+            Term::MediaType(media_type) => return row.media_type() == *media_type,
+        }
+    }
+}
+
+/// Searches the catalog, and returns the page of results that starts at `offset`.
+pub fn search<'a>(
+    image_table: &'a ImageTable,
+    query: &Query,
+    key: SortKey,
+    direction: Direction,
+    offset: usize,
+    limit: usize,
+) -> SearchResults<'a> {
+    let mut rows: Vec<&Row> = image_table
+        .rows()
+        .iter()
+        .filter(|row| query.matches(row, &image_table.annotations(row.md5())))
+        .collect();
+    sort::sort_rows(image_table, &mut rows, key, direction);
+    return SearchResults {
+        total: rows.len(),
+        offset,
+        results: rows
+            .into_iter()
+            .skip(offset)
+            .take(limit)
+            .map(|row| row.view())
+            .collect(),
+    };
+}
@@ -1,3 +1,4 @@
+use super::config::Settings;
 use super::image_table::ImageTable;
 use std::fs;
 use std::path::Path;
@@ -52,6 +53,10 @@ pub fn init(config_path: impl AsRef<Path>) {
     create_file_or_exit(www_path.join("index.html"), INDEX_HTML);
     create_file_or_exit(www_path.join("index.css"), INDEX_CSS);
     create_file_or_exit(www_path.join("index.bundle.js"), INDEX_BUNDLE_JS);
+    create_file_or_exit(
+        config_path.join("settings.json"),
+        &serde_json::to_string_pretty(&Settings::default()).unwrap(),
+    );
 
     let image_table = ImageTable::new();
     image_table.save(config_path.join("image_table.bincode"));
@@ -1,80 +1,1757 @@
+use super::albums;
+use super::albums::GalleryKind;
+use super::annotations::AnnotationEdit;
+use super::auth::{Access, JsonLock, Sessions, Users, SESSION_COOKIE};
 use super::config::Config;
-use super::image_table::ImageTable;
+use super::download;
+use super::dupes;
+use super::error::CommandError;
+use super::events::{self, CatalogEvent};
+use super::image_table::{CatalogLock, ImageTable, RowView};
+use super::listen::{Accepted, Listener};
+use super::map;
+use super::metadata;
+use super::metadata::MetadataPolicy;
+use super::monitor_fs;
+use super::query::{self, Query};
+use super::share::{self, Scope, Shares, SHARE_COOKIE};
+use super::sort::{self, Direction, Page, Position, SortKey};
+use super::stacks::{self, Stack};
+use super::timeline;
+use super::tls::{self, TlsFiles};
+use chrono::{Local, NaiveDate};
+use futures::prelude::*;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashSet};
+use std::convert::Infallible;
 use std::future::Future;
-use std::net::SocketAddr;
-use std::sync::Arc;
-use warp::Filter;
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use warp::hyper::body::Bytes;
+use warp::hyper::service::{make_service_fn, service_fn, Service};
+use warp::{Filter, Reply};
 
-async fn gallery_list(image_table: Arc<ImageTable>) -> Result<impl warp::Reply, warp::Rejection> {
-    return Ok(warp::reply::json(&image_table.gallery_list()));
+/// Lists the galleries with photos that `access` allows. Albums are only listed with access to
+/// everything.
+async fn gallery_list(
+    access: Access,
+    image_table: Arc<ImageTable>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let galleries: Vec<_> = image_table
+        .gallery_list()
+        .into_iter()
+        .filter(|gallery| match access {
+            Access::Everything => true,
+            _ => {
+                gallery.kind == GalleryKind::Directory
+                    && image_table
+                        .gallery_rows(gallery.name)
+                        .iter()
+                        .any(|row| access.allows(row))
+            }
+        })
+        .collect();
+    return Ok(warp::reply::json(&galleries));
+}
+
+#[derive(Deserialize)]
+struct GalleryContentsQuery {
+    /// Collapses bursts of similar shots into stacks.
+    #[serde(default)]
+    stack: bool,
+    /// Defaults to sorting by name, in ascending order.
+    sort: Option<SortKey>,
+    order: Option<Direction>,
+    cursor: Option<String>,
+    limit: Option<usize>,
+    /// A comma-separated list of the fields to include for each photo, e.g.,
+    /// `md5,thumbnail_path`. Defaults to all fields.
+    fields: Option<String>,
+}
+
+/// Serializes a page, keeping only the selected fields of each photo.
+fn select_fields<T: Serialize>(
+    page: Page<T>,
+    fields: Option<&str>,
+) -> Result<Page<serde_json::Value>, warp::Rejection> {
+    let fields: Option<HashSet<&str>> = fields.map(|fields| fields.split(',').collect());
+    let photos = page
+        .photos
+        .into_iter()
+        .map(|photo| {
+            let value = serde_json::to_value(photo).map_err(|_err| warp::reject())?;
+            return Ok(match (&fields, value) {
+                (Some(fields), serde_json::Value::Object(map)) => serde_json::Value::Object(
+                    map.into_iter()
+                        .filter(|(name, _)| fields.contains(name.as_str()))
+                        .collect(),
+                ),
+                (_, value) => value,
+            });
+        })
+        .collect::<Result<_, warp::Rejection>>()?;
+    return Ok(Page {
+        total: page.total,
+        next_cursor: page.next_cursor,
+        photos,
+    });
+}
+
+/// Removes the photos that `access` does not allow from stacks. A stack whose cover is not
+/// allowed is removed entirely.
+fn filter_stacks<'a>(
+    image_table: &ImageTable,
+    stacks: Vec<Stack<'a>>,
+    access: &Access,
+) -> Vec<Stack<'a>> {
+    if let Access::Everything = access {
+        return stacks;
+    }
+    let allows = |view: &RowView| {
+        return u128::from_str_radix(&view.md5, 16)
+            .ok()
+            .and_then(|hash| image_table.get_by_hash(hash))
+            .is_some_and(|row| access.allows(row));
+    };
+    return stacks
+        .into_iter()
+        .filter(|stack| access.allows(stack.row))
+        .map(|mut stack| {
+            stack.members.retain(|view| allows(view));
+            if stack.members.len() == 1 {
+                stack.members.clear();
+            }
+            stack
+        })
+        .collect();
 }
 
 async fn gallery_contents(
+    query: GalleryContentsQuery,
     gallery: String,
+    access: Access,
+    image_table: Arc<ImageTable>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let key = query.sort.unwrap_or(SortKey::Name);
+    let direction = query.order.unwrap_or(Direction::Asc);
+    let cursor = match &query.cursor {
+        None => None,
+        Some(cursor) => Some(Position::from_cursor(cursor).ok_or_else(warp::reject)?),
+    };
+    let limit = query.limit.unwrap_or(200).min(1000);
+    let fields = query.fields.as_deref();
+    if query.stack {
+        let stacks = stacks::stacks(&image_table, &gallery, key, direction);
+        let stacks = filter_stacks(&image_table, stacks, &access);
+        let page = sort::paginate(
+            &image_table,
+            stacks,
+            |stack| stack.row,
+            key,
+            direction,
+            cursor.as_ref(),
+            limit,
+        );
+        return Ok(warp::reply::json(&select_fields(page, fields)?));
+    }
+    let mut rows = image_table.gallery_rows(&gallery);
+    rows.retain(|row| access.allows(row));
+    sort::sort_rows(&image_table, &mut rows, key, direction);
+    let page = sort::paginate(
+        &image_table,
+        rows,
+        |row| row,
+        key,
+        direction,
+        cursor.as_ref(),
+        limit,
+    );
+    let page = Page {
+        total: page.total,
+        next_cursor: page.next_cursor,
+        photos: page
+            .photos
+            .into_iter()
+            .map(|row| row.view())
+            .collect::<Vec<_>>(),
+    };
+    return Ok(warp::reply::json(&select_fields(page, fields)?));
+}
+
+/// Chooses the cover of a stack, by MD5.
+async fn stack_cover(hash: String, catalog: Catalog) -> Result<impl warp::Reply, warp::Rejection> {
+    let hash = u128::from_str_radix(&hash, 16).map_err(|_err| warp::reject())?;
+    edit_catalog(catalog, move |image_table| {
+        stacks::set_cover(image_table, hash)
+    })
+    .await?;
+    return Ok(warp::reply::json(&format!("{:x}", hash)));
+}
+
+async fn image_details(
+    hash: String,
+    access: Access,
+    image_table: Arc<ImageTable>,
+    config: Arc<Config>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let hash = u128::from_str_radix(&hash, 16).map_err(|_err| warp::reject())?;
+    let row = image_table.get_by_hash(hash).ok_or(warp::reject())?;
+    if access.allows(row) == false {
+        return Err(warp::reject::custom(Forbidden));
+    }
+    let include_gps = config.settings.metadata_policy == MetadataPolicy::KeepAll;
+    let annotations = image_table.annotations(hash);
+    return Ok(warp::reply::json(&row.details(annotations, include_gps)));
+}
+
+/// How long a request that changes the catalog waits for the catalog lock, which `spg sync` holds
+/// until it finishes.
+const CATALOG_LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What edits from the web interface change: the catalog that we serve, the browsers that follow
+/// it, and the version in the ETags of API responses.
+#[derive(Clone)]
+struct Catalog {
+    config: Arc<Swappable<Config>>,
+    image_table: Arc<Swappable<ImageTable>>,
+    events: broadcast::Sender<CatalogEvent>,
+    version: Arc<AtomicU64>,
+}
+
+/// Applies `f` to the catalog on disk, while holding the catalog lock, and serves the result
+/// immediately instead of waiting for it to be reloaded, so that a browser that refetches after
+/// an edit sees it.
+async fn edit_catalog<T: Send + 'static>(
+    catalog: Catalog,
+    f: impl FnOnce(&mut ImageTable) -> Result<T, CommandError> + Send + 'static,
+) -> Result<T, warp::Rejection> {
+    return tokio::task::spawn_blocking(move || {
+        let config = catalog.config.get();
+        let _lock = CatalogLock::try_acquire(&config, CATALOG_LOCK_TIMEOUT)
+            .map_err(|_err| warp::reject())?
+            .ok_or_else(|| warp::reject::custom(Busy))?;
+        let mut image_table =
+            ImageTable::try_open(&config.image_table_path).map_err(|_err| warp::reject())?;
+        let result = f(&mut image_table).map_err(|_err| warp::reject())?;
+        image_table.save(&config.image_table_path);
+        let changes = events::diff(&catalog.image_table.get(), &image_table);
+        catalog.image_table.set(image_table);
+        catalog.version.fetch_add(1, Ordering::SeqCst);
+        for change in changes {
+            // Fails when no browser is listening.
+            let _ = catalog.events.send(change);
+        }
+        return Ok(result);
+    })
+    .await
+    .map_err(|_err| warp::reject())?;
+}
+
+/// Edits the annotations of an image.
+async fn annotate(
+    hash: String,
+    edit: AnnotationEdit,
+    catalog: Catalog,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let hash = u128::from_str_radix(&hash, 16).map_err(|_err| warp::reject())?;
+    let annotations =
+        edit_catalog(catalog, move |image_table| image_table.annotate(hash, edit)).await?;
+    return Ok(warp::reply::json(&annotations));
+}
+
+async fn album_contents(
+    name: String,
     image_table: Arc<ImageTable>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    return Ok(warp::reply::json(&image_table.gallery_contents(&gallery)));
+    let contents = image_table
+        .album_contents(&name)
+        .map_err(|_err| warp::reject())?;
+    return Ok(warp::reply::json(&contents));
+}
+
+#[derive(Deserialize)]
+struct CreateAlbum {
+    name: String,
+    /// Creates a smart album when present.
+    query: Option<String>,
+}
+
+async fn create_album(
+    create: CreateAlbum,
+    catalog: Catalog,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let name = create.name.clone();
+    edit_catalog(catalog, move |image_table| {
+        albums::create(image_table, &create.name, create.query.as_deref())
+    })
+    .await?;
+    return Ok(warp::reply::json(&name));
+}
+
+async fn delete_album(name: String, catalog: Catalog) -> Result<impl warp::Reply, warp::Rejection> {
+    let deleted = name.clone();
+    edit_catalog(catalog, move |image_table| {
+        albums::delete(image_table, &deleted)
+    })
+    .await?;
+    return Ok(warp::reply::json(&name));
+}
+
+/// Photos to add to and remove from a manual album, by MD5.
+#[derive(Deserialize)]
+struct EditAlbum {
+    name: String,
+    #[serde(default)]
+    add: Vec<String>,
+    #[serde(default)]
+    remove: Vec<String>,
+}
+
+async fn edit_album(
+    edit: EditAlbum,
+    catalog: Catalog,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let parse = |hashes: &[String]| -> Result<Vec<u128>, warp::Rejection> {
+        return hashes
+            .iter()
+            .map(|hash| u128::from_str_radix(hash, 16).map_err(|_err| warp::reject()))
+            .collect();
+    };
+    let add = parse(&edit.add)?;
+    let remove = parse(&edit.remove)?;
+    let name = edit.name.clone();
+    edit_catalog(catalog, move |image_table| {
+        albums::add_photos(image_table, &edit.name, &add)?;
+        albums::remove_photos(image_table, &edit.name, &remove)
+    })
+    .await?;
+    return Ok(warp::reply::json(&name));
+}
+
+#[derive(Deserialize)]
+struct TimelineQuery {
+    cursor: Option<String>,
+    limit: Option<usize>,
+}
+
+async fn timeline_page(
+    query: TimelineQuery,
+    image_table: Arc<ImageTable>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let limit = query.limit.unwrap_or(100).min(1000);
+    let page = timeline::page(&image_table, query.cursor.as_deref(), limit).ok_or(warp::reject())?;
+    return Ok(warp::reply::json(&page));
+}
+
+async fn timeline_groups(
+    image_table: Arc<ImageTable>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    return Ok(warp::reply::json(&timeline::groups(&image_table)));
+}
+
+#[derive(Deserialize)]
+struct OnThisDayQuery {
+    /// Defaults to today.
+    date: Option<NaiveDate>,
+}
+
+async fn on_this_day(
+    query: OnThisDayQuery,
+    image_table: Arc<ImageTable>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let date = query.date.unwrap_or_else(|| Local::now().date_naive());
+    return Ok(warp::reply::json(&timeline::on_this_day(
+        &image_table,
+        date,
+    )));
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    q: String,
+    sort: Option<SortKey>,
+    order: Option<Direction>,
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+async fn search(
+    query: SearchQuery,
+    image_table: Arc<ImageTable>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let parsed = Query::parse(&query.q).map_err(|_err| warp::reject())?;
+    let results = query::search(
+        &image_table,
+        &parsed,
+        query.sort.unwrap_or(SortKey::Date),
+        query.order.unwrap_or(Direction::Desc),
+        query.offset.unwrap_or(0),
+        query.limit.unwrap_or(100).min(1000),
+    );
+    return Ok(warp::reply::json(&results));
+}
+
+#[derive(Deserialize)]
+struct DupesQuery {
+    distance: Option<u32>,
+}
+
+async fn dupes(
+    query: DupesQuery,
+    image_table: Arc<ImageTable>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let distance = query.distance.unwrap_or(dupes::DEFAULT_DISTANCE);
+    // Comparing every photo takes a while in a large catalog.
+    let groups = tokio::task::spawn_blocking(move || {
+        return serde_json::to_value(dupes::groups(&image_table, distance));
+    })
+    .await
+    .map_err(|_err| warp::reject())?
+    .map_err(|_err| warp::reject())?;
+    return Ok(warp::reply::json(&groups));
+}
+
+/// Geotagged photos as GeoJSON. Since this publishes GPS positions, it is only available when
+/// the metadata policy keeps them, and otherwise says so, so that the web interface can explain
+/// why there is no map.
+async fn map_features(
+    query: map::MapQuery,
+    image_table: Arc<ImageTable>,
+    config: Arc<Config>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if config.settings.metadata_policy != MetadataPolicy::KeepAll {
+        return Err(warp::reject::custom(MapDisabled));
+    }
+    let features = map::features(&image_table, &query).ok_or(warp::reject())?;
+    return Ok(warp::reply::json(&features));
 }
 
 /// Downloads an original image by hash. Note that we only download an image that is in the
 /// ImageTable, and do not give unrestricted file system access.
+///
+/// When the settings ask for originals to be stripped of metadata, we strip a copy in memory. We
+/// cannot rewrite the metadata of a HEIC file, so we send the JPEG that heif-convert produced
+/// from it instead. Otherwise, we stream the original from disk.
 async fn original(
     hash: String,
+    range: Option<String>,
+    if_range: Option<String>,
+    access: Access,
     image_table: Arc<ImageTable>,
+    config: Arc<Config>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
     // NOTE(arjun): It is fairly obvious in this code that errors are being silently rejected.
     let hash = u128::from_str_radix(&hash, 16).map_err(|_err| warp::reject())?;
     let row = image_table.get_by_hash(hash).ok_or(warp::reject())?;
-    let body = std::fs::read(&row.original_path).map_err(|_err| warp::reject())?;
-    let filename = &row.original_path;
+    if access.allows(row) == false || access.allows_originals() == false {
+        return Err(warp::reject::custom(Forbidden));
+    }
+    let policy = config.settings.metadata_policy;
+    let download = if config.settings.strip_originals == false || policy == MetadataPolicy::KeepAll
+    {
+        download::Download {
+            source: download::Source::File(PathBuf::from(&row.original_path)),
+            filename: row.original_path.clone(),
+            etag: format!("\"{:x}\"", row.md5()),
+        }
+    } else {
+        let (path, filename) = if row.is_heic() {
+            let filename = Path::new(&row.original_path).with_extension("jpg");
+            (
+                row.converted_path(&config.data_dir),
+                filename.to_string_lossy().to_string(),
+            )
+        } else {
+            (row.original_path.clone(), row.original_path.clone())
+        };
+        let body = tokio::fs::read(&path)
+            .await
+            .map_err(|_err| warp::reject())?;
+        let body = tokio::task::spawn_blocking(move || metadata::strip_jpeg(body, policy))
+            .await
+            .map_err(|_err| warp::reject())?
+            .map_err(|_err| warp::reject())?;
+        download::Download {
+            source: download::Source::Memory(body),
+            filename,
+            // Stripping the same original with the same policy gives the same bytes.
+            etag: format!("\"{:x}-{:?}\"", row.md5(), policy),
+        }
+    };
+    return download::reply(download, range, if_range)
+        .await
+        .map_err(|_err| warp::reject());
+}
+
+/// A value that is shared by all requests, and that we replace when the file that it was loaded
+/// from changes. Each request gets the current value when it starts, and keeps it until it
+/// finishes, even if the value is replaced in the meantime.
+pub struct Swappable<T> {
+    value: RwLock<Arc<T>>,
+}
+
+impl<T> Swappable<T> {
+    pub fn new(value: T) -> Self {
+        return Swappable {
+            value: RwLock::new(Arc::new(value)),
+        };
+    }
+
+    pub fn get(&self) -> Arc<T> {
+        return self.value.read().expect("reading shared value").clone();
+    }
+
+    pub fn set(&self, value: T) {
+        *self.value.write().expect("replacing shared value") = Arc::new(value);
+    }
+}
+
+/// How long the catalog and settings must be left alone before we reload them. `spg sync` saves
+/// the catalog after every photo that it adds, so we wait until it pauses.
+const RELOAD_DELAY: Duration = Duration::from_millis(500);
+
+/// Reloads the catalog and settings, and broadcasts the changes to the catalog. If the new
+/// catalog or settings cannot be read, we keep serving the old ones.
+fn reload_catalog(
+    config: &Swappable<Config>,
+    image_table: &Swappable<ImageTable>,
+    events: &broadcast::Sender<CatalogEvent>,
+) {
+    let new_config = match Config::try_new(config.get().data_dir.clone()) {
+        Ok(new_config) => new_config,
+        Err(err) => {
+            eprintln!("{}\n\nKeeping the previous settings", err);
+            return;
+        }
+    };
+    match ImageTable::try_open(&new_config.image_table_path) {
+        Ok(new_image_table) => {
+            let changes = events::diff(&image_table.get(), &new_image_table);
+            image_table.set(new_image_table);
+            config.set(new_config);
+            eprintln!("Reloaded catalog");
+            for change in changes {
+                // Fails when no browser is listening.
+                let _ = events.send(change);
+            }
+        }
+        Err(err) => eprintln!("{}\n\nKeeping the previous catalog", err),
+    }
+}
+
+/// Reloads the users, sessions and shares, e.g., after a session or share is revoked from the
+/// command line.
+fn reload_access(
+    config: &Config,
+    users: &Swappable<Users>,
+    sessions: &Swappable<Sessions>,
+    shares: &Swappable<Shares>,
+) {
+    match Users::open(&config.users_path) {
+        Ok(new_users) => users.set(new_users),
+        Err(err) => eprintln!("{}\n\nKeeping the previous users", err),
+    }
+    match Sessions::open(&config.sessions_path) {
+        Ok(new_sessions) => sessions.set(new_sessions),
+        Err(err) => eprintln!("{}\n\nKeeping the previous sessions", err),
+    }
+    match Shares::open(&config.shares_path) {
+        Ok(new_shares) => shares.set(new_shares),
+        Err(err) => eprintln!("{}\n\nKeeping the previous shares", err),
+    }
+}
+
+/// Reloads whatever changes on disk.
+async fn reload_on_change(
+    config: Arc<Swappable<Config>>,
+    image_table: Arc<Swappable<ImageTable>>,
+    users: Arc<Swappable<Users>>,
+    sessions: Arc<Swappable<Sessions>>,
+    shares: Arc<Swappable<Shares>>,
+    events: broadcast::Sender<CatalogEvent>,
+    version: Arc<AtomicU64>,
+) {
+    let data_dir = config.get().data_dir.clone();
+    let mut changes = monitor_fs::monitor_changes(&data_dir);
+    while let Some(file) = changes.next().await {
+        let mut files = HashSet::new();
+        files.insert(file);
+        while let Ok(Some(file)) = tokio::time::timeout(RELOAD_DELAY, changes.next()).await {
+            files.insert(file);
+        }
+        if files.contains("image_table.bincode") || files.contains("settings.json") {
+            reload_catalog(&config, &image_table, &events);
+        }
+        if files.contains("users.json")
+            || files.contains("sessions.json")
+            || files.contains("shares.json")
+        {
+            reload_access(&config.get(), &users, &sessions, &shares);
+        }
+        // ACLs in users.json change what users see, while sessions and shares only change who
+        // sees it, which is part of the ETag.
+        if files.contains("image_table.bincode")
+            || files.contains("settings.json")
+            || files.contains("users.json")
+        {
+            version.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+/// Rejected when a request may not see what it asks for, e.g., a photo outside a share.
+#[derive(Debug)]
+struct Forbidden;
+
+impl warp::reject::Reject for Forbidden {}
+
+/// Rejected when a request that changes the catalog cannot take the catalog lock in time.
+#[derive(Debug)]
+struct Busy;
+
+impl warp::reject::Reject for Busy {}
+
+/// Rejected when someone has failed to log in as a user too many times recently.
+#[derive(Debug)]
+struct TooManyRequests;
+
+impl warp::reject::Reject for TooManyRequests {}
+
+/// Rejected when the metadata policy strips the GPS positions that the map would show.
+#[derive(Debug)]
+struct MapDisabled;
+
+impl warp::reject::Reject for MapDisabled {}
+
+/// Rejected when a trusted proxy sends an `X-Forwarded-Prefix` that cannot be a path.
+#[derive(Debug)]
+struct BadPrefix;
+
+impl warp::reject::Reject for BadPrefix {}
+
+/// Determines what a request may see from its cookies. A valid session grants access to what the
+/// ACLs allow the user to see, and there is access to everything when there are no users.
+/// Otherwise, a valid share cookie grants access to what it shares, and all other requests are
+/// rejected.
+fn access(
+    users: Arc<Swappable<Users>>,
+    sessions: Arc<Swappable<Sessions>>,
+    shares: Arc<Swappable<Shares>>,
+) -> impl Filter<Extract = (Access,), Error = warp::Rejection> + Clone {
+    return warp::cookie::optional(SESSION_COOKIE)
+        .and(warp::cookie::optional(SHARE_COOKIE))
+        .and_then(move |token: Option<String>, share: Option<String>| {
+            let users = users.get();
+            let sessions = sessions.get();
+            let shares = shares.get();
+            async move {
+                if users.is_enabled() == false {
+                    return Ok(Access::Everything);
+                }
+                let session = token.as_deref().and_then(|token| sessions.get(token));
+                if let Some(session) = session {
+                    let user = &session.user;
+                    if users.users.contains_key(user) && users.sees_everything(user) {
+                        return Ok(Access::Everything);
+                    } else if users.users.contains_key(user) {
+                        return Ok(Access::User(user.clone(), users.clone()));
+                    }
+                }
+                match share.as_deref().and_then(|share| shares.verify(share)) {
+                    Some(share) => return Ok(Access::Share(share)),
+                    None => return Err(warp::reject::custom(Unauthorized)),
+                }
+            }
+        });
+}
+
+/// Rejects requests that may not see everything, e.g., from share links and users whom ACLs
+/// restrict.
+fn full_access(
+    access: impl Filter<Extract = (Access,), Error = warp::Rejection> + Clone,
+) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    return access
+        .and_then(|access: Access| async move {
+            match access {
+                Access::Everything => return Ok(()),
+                _ => return Err(warp::reject::custom(Forbidden)),
+            }
+        })
+        .untuple_one();
+}
+
+/// Rejects requests for the derivatives of photos that `access` does not allow. Derivatives are
+/// named after the MD5 of their photo.
+async fn photo_access(
+    file: warp::path::Peek,
+    access: Access,
+    image_table: Arc<ImageTable>,
+) -> Result<(), warp::Rejection> {
+    if let Access::Everything = access {
+        return Ok(());
+    }
+    let row = file
+        .as_str()
+        .split('-')
+        .next()
+        .and_then(|hash| u128::from_str_radix(hash, 16).ok())
+        .and_then(|hash| image_table.get_by_hash(hash));
+    match row {
+        Some(row) if access.allows(row) => return Ok(()),
+        _ => return Err(warp::reject::custom(Forbidden)),
+    }
+}
+
+/// How browsers may cache derivatives. Derivatives are named after the MD5 of their photo, so
+/// the file at a URL never changes. They are private, since we check access to them.
+const IMMUTABLE: &'static str = "private, max-age=31536000, immutable";
+
+/// How browsers may cache API responses: they must ask us whether their copy is still current.
+const REVALIDATE: &'static str = "private, no-cache";
+
+/// Rejected when the browser already has the current response, which is identified by `etag`.
+#[derive(Debug)]
+struct NotModified {
+    etag: String,
+    cache_control: &'static str,
+}
+
+impl warp::reject::Reject for NotModified {}
+
+/// Whether an `If-None-Match` header lists `etag`. ETags are compared weakly, as RFC 7232
+/// requires for this header.
+fn matches_etag(if_none_match: Option<&str>, etag: &str) -> bool {
+    let if_none_match = match if_none_match {
+        Some(if_none_match) => if_none_match,
+        None => return false,
+    };
+    return if_none_match.split(',').any(|candidate| {
+        let candidate = candidate.trim();
+        return candidate == "*" || candidate.trim_start_matches("W/") == etag;
+    });
+}
+
+fn cache_headers(
+    reply: impl warp::Reply,
+    etag: &str,
+    cache_control: &'static str,
+) -> warp::reply::Response {
+    let mut response = reply.into_response();
+    let headers = response.headers_mut();
+    if let Ok(etag) = http::HeaderValue::from_str(etag) {
+        headers.insert(http::header::ETAG, etag);
+    }
+    headers.insert(
+        http::header::CACHE_CONTROL,
+        http::HeaderValue::from_static(cache_control),
+    );
+    // Who is logged in determines what the API returns.
+    if cache_control == REVALIDATE {
+        headers.insert(http::header::VARY, http::HeaderValue::from_static("Cookie"));
+    }
+    return response;
+}
+
+/// Extracts the ETag of a derivative, which is its file name, or rejects with `NotModified` when
+/// the browser already has it.
+fn derivative_etag() -> impl Filter<Extract = (String,), Error = warp::Rejection> + Clone {
+    return warp::path::peek()
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and_then(|file: warp::path::Peek, if_none_match: Option<String>| {
+            let etag = format!("\"{}\"", file.as_str());
+            async move {
+                if matches_etag(if_none_match.as_deref(), &etag) {
+                    return Err(warp::reject::custom(NotModified {
+                        etag,
+                        cache_control: IMMUTABLE,
+                    }));
+                }
+                return Ok(etag);
+            }
+        });
+}
+
+/// The ETag of the API responses that `access` gets from the catalog in `version`, which may also
+/// depend on `key`, or `NotModified` when the browser already has the response.
+fn check_etag(
+    version: u64,
+    access: Access,
+    key: impl Hash,
+    if_none_match: Option<String>,
+) -> Result<String, warp::Rejection> {
+    let mut hasher = DefaultHasher::new();
+    match access {
+        Access::Everything => "everything".hash(&mut hasher),
+        Access::Share(share) => ("share", share.id).hash(&mut hasher),
+        Access::User(user, _) => ("user", user).hash(&mut hasher),
+    }
+    key.hash(&mut hasher);
+    let etag = format!("\"{:x}-{:x}\"", version, hasher.finish());
+    if matches_etag(if_none_match.as_deref(), &etag) {
+        return Err(warp::reject::custom(NotModified {
+            etag,
+            cache_control: REVALIDATE,
+        }));
+    }
+    return Ok(etag);
+}
+
+/// Extracts the ETag of the API responses that `access` gets from the catalog in its current
+/// `version`, or rejects with `NotModified` when the browser already has the response. Responses
+/// that change with the date, such as the photos taken on this day, ask for it to be `dated`.
+fn api_etag(
+    access: impl Filter<Extract = (Access,), Error = warp::Rejection> + Clone,
+    version: Arc<AtomicU64>,
+    dated: bool,
+) -> impl Filter<Extract = (String,), Error = warp::Rejection> + Clone {
+    return access
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and_then(move |access: Access, if_none_match: Option<String>| {
+            let version = version.load(Ordering::SeqCst);
+            let today = if dated {
+                Some(Local::now().date_naive())
+            } else {
+                None
+            };
+            return future::ready(check_etag(version, access, today, if_none_match));
+        });
+}
+
+/// Like `api_etag`, for a POST whose response also depends on its query string and its JSON body,
+/// which it extracts after the ETag.
+fn api_etag_with_body<T: DeserializeOwned + Send + 'static>(
+    access: impl Filter<Extract = (Access,), Error = warp::Rejection> + Clone,
+    version: Arc<AtomicU64>,
+) -> impl Filter<Extract = (String, T), Error = warp::Rejection> + Clone {
+    return access
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and(warp::body::bytes())
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and_then(
+            move |access: Access, query: String, body: Bytes, if_none_match: Option<String>| {
+                let version = version.load(Ordering::SeqCst);
+                let result = serde_json::from_slice::<T>(&body)
+                    .map_err(|_err| warp::reject())
+                    .and_then(|value| {
+                        let etag = check_etag(version, access, (query, &body[..]), if_none_match)?;
+                        return Ok((etag, value));
+                    });
+                return future::ready(result);
+            },
+        )
+        .untuple_one();
+}
+
+/// Where the browser reaches the web interface, for absolute links and cookie paths. A reverse
+/// proxy describes the request that it forwards with `X-Forwarded-Proto`, `X-Forwarded-Host` and
+/// `X-Forwarded-Prefix`, the last of which is the path that it strips before forwarding.
+#[derive(Clone, Debug)]
+struct Origin {
+    scheme: String,
+    host: String,
+    /// The path of the web interface, without a trailing slash, e.g., `/photos`, or empty.
+    base_path: String,
+}
+
+impl Origin {
+    /// An absolute URL for `path`, which is relative to the web interface.
+    fn url(&self, path: &str) -> String {
+        return format!("{}://{}{}/{}", self.scheme, self.host, self.base_path, path);
+    }
+}
+
+/// The address of the client that sent a request, which we store in its extensions.
+#[derive(Clone, Copy)]
+struct RemoteAddr(Option<SocketAddr>);
+
+/// Extracts the address of the client. Behind a trusted proxy, that is the last address in
+/// `X-Forwarded-For`, which the proxy appends, since the client may send the others.
+fn client_addr(
+    trust_proxy: bool,
+) -> impl Filter<Extract = (Option<IpAddr>,), Error = warp::Rejection> + Clone {
+    return warp::ext::optional::<RemoteAddr>()
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .map(
+            move |remote_addr: Option<RemoteAddr>, forwarded_for: Option<String>| {
+                let forwarded = forwarded_for.filter(|_| trust_proxy).and_then(|value| {
+                    return value.rsplit(',').next()?.trim().parse::<IpAddr>().ok();
+                });
+                return forwarded.or_else(|| remote_addr?.0.map(|addr| addr.ip()));
+            },
+        );
+}
+
+/// Extracts the `Origin` of a request to a server whose routes are under `base_path`. Only when we
+/// trust the proxy in front of us do we believe the `X-Forwarded-*` headers, which a browser could
+/// otherwise forge.
+fn origin(
+    base_path: String,
+    https: bool,
+    trust_proxy: bool,
+) -> impl Filter<Extract = (Origin,), Error = warp::Rejection> + Clone {
+    let forwarded = move |name: &'static str| {
+        return warp::header::optional::<String>(name).map(move |value: Option<String>| {
+            return value.filter(|value| trust_proxy && value.trim().is_empty() == false);
+        });
+    };
+    return forwarded("x-forwarded-proto")
+        .and(forwarded("x-forwarded-host"))
+        .and(forwarded("x-forwarded-prefix"))
+        .and(warp::header::optional::<String>("host"))
+        .and_then(
+            move |scheme: Option<String>,
+                  forwarded_host: Option<String>,
+                  prefix: Option<String>,
+                  host: Option<String>| {
+                // A header that passed through several proxies lists a value from each, the first
+                // of which is from the browser.
+                let first = |value: String| {
+                    value
+                        .split(',')
+                        .next()
+                        .unwrap_or_default()
+                        .trim()
+                        .to_string()
+                };
+                let default_scheme = if https { "https" } else { "http" };
+                let prefix = prefix.unwrap_or_default();
+                // The prefix ends up in the paths of cookies and links, which it must not escape.
+                let result = if prefix.contains(|c: char| c == ';' || c == ',' || c.is_whitespace())
+                {
+                    Err(warp::reject::custom(BadPrefix))
+                } else {
+                    Ok(Origin {
+                        scheme: scheme
+                            .map(first)
+                            .unwrap_or_else(|| default_scheme.to_string()),
+                        host: forwarded_host
+                            .map(first)
+                            .or(host)
+                            .unwrap_or_else(|| "localhost".to_string()),
+                        base_path: format!("{}{}", prefix.trim_end_matches('/'), base_path),
+                    })
+                };
+                return future::ready(result);
+            },
+        );
+}
+
+/// Applies `f` to the sessions on disk, which the command line may have changed, and serves the
+/// result immediately instead of waiting for it to be reloaded.
+async fn edit_sessions<T: Send + 'static>(
+    config: Arc<Config>,
+    sessions: Arc<Swappable<Sessions>>,
+    f: impl FnOnce(&mut Sessions) -> T + Send + 'static,
+) -> Result<T, warp::Rejection> {
+    return tokio::task::spawn_blocking(move || {
+        let _lock = JsonLock::acquire(&config.sessions_path).map_err(|_err| warp::reject())?;
+        let mut new_sessions =
+            Sessions::open(&config.sessions_path).map_err(|_err| warp::reject())?;
+        let result = f(&mut new_sessions);
+        new_sessions
+            .save(&config.sessions_path)
+            .map_err(|_err| warp::reject())?;
+        sessions.set(new_sessions);
+        return Ok(result);
+    })
+    .await
+    .map_err(|_err| warp::reject())?;
+}
+
+/// The value of a `Set-Cookie` header for a cookie that scripts cannot read, which the browser
+/// sends to the web interface at `origin`.
+fn set_cookie(
+    config: &Config,
+    origin: &Origin,
+    name: &str,
+    value: &str,
+    max_age: i64,
+    same_site: &str,
+) -> String {
+    let secure = if config.settings.secure_cookies {
+        "; Secure"
+    } else {
+        ""
+    };
+    return format!(
+        "{}={}; Max-Age={}; Path={}/; HttpOnly; SameSite={}{}",
+        name, value, max_age, origin.base_path, same_site, secure
+    );
+}
+
+fn session_cookie(config: &Config, origin: &Origin, token: &str, max_age: i64) -> String {
+    return set_cookie(config, origin, SESSION_COOKIE, token, max_age, "Strict");
+}
+
+#[derive(Deserialize)]
+struct Login {
+    user: String,
+    password: String,
+}
+
+/// How many times someone may fail to log in as a user from an address within
+/// `LOGIN_FAILURE_WINDOW`, after which we stop checking passwords for that user from that address
+/// until the window ends. Counting by address alone would let one guesser behind a shared address
+/// lock out everyone else there, and counting by user alone would let anyone lock out any user.
+/// Counting by both lets a guesser with many addresses keep guessing, but only as fast as they
+/// have addresses. Clients of a Unix socket have no address, and share their count.
+const MAX_LOGIN_FAILURES: u32 = 5;
+const LOGIN_FAILURE_WINDOW: Duration = Duration::from_secs(15 * 60);
+
+/// Who tries to log in: a user name and the address of the client.
+type LoginKey = (String, Option<IpAddr>);
+
+/// The number of failed logins for each user and address, and when the first of them was.
+static LOGIN_FAILURES: Mutex<BTreeMap<LoginKey, (u32, Instant)>> = Mutex::new(BTreeMap::new());
+
+fn is_throttled(key: &LoginKey) -> bool {
+    let failures = LOGIN_FAILURES.lock().expect("locking login failures");
+    return match failures.get(key) {
+        None => false,
+        Some((count, first)) => {
+            *count >= MAX_LOGIN_FAILURES && first.elapsed() < LOGIN_FAILURE_WINDOW
+        }
+    };
+}
+
+fn record_login(key: LoginKey, succeeded: bool) {
+    let mut failures = LOGIN_FAILURES.lock().expect("locking login failures");
+    // Forgets failures that no longer count, so that guessing names cannot grow the map forever.
+    failures.retain(|_, (_, first)| first.elapsed() < LOGIN_FAILURE_WINDOW);
+    if succeeded {
+        failures.remove(&key);
+    } else {
+        failures.entry(key).or_insert((0, Instant::now())).0 += 1;
+    }
+}
+
+async fn login(
+    login: Login,
+    client: Option<IpAddr>,
+    origin: Origin,
+    config: Arc<Config>,
+    users: Arc<Swappable<Users>>,
+    sessions: Arc<Swappable<Sessions>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let Login { user, password } = login;
+    let key = (user.clone(), client);
+    if is_throttled(&key) {
+        return Err(warp::reject::custom(TooManyRequests));
+    }
+    let verified = {
+        let users = users.get();
+        let user = user.clone();
+        tokio::task::spawn_blocking(move || users.verify(&user, &password))
+            .await
+            .map_err(|_err| warp::reject())?
+    };
+    record_login(key, verified);
+    if verified == false {
+        return Err(warp::reject::custom(Unauthorized));
+    }
+    let days = config.settings.session_days;
+    let token = {
+        let user = user.clone();
+        edit_sessions(config.clone(), sessions, move |sessions| {
+            sessions.create(&user, days)
+        })
+        .await?
+    };
+    let max_age = i64::from(days) * 24 * 60 * 60;
+    return Ok(warp::reply::with_header(
+        warp::reply::json(&user),
+        http::header::SET_COOKIE,
+        session_cookie(&config, &origin, &token, max_age),
+    ));
+}
+
+async fn logout(
+    token: Option<String>,
+    origin: Origin,
+    config: Arc<Config>,
+    sessions: Arc<Swappable<Sessions>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if let Some(token) = token {
+        edit_sessions(config.clone(), sessions, move |sessions| {
+            sessions.remove(&token)
+        })
+        .await?;
+    }
     return Ok(warp::reply::with_header(
-        body,
-        http::header::CONTENT_DISPOSITION,
-        format!("attachment; filename={}", filename),
+        warp::reply::json(&()),
+        http::header::SET_COOKIE,
+        session_cookie(&config, &origin, "", 0),
     ));
 }
 
+/// Applies `f` to the shares on disk, and serves the result immediately.
+async fn edit_shares<T: Send + 'static>(
+    config: Arc<Config>,
+    shares: Arc<Swappable<Shares>>,
+    f: impl FnOnce(&mut Shares) -> Result<T, CommandError> + Send + 'static,
+) -> Result<T, warp::Rejection> {
+    return tokio::task::spawn_blocking(move || {
+        let _lock = JsonLock::acquire(&config.shares_path).map_err(|_err| warp::reject())?;
+        let mut new_shares = Shares::open(&config.shares_path).map_err(|_err| warp::reject())?;
+        let result = f(&mut new_shares).map_err(|_err| warp::reject())?;
+        new_shares
+            .save(&config.shares_path)
+            .map_err(|_err| warp::reject())?;
+        shares.set(new_shares);
+        return Ok(result);
+    })
+    .await
+    .map_err(|_err| warp::reject())?;
+}
+
+/// Shares either a gallery, by name, or a photo, by MD5.
+#[derive(Deserialize)]
+struct CreateShare {
+    gallery: Option<String>,
+    photo: Option<String>,
+    /// A duration such as `7d`.
+    expires: String,
+    #[serde(default)]
+    originals: bool,
+}
+
+#[derive(Serialize)]
+struct CreatedShare {
+    #[serde(flatten)]
+    share: share::Share,
+    /// The link to give to the recipient, relative to the web interface.
+    path: String,
+    /// The same link as an absolute URL.
+    url: String,
+}
+
+async fn create_share(
+    create: CreateShare,
+    origin: Origin,
+    config: Arc<Config>,
+    image_table: Arc<ImageTable>,
+    shares: Arc<Swappable<Shares>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let scope = match (create.gallery, create.photo) {
+        (Some(gallery), None) if image_table.gallery_rows(&gallery).is_empty() == false => {
+            Scope::Gallery(gallery)
+        }
+        (None, Some(photo)) => {
+            let hash = u128::from_str_radix(&photo, 16).map_err(|_err| warp::reject())?;
+            let row = image_table.get_by_hash(hash).ok_or(warp::reject())?;
+            Scope::Photo(format!("{:x}", row.md5()))
+        }
+        _ => return Err(warp::reject()),
+    };
+    let seconds = share::parse_duration(&create.expires).map_err(|_err| warp::reject())?;
+    let originals = create.originals;
+    let (share, token) = edit_shares(config, shares, move |shares| {
+        Ok(shares.create(scope, originals, seconds))
+    })
+    .await?;
+    let path = format!("share/{}", token);
+    return Ok(warp::reply::json(&CreatedShare {
+        share,
+        url: origin.url(&path),
+        path,
+    }));
+}
+
+async fn list_shares(shares: Arc<Swappable<Shares>>) -> Result<impl warp::Reply, warp::Rejection> {
+    let shares = shares.get();
+    let shares: Vec<&share::Share> = shares.shares.values().collect();
+    return Ok(warp::reply::json(&shares));
+}
+
+async fn revoke_share(
+    id: String,
+    config: Arc<Config>,
+    shares: Arc<Swappable<Shares>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let revoked = id.clone();
+    edit_shares(config, shares, move |shares| shares.revoke(&revoked)).await?;
+    return Ok(warp::reply::json(&id));
+}
+
+/// Opens a share link: stores its token in a cookie, which grants access to what it shares, and
+/// shows the gallery.
+async fn open_share(
+    token: String,
+    origin: Origin,
+    config: Arc<Config>,
+    shares: Arc<Swappable<Shares>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let share = shares
+        .get()
+        .verify(&token)
+        .ok_or_else(|| warp::reject::custom(Forbidden))?;
+    // The recipient arrives from another site, e.g., their email, so SameSite=Strict would
+    // withhold the cookie from the first page that they see.
+    let max_age = share.expires - chrono::Utc::now().timestamp();
+    let cookie = set_cookie(&config, &origin, SHARE_COOKIE, &token, max_age, "Lax");
+    // The web interface is the parent of the share link, wherever the proxy puts it.
+    let reply = warp::reply::with_status(warp::reply(), http::StatusCode::TEMPORARY_REDIRECT);
+    let reply = warp::reply::with_header(reply, http::header::LOCATION, "../");
+    return Ok(warp::reply::with_header(
+        reply,
+        http::header::SET_COOKIE,
+        cookie,
+    ));
+}
+
+/// Answers requests that were rejected for lack of access, or because the browser has a fresh
+/// copy of the response.
+async fn handle_rejection(rejection: warp::Rejection) -> Result<impl warp::Reply, warp::Rejection> {
+    if rejection.find::<Unauthorized>().is_some() {
+        let reply = warp::reply::with_status(
+            warp::reply::json(&"login required"),
+            http::StatusCode::UNAUTHORIZED,
+        );
+        return Ok(reply.into_response());
+    }
+    if rejection.find::<Forbidden>().is_some() {
+        let reply =
+            warp::reply::with_status(warp::reply::json(&"forbidden"), http::StatusCode::FORBIDDEN);
+        return Ok(reply.into_response());
+    }
+    if rejection.find::<TooManyRequests>().is_some() {
+        let reply = warp::reply::with_status(
+            warp::reply::json(&"too many failed logins, try again later"),
+            http::StatusCode::TOO_MANY_REQUESTS,
+        );
+        return Ok(reply.into_response());
+    }
+    if rejection.find::<MapDisabled>().is_some() {
+        let reply = warp::reply::with_status(
+            warp::reply::json(&"the map needs \"metadata_policy\": \"keep_all\" in settings.json"),
+            http::StatusCode::FORBIDDEN,
+        );
+        return Ok(reply.into_response());
+    }
+    if rejection.find::<BadPrefix>().is_some() {
+        let reply = warp::reply::with_status(
+            warp::reply::json(&"invalid X-Forwarded-Prefix"),
+            http::StatusCode::BAD_REQUEST,
+        );
+        return Ok(reply.into_response());
+    }
+    if rejection.find::<Busy>().is_some() {
+        let reply = warp::reply::with_status(
+            warp::reply::json(&"the catalog is being synchronized, try again later"),
+            http::StatusCode::SERVICE_UNAVAILABLE,
+        );
+        return Ok(reply.into_response());
+    }
+    if let Some(not_modified) = rejection.find::<NotModified>() {
+        let reply = warp::reply::with_status(warp::reply(), http::StatusCode::NOT_MODIFIED);
+        return Ok(cache_headers(
+            reply,
+            &not_modified.etag,
+            not_modified.cache_control,
+        ));
+    }
+    return Err(rejection);
+}
+
+/// The number of events that a browser may fall behind by before it misses some.
+const EVENT_BUFFER: usize = 1024;
+
+/// Streams changes to the catalog as Server-Sent Events. Each event is a JSON object with a
+/// `type` field.
+fn catalog_events(events: broadcast::Sender<CatalogEvent>) -> impl warp::Reply {
+    let stream = events
+        .subscribe()
+        .filter_map(|event| {
+            future::ready(match event {
+                Ok(event) => Some(event),
+                Err(broadcast::RecvError::Lagged(_)) => Some(CatalogEvent::Resync),
+                Err(broadcast::RecvError::Closed) => None,
+            })
+        })
+        .map(|event| Ok::<_, Infallible>(warp::sse::json(event)));
+    return warp::sse::reply(warp::sse::keep_alive().stream(stream));
+}
+
+/// Serves the web interface under `base_path` on every listener, over HTTPS when `tls` is given,
+/// until `until` completes.
 pub async fn serve(
-    addr: impl Into<SocketAddr> + 'static,
+    listeners: Vec<Listener>,
+    base_path: &str,
+    trust_proxy: bool,
+    tls: Option<TlsFiles>,
     until: impl Future<Output = ()> + Send + 'static,
     config: Config,
     image_table: ImageTable,
 ) {
-    let image_table = Arc::new(image_table);
-    let config = Arc::new(config);
+    let www_dir = format!("{}/www", config.data_dir);
+    let photos_dir = PathBuf::from(format!("{}/photos", www_dir));
+    let users = Arc::new(Swappable::new(Users::default()));
+    let sessions = Arc::new(Swappable::new(Sessions::default()));
+    let shares = Arc::new(Swappable::new(Shares::default()));
+    reload_access(&config, &users, &sessions, &shares);
+    // Browsers drop a secure cookie that arrives over plain HTTP, except from localhost, so logins
+    // would silently fail. A trusted proxy presumably serves HTTPS in front of us.
+    if config.settings.secure_cookies
+        && tls.is_none()
+        && trust_proxy == false
+        && users.get().users.is_empty() == false
+    {
+        eprintln!(
+            "Warning: browsers only keep logins over HTTPS, or from localhost, since \
+             secure_cookies is set. Serve HTTPS with --tls-cert, or set \"secure_cookies\": \
+             false in settings.json."
+        );
+    }
+    let image_table = Arc::new(Swappable::new(image_table));
+    let config = Arc::new(Swappable::new(config));
+    let (events, _) = broadcast::channel(EVENT_BUFFER);
+    // Starts at random, so that ETags from before a restart do not match.
+    let version = Arc::new(AtomicU64::new(rand::random()));
+    tokio::spawn(reload_on_change(
+        config.clone(),
+        image_table.clone(),
+        users.clone(),
+        sessions.clone(),
+        shares.clone(),
+        events.clone(),
+        version.clone(),
+    ));
+    let access = access(users.clone(), sessions.clone(), shares.clone());
+    let full_access = full_access(access.clone());
+    let dated_api_etag = api_etag(access.clone(), version.clone(), true);
+    let api_etag = api_etag(access.clone(), version.clone(), false);
+    let catalog = Catalog {
+        config: config.clone(),
+        image_table: image_table.clone(),
+        events: events.clone(),
+        version,
+    };
+    let segments: Vec<String> = base_path
+        .split('/')
+        .filter(|segment| segment.is_empty() == false)
+        .map(|segment| segment.to_string())
+        .collect();
+    let base_path: String = segments
+        .iter()
+        .map(|segment| format!("/{}", segment))
+        .collect();
+    let origin = origin(base_path, tls.is_some(), trust_proxy);
+
+    let login_route = {
+        let config = config.clone();
+        let users = users.clone();
+        let sessions = sessions.clone();
+        warp::path!("api" / "login")
+            .and(warp::post())
+            .and(warp::body::json())
+            .and(client_addr(trust_proxy))
+            .and(origin.clone())
+            .and(warp::any().map(move || config.get()))
+            .and(warp::any().map(move || users.clone()))
+            .and(warp::any().map(move || sessions.clone()))
+            .and_then(login)
+    };
+
+    let logout_route = {
+        let config = config.clone();
+        let sessions = sessions.clone();
+        warp::path!("api" / "logout")
+            .and(warp::post())
+            .and(warp::cookie::optional(SESSION_COOKIE))
+            .and(origin.clone())
+            .and(warp::any().map(move || config.get()))
+            .and(warp::any().map(move || sessions.clone()))
+            .and_then(logout)
+    };
 
     let gallery_list_route = {
         let image_table = image_table.clone();
         warp::path!("api" / "list_galleries")
             .and(warp::get())
-            .and(warp::any().map(move || image_table.clone()))
-            .and_then(gallery_list)
+            .and(api_etag.clone())
+            .and(
+                access
+                    .clone()
+                    .and(warp::any().map(move || image_table.get()))
+                    .and_then(gallery_list),
+            )
+            .map(|etag: String, reply| cache_headers(reply, &etag, REVALIDATE))
     };
 
     let gallery_contents_route = {
         let image_table = image_table.clone();
         warp::path!("api" / "gallery_contents")
             .and(warp::post())
+            .and(api_etag_with_body(access.clone(), catalog.version.clone()))
+            .and(warp::query())
+            .and(access.clone())
+            .and(warp::any().map(move || image_table.get()))
+            .and_then(
+                |etag: String, gallery, query, access, image_table| async move {
+                    let reply = gallery_contents(query, gallery, access, image_table).await?;
+                    return Ok::<_, warp::Rejection>(cache_headers(reply, &etag, REVALIDATE));
+                },
+            )
+    };
+
+    let timeline_route = {
+        let image_table = image_table.clone();
+        warp::path!("api" / "timeline")
+            .and(warp::get())
+            .and(api_etag.clone())
+            .and(
+                full_access
+                    .clone()
+                    .and(warp::query())
+                    .and(warp::any().map(move || image_table.get()))
+                    .and_then(timeline_page),
+            )
+            .map(|etag: String, reply| cache_headers(reply, &etag, REVALIDATE))
+    };
+
+    let timeline_groups_route = {
+        let image_table = image_table.clone();
+        warp::path!("api" / "timeline" / "groups")
+            .and(warp::get())
+            .and(api_etag.clone())
+            .and(
+                full_access
+                    .clone()
+                    .and(warp::any().map(move || image_table.get()))
+                    .and_then(timeline_groups),
+            )
+            .map(|etag: String, reply| cache_headers(reply, &etag, REVALIDATE))
+    };
+
+    let on_this_day_route = {
+        let image_table = image_table.clone();
+        warp::path!("api" / "timeline" / "on_this_day")
+            .and(warp::get())
+            .and(dated_api_etag)
+            .and(
+                full_access
+                    .clone()
+                    .and(warp::query())
+                    .and(warp::any().map(move || image_table.get()))
+                    .and_then(on_this_day),
+            )
+            .map(|etag: String, reply| cache_headers(reply, &etag, REVALIDATE))
+    };
+
+    let search_route = {
+        let image_table = image_table.clone();
+        warp::path!("api" / "search")
+            .and(warp::get())
+            .and(api_etag.clone())
+            .and(
+                full_access
+                    .clone()
+                    .and(warp::query())
+                    .and(warp::any().map(move || image_table.get()))
+                    .and_then(search),
+            )
+            .map(|etag: String, reply| cache_headers(reply, &etag, REVALIDATE))
+    };
+
+    let dupes_route = {
+        let image_table = image_table.clone();
+        warp::path!("api" / "dupes")
+            .and(warp::get())
+            .and(api_etag.clone())
+            .and(
+                full_access
+                    .clone()
+                    .and(warp::query())
+                    .and(warp::any().map(move || image_table.get()))
+                    .and_then(dupes),
+            )
+            .map(|etag: String, reply| cache_headers(reply, &etag, REVALIDATE))
+    };
+
+    let map_route = {
+        let image_table = image_table.clone();
+        let config = config.clone();
+        warp::path!("api" / "map")
+            .and(warp::get())
+            .and(api_etag.clone())
+            .and(
+                full_access
+                    .clone()
+                    .and(warp::query())
+                    .and(warp::any().map(move || image_table.get()))
+                    .and(warp::any().map(move || config.get()))
+                    .and_then(map_features),
+            )
+            .map(|etag: String, reply| cache_headers(reply, &etag, REVALIDATE))
+    };
+
+    let image_details_route = {
+        let image_table = image_table.clone();
+        let config = config.clone();
+        warp::path!("api" / "image" / ..)
+            .and(warp::get())
+            .and(api_etag.clone())
+            .and(
+                warp::path::param()
+                    .and(warp::path::end())
+                    .and(access.clone())
+                    .and(warp::any().map(move || image_table.get()))
+                    .and(warp::any().map(move || config.get()))
+                    .and_then(image_details),
+            )
+            .map(|etag: String, reply| cache_headers(reply, &etag, REVALIDATE))
+    };
+
+    let album_contents_route = {
+        let image_table = image_table.clone();
+        warp::path!("api" / "album_contents")
+            .and(warp::post())
+            .and(full_access.clone())
+            .and(api_etag_with_body(access.clone(), catalog.version.clone()))
+            .and(warp::any().map(move || image_table.get()))
+            .and_then(|etag: String, name, image_table| async move {
+                let reply = album_contents(name, image_table).await?;
+                return Ok::<_, warp::Rejection>(cache_headers(reply, &etag, REVALIDATE));
+            })
+    };
+
+    let create_album_route = {
+        let catalog = catalog.clone();
+        warp::path!("api" / "create_album")
+            .and(warp::post())
+            .and(full_access.clone())
+            .and(warp::body::json())
+            .and(warp::any().map(move || catalog.clone()))
+            .and_then(create_album)
+    };
+
+    let delete_album_route = {
+        let catalog = catalog.clone();
+        warp::path!("api" / "delete_album")
+            .and(warp::post())
+            .and(full_access.clone())
+            .and(warp::body::json())
+            .and(warp::any().map(move || catalog.clone()))
+            .and_then(delete_album)
+    };
+
+    let edit_album_route = {
+        let catalog = catalog.clone();
+        warp::path!("api" / "edit_album")
+            .and(warp::post())
+            .and(full_access.clone())
+            .and(warp::body::json())
+            .and(warp::any().map(move || catalog.clone()))
+            .and_then(edit_album)
+    };
+
+    let stack_cover_route = {
+        let catalog = catalog.clone();
+        warp::path!("api" / "stack_cover")
+            .and(warp::post())
+            .and(full_access.clone())
+            .and(warp::body::json())
+            .and(warp::any().map(move || catalog.clone()))
+            .and_then(stack_cover)
+    };
+
+    let annotate_route = {
+        let catalog = catalog.clone();
+        warp::path!("api" / "annotations" / String)
+            .and(warp::post())
+            .and(full_access.clone())
             .and(warp::body::json())
-            .and(warp::any().map(move || image_table.clone()))
-            .and_then(gallery_contents)
+            .and(warp::any().map(move || catalog.clone()))
+            .and_then(annotate)
     };
 
     let original_image_route = {
         let image_table = image_table.clone();
+        let config = config.clone();
         warp::path!("api" / "original" / String)
             .and(warp::get())
-            .and(warp::any().map(move || image_table.clone()))
+            .and(warp::header::optional("range"))
+            .and(warp::header::optional("if-range"))
+            .and(access.clone())
+            .and(warp::any().map(move || image_table.get()))
+            .and(warp::any().map(move || config.get()))
             .and_then(original)
     };
 
-    let routes = gallery_list_route
+    let create_share_route = {
+        let config = config.clone();
+        let image_table = image_table.clone();
+        let shares = shares.clone();
+        warp::path!("api" / "create_share")
+            .and(warp::post())
+            .and(full_access.clone())
+            .and(warp::body::json())
+            .and(origin.clone())
+            .and(warp::any().map(move || config.get()))
+            .and(warp::any().map(move || image_table.get()))
+            .and(warp::any().map(move || shares.clone()))
+            .and_then(create_share)
+    };
+
+    let list_shares_route = {
+        let shares = shares.clone();
+        warp::path!("api" / "list_shares")
+            .and(warp::get())
+            .and(full_access.clone())
+            .and(warp::any().map(move || shares.clone()))
+            .and_then(list_shares)
+    };
+
+    let revoke_share_route = {
+        let config = config.clone();
+        let shares = shares.clone();
+        warp::path!("api" / "revoke_share")
+            .and(warp::post())
+            .and(full_access.clone())
+            .and(warp::body::json())
+            .and(warp::any().map(move || config.get()))
+            .and(warp::any().map(move || shares.clone()))
+            .and_then(revoke_share)
+    };
+
+    let open_share_route = {
+        let config = config.clone();
+        let shares = shares.clone();
+        warp::path!("share" / String)
+            .and(warp::get())
+            .and(origin.clone())
+            .and(warp::any().map(move || config.get()))
+            .and(warp::any().map(move || shares.clone()))
+            .and_then(open_share)
+    };
+
+    let events_route = warp::path!("api" / "events")
+        .and(warp::get())
+        .and(full_access.clone())
+        .map(move || catalog_events(events.clone()));
+
+    let photos_route = {
+        let image_table = image_table.clone();
+        warp::path("photos")
+            .and(warp::path::peek())
+            .and(access.clone())
+            .and(warp::any().map(move || image_table.get()))
+            .and_then(photo_access)
+            .untuple_one()
+            .and(derivative_etag())
+            .and(warp::fs::dir(photos_dir.clone()))
+            .map(|etag: String, file: warp::fs::File| cache_headers(file, &etag, IMMUTABLE))
+    };
+
+    // Photos are only served by photos_route, which checks access to them.
+    let static_route = warp::fs::dir(www_dir).and_then(move |file: warp::fs::File| {
+        let is_photo = file.path().starts_with(&photos_dir);
+        async move {
+            if is_photo {
+                return Err(warp::reject::not_found());
+            }
+            return Ok(file);
+        }
+    });
+
+    let api_routes = gallery_list_route
         .or(gallery_contents_route)
+        .or(events_route)
+        .or(timeline_route)
+        .or(timeline_groups_route)
+        .or(on_this_day_route)
+        .or(search_route)
+        .or(dupes_route)
+        .or(map_route)
+        .or(image_details_route)
+        .or(album_contents_route)
+        .or(create_album_route)
+        .or(delete_album_route)
+        .or(edit_album_route)
+        .or(stack_cover_route)
+        .or(annotate_route)
         .or(original_image_route)
-        .or(warp::fs::dir(format!("{}/www", config.data_dir)));
+        .or(create_share_route)
+        .or(list_shares_route)
+        .or(revoke_share_route);
 
-    warp::serve(routes)
-        .bind_with_graceful_shutdown(addr, until)
-        .1
-        .await;
+    // Relative links from the web interface need its path to end with a slash.
+    let base_route = warp::path::end().and(warp::path::full()).and_then(
+        |path: warp::path::FullPath| async move {
+            let location = match path.as_str().rsplit('/').next() {
+                Some(name) if name.is_empty() == false => format!("{}/", name),
+                _ => return Err(warp::reject::not_found()),
+            };
+            let status = http::StatusCode::MOVED_PERMANENTLY;
+            let reply = warp::reply::with_status(warp::reply(), status);
+            return Ok(warp::reply::with_header(
+                reply,
+                http::header::LOCATION,
+                location,
+            ));
+        },
+    );
+
+    let mut prefix = warp::any().boxed();
+    for segment in segments {
+        prefix = prefix.and(warp::path(segment)).boxed();
+    }
+
+    let routes = prefix
+        .and(
+            base_route
+                .or(login_route)
+                .or(logout_route)
+                .or(open_share_route)
+                .or(api_routes)
+                .or(photos_route)
+                .or(static_route),
+        )
+        .recover(handle_rejection);
+
+    let acceptor = match tls.map(tls::acceptor).transpose() {
+        Ok(acceptor) => acceptor,
+        Err(err) => {
+            eprintln!("{}\n\nError starting HTTPS server", err);
+            std::process::exit(1);
+        }
+    };
+    let until = until.shared();
+    let service = warp::service(routes);
+    let servers = listeners.into_iter().map(|listener| {
+        let incoming = match &acceptor {
+            None => listener.incoming(),
+            Some(acceptor) => tls::accept(listener.incoming(), acceptor.clone()),
+        };
+        // We serve with hyper, rather than warp, so that requests know the address of the client.
+        let service = service.clone();
+        let service = make_service_fn(move |connection: &Accepted| {
+            let remote_addr = RemoteAddr(connection.remote_addr);
+            let mut service = service.clone();
+            return future::ok::<_, Infallible>(service_fn(move |mut request| {
+                request.extensions_mut().insert(remote_addr);
+                return service.call(request);
+            }));
+        });
+        return warp::hyper::Server::builder(warp::hyper::server::accept::from_stream(incoming))
+            .serve(service)
+            .with_graceful_shutdown(until.clone());
+    });
+    for result in future::join_all(servers).await {
+        if let Err(err) = result {
+            eprintln!("{}\n\nError serving", err);
+        }
+    }
 }
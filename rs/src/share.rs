@@ -0,0 +1,219 @@
+use super::auth::{now, open_json, random_string, save_json, JsonLock};
+use super::config::Config;
+use super::error::*;
+use super::image_table::{ImageTable, Row};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::process;
+
+/// The name of the cookie that holds a share token, after its link is opened.
+pub const SHARE_COOKIE: &'static str = "spg_share";
+
+/// What a share link grants access to.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// A directory gallery, by name.
+    Gallery(String),
+    /// A single photo, by MD5.
+    Photo(String),
+}
+
+/// Read-only access to the thumbnails and webviews of a gallery or photo, and optionally to
+/// their originals, until the share expires or is revoked.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Share {
+    pub id: String,
+    pub scope: Scope,
+    pub originals: bool,
+    /// Seconds since the Unix epoch.
+    pub expires: i64,
+}
+
+/// The shares that have not been revoked, in `shares.json` in the data directory, and the key
+/// that signs their tokens. A token is only accepted while its share is in this file, so
+/// deleting a share revokes it.
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct Shares {
+    /// A random HMAC-SHA256 key, encoded with base64. Created with the first share.
+    key: String,
+    pub shares: BTreeMap<String, Share>,
+}
+
+/// The longest that a share may last, which keeps its expiry far from overflowing.
+const MAX_DURATION: i64 = 100 * 365 * 24 * 60 * 60;
+
+/// Parses a duration such as `12h`, `7d` or `2w` as a number of seconds.
+pub fn parse_duration(text: &str) -> Result<i64, CommandError> {
+    let invalid = || {
+        error(format!(
+            "invalid duration {}, expected e.g. 12h, 7d or 2w, and at most 100 years",
+            text
+        ))
+    };
+    let unit = text.chars().last().ok_or_else(invalid)?;
+    let number: i64 = text[..text.len() - unit.len_utf8()]
+        .parse()
+        .map_err(|_err| invalid())?;
+    let seconds = match unit {
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        'w' => 7 * 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    return match number.checked_mul(seconds) {
+        Some(seconds) if 0 < seconds && seconds <= MAX_DURATION => Ok(seconds),
+        _ => Err(invalid()),
+    };
+}
+
+impl Scope {
+    pub fn includes(&self, row: &Row) -> bool {
+        return match self {
+            Scope::Gallery(gallery) => row.gallery() == gallery,
+            Scope::Photo(hash) => format!("{:x}", row.md5()) == *hash,
+        };
+    }
+}
+
+impl Shares {
+    pub fn open(path: &str) -> Result<Shares, CommandError> {
+        return open_json(path);
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), CommandError> {
+        return save_json(path, self);
+    }
+
+    fn mac(&self) -> Hmac<Sha256> {
+        let key = base64::decode_config(&self.key, base64::URL_SAFE_NO_PAD).unwrap_or_default();
+        return Hmac::new_from_slice(&key).expect("HMAC accepts keys of any length");
+    }
+
+    /// Records a new share, and returns it with its token. The token is the share encoded as
+    /// JSON, followed by its signature.
+    pub fn create(&mut self, scope: Scope, originals: bool, seconds: i64) -> (Share, String) {
+        if self.key.is_empty() {
+            self.key = random_string(32);
+        }
+        let now = now();
+        self.shares.retain(|_, share| share.expires > now);
+        let share = Share {
+            id: random_string(6),
+            scope,
+            originals,
+            expires: now.saturating_add(seconds),
+        };
+        let payload =
+            base64::encode_config(serde_json::to_vec(&share).unwrap(), base64::URL_SAFE_NO_PAD);
+        let mut mac = self.mac();
+        mac.update(payload.as_bytes());
+        let signature = base64::encode_config(mac.finalize().into_bytes(), base64::URL_SAFE_NO_PAD);
+        self.shares.insert(share.id.clone(), share.clone());
+        return (share, format!("{}.{}", payload, signature));
+    }
+
+    /// The share that a token grants, if its signature is valid, and it has neither expired nor
+    /// been revoked.
+    pub fn verify(&self, token: &str) -> Option<Share> {
+        if self.key.is_empty() {
+            return None;
+        }
+        let (payload, signature) = token.split_at(token.find('.')?);
+        let signature = base64::decode_config(&signature[1..], base64::URL_SAFE_NO_PAD).ok()?;
+        let mut mac = self.mac();
+        mac.update(payload.as_bytes());
+        mac.verify_slice(&signature).ok()?;
+        let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?;
+        let share: Share = serde_json::from_slice(&payload).ok()?;
+        if share.expires <= now() || self.shares.contains_key(&share.id) == false {
+            return None;
+        }
+        return Some(share);
+    }
+
+    pub fn revoke(&mut self, id: &str) -> Result<(), CommandError> {
+        return match self.shares.remove(id) {
+            Some(_) => Ok(()),
+            None => Err(error(format!("no share with ID {}", id))),
+        };
+    }
+}
+
+/// Share commands from the command line.
+pub enum ShareCommand {
+    /// Shares a gallery or photo for a number of seconds. Photos are named by filename.
+    Create(Scope, bool, i64),
+    List,
+    Revoke(String),
+}
+
+fn share_command_(
+    config: &Config,
+    image_table: &ImageTable,
+    command: ShareCommand,
+) -> Result<(), CommandError> {
+    let _lock = JsonLock::acquire(&config.shares_path)?;
+    let mut shares = Shares::open(&config.shares_path)?;
+    match command {
+        ShareCommand::Create(scope, originals, seconds) => {
+            let scope = match scope {
+                Scope::Gallery(gallery) => {
+                    if image_table.gallery_rows(&gallery).is_empty() {
+                        return Err(error(format!("no gallery named {}", gallery)));
+                    }
+                    Scope::Gallery(gallery)
+                }
+                Scope::Photo(filename) => {
+                    let path = Path::new(&filename)
+                        .canonicalize()
+                        .map_err(trace(format!("finding {}", filename)))?;
+                    let row = image_table
+                        .get_by_original_path(&path.to_string_lossy())
+                        .ok_or_else(|| error(format!("{} is not in the catalog", filename)))?;
+                    Scope::Photo(format!("{:x}", row.md5()))
+                }
+            };
+            let (share, token) = shares.create(scope, originals, seconds);
+            shares.save(&config.shares_path)?;
+            println!("{}\t/share/{}", share.id, token);
+        }
+        ShareCommand::List => {
+            let now = now();
+            for share in shares.shares.values() {
+                if share.expires <= now {
+                    continue;
+                }
+                let scope = match &share.scope {
+                    Scope::Gallery(gallery) => format!("gallery {}", gallery),
+                    Scope::Photo(hash) => format!("photo {}", hash),
+                };
+                let expires = chrono::DateTime::from_timestamp(share.expires, 0)
+                    .map(|expires| expires.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+                    .unwrap_or_default();
+                let originals = if share.originals {
+                    "\twith originals"
+                } else {
+                    ""
+                };
+                println!("{}\t{}\texpires {}{}", share.id, scope, expires, originals);
+            }
+        }
+        ShareCommand::Revoke(id) => {
+            shares.revoke(&id)?;
+            shares.save(&config.shares_path)?;
+        }
+    }
+    return Ok(());
+}
+
+pub fn share_command(config: &Config, image_table: &ImageTable, command: ShareCommand) {
+    if let Err(err) = share_command_(config, image_table, command) {
+        eprintln!("{}\n\nError sharing", err);
+        process::exit(1);
+    }
+}
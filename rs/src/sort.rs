@@ -0,0 +1,217 @@
+use super::image_table::{ImageTable, Row};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::str::FromStr;
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SortKey {
+    /// The filename, with runs of digits compared as numbers, so that 2.jpg precedes 10.jpg
+    Name,
+    /// When the photo was taken
+    Date,
+    /// When the original was last modified
+    Modified,
+    /// The star rating
+    Rating,
+    /// The size of the original in bytes
+    Size,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Asc,
+    Desc,
+}
+
+/// Parses the same names that the server accepts in query strings.
+fn parse_name<T: serde::de::DeserializeOwned>(what: &str, s: &str) -> Result<T, String> {
+    return serde_json::from_value(serde_json::Value::String(s.to_string()))
+        .map_err(|_| format!("unknown {}: {}", what, s));
+}
+
+impl FromStr for SortKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        return parse_name("sort key", s);
+    }
+}
+
+impl FromStr for Direction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        return parse_name("sort order", s);
+    }
+}
+
+/// Splits a string into runs of digits and runs of other characters.
+fn chunks(s: &str) -> Vec<&str> {
+    let mut chunks = vec![];
+    let mut start = 0;
+    let mut previous_is_digit = None;
+    for (i, c) in s.char_indices() {
+        let is_digit = c.is_ascii_digit();
+        if previous_is_digit.is_some_and(|previous| previous != is_digit) {
+            chunks.push(&s[start..i]);
+            start = i;
+        }
+        previous_is_digit = Some(is_digit);
+    }
+    if start < s.len() {
+        chunks.push(&s[start..]);
+    }
+    return chunks;
+}
+
+/// Compares strings in natural order: runs of digits are compared by their numeric value, and
+/// everything else case-insensitively. Strings that are equal in natural order (e.g., "01" and
+/// "1") are ordered lexicographically, so that only equal strings are equal.
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (a_chunks, b_chunks) = (chunks(a), chunks(b));
+    for (x, y) in a_chunks.iter().zip(b_chunks.iter()) {
+        let is_number = |chunk: &str| chunk.starts_with(|c: char| c.is_ascii_digit());
+        let ordering = if is_number(x) && is_number(y) {
+            let x = x.trim_start_matches('0');
+            let y = y.trim_start_matches('0');
+            x.len().cmp(&y.len()).then_with(|| x.cmp(y))
+        } else {
+            x.to_lowercase().cmp(&y.to_lowercase())
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    return a_chunks.len().cmp(&b_chunks.len()).then_with(|| a.cmp(b));
+}
+
+#[derive(Serialize, Deserialize)]
+enum KeyValue<'a> {
+    Text(Cow<'a, str>),
+    Number(i128),
+}
+
+impl KeyValue<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        return match (self, other) {
+            (KeyValue::Text(a), KeyValue::Text(b)) => natural_cmp(a, b),
+            (KeyValue::Number(a), KeyValue::Number(b)) => a.cmp(b),
+            (KeyValue::Text(_), KeyValue::Number(_)) => Ordering::Less,
+            (KeyValue::Number(_), KeyValue::Text(_)) => Ordering::Greater,
+        };
+    }
+}
+
+/// The position of a row in a sort order: its value of the sort key, and then its path and hash,
+/// so that the order is total and does not depend on the order of rows in the catalog.
+#[derive(Serialize, Deserialize)]
+pub struct Position<'a> {
+    key: KeyValue<'a>,
+    path: Cow<'a, str>,
+    md5: u128,
+}
+
+impl<'a> Position<'a> {
+    pub fn new(image_table: &ImageTable, key: SortKey, row: &'a Row) -> Self {
+        let value = match key {
+            SortKey::Name => KeyValue::Text(Cow::Borrowed(row.title())),
+            SortKey::Date => KeyValue::Number(row.timestamp() as i128),
+            SortKey::Modified => KeyValue::Number(row.modified() as i128),
+            SortKey::Rating => {
+                let annotations = image_table.annotations(row.md5());
+                KeyValue::Number(annotations.rating_or(row.description()) as i128)
+            }
+            SortKey::Size => KeyValue::Number(row.size() as i128),
+        };
+        return Position {
+            key: value,
+            path: Cow::Borrowed(&row.original_path),
+            md5: row.md5(),
+        };
+    }
+
+    fn cmp(&self, other: &Self, direction: Direction) -> Ordering {
+        let ordering = self
+            .key
+            .cmp(&other.key)
+            .then_with(|| natural_cmp(&self.path, &other.path))
+            .then_with(|| self.md5.cmp(&other.md5));
+        return match direction {
+            Direction::Asc => ordering,
+            Direction::Desc => ordering.reverse(),
+        };
+    }
+
+    /// An opaque string that a client can send back to resume a listing after this position.
+    pub fn to_cursor(&self) -> String {
+        let json = serde_json::to_vec(self).expect("serializing position");
+        return base64::encode_config(json, base64::URL_SAFE_NO_PAD);
+    }
+
+    pub fn from_cursor(cursor: &str) -> Option<Position<'static>> {
+        let json = base64::decode_config(cursor, base64::URL_SAFE_NO_PAD).ok()?;
+        return serde_json::from_slice(&json).ok();
+    }
+}
+
+pub fn compare(
+    image_table: &ImageTable,
+    key: SortKey,
+    direction: Direction,
+    a: &Row,
+    b: &Row,
+) -> Ordering {
+    return Position::new(image_table, key, a).cmp(&Position::new(image_table, key, b), direction);
+}
+
+pub fn sort_rows(image_table: &ImageTable, rows: &mut [&Row], key: SortKey, direction: Direction) {
+    rows.sort_by(|a, b| compare(image_table, key, direction, a, b));
+}
+
+/// A page of a sorted listing. To get the next page, pass `next_cursor` back as the cursor. Like
+/// timeline cursors, the cursor refers to the last item on this page rather than to an offset,
+/// so pages do not shift when photos are added or removed.
+#[derive(Serialize)]
+pub struct Page<T> {
+    /// The number of items in the whole listing.
+    pub total: usize,
+    pub next_cursor: Option<String>,
+    pub photos: Vec<T>,
+}
+
+/// Returns up to `limit` items that follow `cursor`. The items must be sorted by `key` and
+/// `direction`, and `row` must return the row that determines the position of an item.
+pub fn paginate<'a, T>(
+    image_table: &ImageTable,
+    items: Vec<T>,
+    row: impl Fn(&T) -> &'a Row,
+    key: SortKey,
+    direction: Direction,
+    cursor: Option<&Position<'_>>,
+    limit: usize,
+) -> Page<T> {
+    let total = items.len();
+    let mut photos: Vec<T> = items
+        .into_iter()
+        .filter(|item| {
+            cursor.is_none_or(|cursor| {
+                let position = Position::new(image_table, key, row(item));
+                position.cmp(cursor, direction) == Ordering::Greater
+            })
+        })
+        .collect();
+    let has_more = photos.len() > limit;
+    photos.truncate(limit);
+    let next_cursor = match photos.last() {
+        Some(item) if has_more => Some(Position::new(image_table, key, row(item)).to_cursor()),
+        _ => None,
+    };
+    return Page {
+        total,
+        next_cursor,
+        photos,
+    };
+}
@@ -0,0 +1,112 @@
+use super::dupes;
+use super::error::*;
+use super::image_table::{ImageTable, Row, RowView};
+use super::sort::{self, Direction, SortKey};
+use serde::Serialize;
+
+/// The longest gap between consecutive shots of a burst, in seconds.
+pub const MAX_SECONDS: i64 = 2;
+
+/// The largest Hamming distance between the perceptual hashes of consecutive shots of a burst.
+/// This is looser than `dupes::DEFAULT_DISTANCE`, since the subject moves between shots.
+pub const MAX_DISTANCE: u32 = 10;
+
+/// A stack of similar shots, represented by its cover. A photo that is not part of a burst is a
+/// stack without members, and serializes exactly like a `RowView`.
+#[derive(Serialize)]
+pub struct Stack<'a> {
+    /// The row of the cover, which determines the position of the stack in a listing.
+    #[serde(skip)]
+    pub row: &'a Row,
+    #[serde(flatten)]
+    pub cover: RowView<'a>,
+    /// All the photos in the stack, including the cover, in the order they were taken.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub members: Vec<RowView<'a>>,
+}
+
+/// Photos that we have not decoded have neither a capture time nor a perceptual hash, so we do not
+/// know whether they are part of a burst.
+fn is_burst(previous: &Row, next: &Row) -> bool {
+    return previous.is_decoded()
+        && next.is_decoded()
+        && next.timestamp() - previous.timestamp() <= MAX_SECONDS
+        && dupes::distance(previous.phash(), next.phash()) <= MAX_DISTANCE;
+}
+
+/// Orders the photos in a gallery by capture time, and groups consecutive photos that are part
+/// of the same burst.
+fn group<'a>(image_table: &'a ImageTable, gallery: &str) -> Vec<Vec<&'a Row>> {
+    let mut rows = image_table.gallery_rows(gallery);
+    rows.sort_by(|a, b| {
+        a.timestamp()
+            .cmp(&b.timestamp())
+            .then_with(|| a.original_path.cmp(&b.original_path))
+    });
+    let mut groups: Vec<Vec<&Row>> = vec![];
+    for row in rows {
+        match groups.last_mut() {
+            Some(group) if is_burst(group.last().unwrap(), row) => group.push(row),
+            _ => groups.push(vec![row]),
+        }
+    }
+    return groups;
+}
+
+/// The cover of a stack is the photo that the user chose, or else the first shot.
+fn cover<'a>(image_table: &ImageTable, group: &[&'a Row]) -> &'a Row {
+    return group
+        .iter()
+        .find(|row| image_table.stack_covers.contains(&row.md5()))
+        .unwrap_or(&group[0]);
+}
+
+/// The stacks in a gallery, ordered by their covers. The members of a stack are always in the
+/// order they were taken.
+pub fn stacks<'a>(
+    image_table: &'a ImageTable,
+    gallery: &str,
+    key: SortKey,
+    direction: Direction,
+) -> Vec<Stack<'a>> {
+    let mut groups: Vec<(&Row, Vec<&Row>)> = group(image_table, gallery)
+        .into_iter()
+        .map(|group| (cover(image_table, &group), group))
+        .collect();
+    groups.sort_by(|(a, _), (b, _)| sort::compare(image_table, key, direction, a, b));
+    return groups
+        .into_iter()
+        .map(|(cover, group)| {
+            let members = if group.len() > 1 {
+                group.iter().map(|row| row.view()).collect()
+            } else {
+                vec![]
+            };
+            Stack {
+                row: cover,
+                cover: cover.view(),
+                members,
+            }
+        })
+        .collect();
+}
+
+/// Chooses a photo as the cover of its stack, replacing the previous choice.
+pub fn set_cover(image_table: &mut ImageTable, hash: u128) -> Result<(), CommandError> {
+    let row = image_table
+        .get_by_hash(hash)
+        .ok_or_else(|| error(format!("no photo with hash {:x}", hash)))?;
+    let members: Vec<u128> = group(image_table, row.gallery())
+        .into_iter()
+        .find(|group| group.iter().any(|row| row.md5() == hash))
+        .filter(|group| group.len() > 1)
+        .ok_or_else(|| error("photo is not part of a stack"))?
+        .iter()
+        .map(|row| row.md5())
+        .collect();
+    for member in members {
+        image_table.stack_covers.remove(&member);
+    }
+    image_table.stack_covers.insert(hash);
+    return Ok(());
+}
@@ -163,3 +163,1964 @@ fn integration() {
     //         .dir(&p).read().expect("stat 2.jpg"),
     //     "Nothing is in the gallery with this path.");
 }
+
+/// Copies a test image, adding EXIF data with a camera make and a GPS position.
+fn copy_with_exif(src: &str, dst: &str) {
+    use exif::experimental::Writer;
+    use exif::{Field, In, Rational, Tag, Value};
+    use img_parts::jpeg::Jpeg;
+    use img_parts::ImageEXIF;
+
+    let ascii = |s: &str| Value::Ascii(vec![s.as_bytes().to_vec()]);
+    let degrees = |d: u32, m: u32| {
+        Value::Rational(vec![
+            Rational { num: d, denom: 1 },
+            Rational { num: m, denom: 1 },
+            Rational { num: 0, denom: 1 },
+        ])
+    };
+    let fields = vec![
+        (Tag::Make, ascii("Apple")),
+        (Tag::Model, ascii("iPhone 12")),
+        (Tag::GPSLatitudeRef, ascii("N")),
+        (Tag::GPSLatitude, degrees(42, 21)),
+        (Tag::GPSLongitudeRef, ascii("W")),
+        (Tag::GPSLongitude, degrees(71, 3)),
+    ];
+    let fields: Vec<_> = fields
+        .into_iter()
+        .map(|(tag, value)| Field {
+            tag,
+            ifd_num: In::PRIMARY,
+            value,
+        })
+        .collect();
+    let mut writer = Writer::new();
+    for field in fields.iter() {
+        writer.push_field(field);
+    }
+    let mut tiff = std::io::Cursor::new(Vec::new());
+    writer.write(&mut tiff, false).unwrap();
+    let mut jpeg = Jpeg::from_bytes(fs::read(src).unwrap().into()).unwrap();
+    jpeg.set_exif(Some(tiff.into_inner().into()));
+    jpeg.encoder()
+        .write_to(fs::File::create(dst).unwrap())
+        .unwrap();
+}
+
+#[test]
+fn metadata_policy() {
+    use super::metadata::{self, MetadataPolicy};
+    use exif::{In, Tag};
+
+    let d = tempfile::tempdir_in(".").expect("creating temp directory");
+    let p = d.path().to_str().unwrap();
+    fs::create_dir(format!("{}/a", p)).unwrap();
+    copy_with_exif("./test_data/1.jpg", &format!("{}/a/1.jpg", p));
+
+    cmd!("./target/debug/spg", "--config-path", ".spg", "init")
+        .dir(&p)
+        .run()
+        .expect("spg init");
+    cmd!("./target/debug/spg", "--config-path", ".spg", "sync", "a")
+        .dir(&p)
+        .run()
+        .expect("sync a/");
+
+    // The default policy keeps the camera, but not the location.
+    let webview = fs::read_dir(format!("{}/.spg/www/photos", p))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.to_string_lossy().ends_with("-webview.jpg"))
+        .expect("finding web-view image");
+    let exif = metadata::read_exif(&webview).expect("reading EXIF of web-view image");
+    assert!(exif.get_field(Tag::Make, In::PRIMARY).is_some());
+    assert!(exif.get_field(Tag::GPSLatitude, In::PRIMARY).is_none());
+
+    let original = fs::read(format!("{}/a/1.jpg", p)).unwrap();
+    let stripped = metadata::strip_jpeg(original.clone(), MetadataPolicy::StripAll).unwrap();
+    let stripped_path = format!("{}/stripped.jpg", p);
+    fs::write(&stripped_path, stripped).unwrap();
+    assert!(metadata::read_exif(&stripped_path).is_none());
+    assert_eq!(
+        metadata::strip_jpeg(original.clone(), MetadataPolicy::KeepAll).unwrap(),
+        original
+    );
+}
+
+/// An ICC profile for Display P3. We approximate its transfer curve with a gamma of 2.2.
+fn display_p3_profile() -> Vec<u8> {
+    let xyz = |x: f64, y: f64, z: f64| {
+        let mut tag = b"XYZ \0\0\0\0".to_vec();
+        for value in [x, y, z].iter() {
+            tag.extend(&((value * 65536.0).round() as i32).to_be_bytes());
+        }
+        return tag;
+    };
+    // A curve with one entry is a gamma, in 8.8 fixed point.
+    let gamma = [
+        &b"curv\0\0\0\0"[..],
+        &1u32.to_be_bytes(),
+        &[0x02, 0x33, 0, 0],
+    ]
+    .concat();
+    let tags: Vec<(&[u8], Vec<u8>)> = vec![
+        (b"rXYZ", xyz(0.51512, 0.24120, -0.00105)),
+        (b"gXYZ", xyz(0.29198, 0.69225, 0.04189)),
+        (b"bXYZ", xyz(0.15710, 0.06657, 0.78407)),
+        (b"wtpt", xyz(0.96420, 1.0, 0.82491)),
+        (b"rTRC", gamma.clone()),
+        (b"gTRC", gamma.clone()),
+        (b"bTRC", gamma),
+    ];
+    let mut table = (tags.len() as u32).to_be_bytes().to_vec();
+    let mut data = vec![];
+    let data_start = 128 + 4 + 12 * tags.len();
+    for (signature, tag) in tags {
+        table.extend(signature);
+        table.extend(&((data_start + data.len()) as u32).to_be_bytes());
+        table.extend(&(tag.len() as u32).to_be_bytes());
+        data.extend(tag);
+    }
+    let mut header = vec![0u8; 128];
+    let size = (header.len() + table.len() + data.len()) as u32;
+    header[0..4].copy_from_slice(&size.to_be_bytes());
+    header[8..12].copy_from_slice(&[2, 0x10, 0, 0]);
+    header[12..16].copy_from_slice(b"mntr");
+    header[16..20].copy_from_slice(b"RGB ");
+    header[20..24].copy_from_slice(b"XYZ ");
+    header[36..40].copy_from_slice(b"acsp");
+    return [header, table, data].concat();
+}
+
+#[test]
+fn color_profiles() {
+    use image::{DynamicImage, ImageOutputFormat, Rgb, RgbImage};
+    use img_parts::jpeg::Jpeg;
+    use img_parts::ImageICC;
+
+    let d = tempfile::tempdir_in(".").expect("creating temp directory");
+    let p = d.path().to_str().unwrap();
+    fs::create_dir(format!("{}/a", p)).unwrap();
+    let image = RgbImage::from_pixel(64, 48, Rgb([200, 120, 80]));
+    let mut bytes = vec![];
+    DynamicImage::ImageRgb8(image)
+        .write_to(&mut bytes, ImageOutputFormat::Jpeg(95))
+        .unwrap();
+    let mut jpeg = Jpeg::from_bytes(bytes.into()).unwrap();
+    jpeg.set_icc_profile(Some(display_p3_profile().into()));
+    jpeg.encoder()
+        .write_to(fs::File::create(format!("{}/a/p3.jpg", p)).unwrap())
+        .unwrap();
+
+    cmd!("./target/debug/spg", "--config-path", ".spg", "init")
+        .dir(&p)
+        .run()
+        .expect("spg init");
+    cmd!("./target/debug/spg", "--config-path", ".spg", "sync", "a")
+        .dir(&p)
+        .run()
+        .expect("sync a/");
+
+    let webview = fs::read_dir(format!("{}/.spg/www/photos", p))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.to_string_lossy().ends_with("-webview.jpg"))
+        .expect("finding web-view image");
+    // Browsers assume that the derivative is sRGB, so it must not carry the profile.
+    let derivative = Jpeg::from_bytes(fs::read(&webview).unwrap().into()).unwrap();
+    assert!(derivative.icc_profile().is_none());
+    // Display P3 (200, 120, 80) is sRGB (214, 116, 69).
+    let pixel = image::open(&webview).unwrap().to_rgb8()[(32, 24)];
+    for (actual, expected) in pixel.0.iter().zip([214, 116, 69].iter()) {
+        assert!(
+            (*actual as i32 - expected).abs() <= 4,
+            "expected sRGB (214, 116, 69), got {:?}",
+            pixel
+        );
+    }
+}
+
+#[test]
+fn capture_metadata() {
+    let d = tempfile::tempdir_in(".").expect("creating temp directory");
+    let p = d.path().to_str().unwrap();
+    fs::create_dir(format!("{}/a", p)).unwrap();
+    copy_with_exif("./test_data/1.jpg", &format!("{}/a/1.jpg", p));
+
+    cmd!("./target/debug/spg", "--config-path", ".spg", "init")
+        .dir(&p)
+        .run()
+        .expect("spg init");
+    cmd!(
+        "./target/debug/spg",
+        "--config-path",
+        ".spg",
+        "add",
+        "a/1.jpg"
+    )
+    .dir(&p)
+    .run()
+    .expect("adding 1.jpg");
+
+    assert_eq!(
+        cmd!(
+            "./target/debug/spg",
+            "--config-path",
+            ".spg",
+            "stat",
+            "a/1.jpg"
+        )
+        .dir(&p)
+        .read()
+        .expect("stat 1.jpg"),
+        "The image is in the gallery.\nCamera: Apple iPhone 12\nGPS: 42.350000, -71.050000"
+    );
+}
+
+#[test]
+fn map_features() {
+    use super::image_table::ImageTable;
+    use super::map::{self, MapQuery};
+
+    let d = tempfile::tempdir_in(".").expect("creating temp directory");
+    let p = d.path().to_str().unwrap();
+    fs::create_dir(format!("{}/a", p)).unwrap();
+    copy_with_exif("./test_data/1.jpg", &format!("{}/a/1.jpg", p));
+    copy_with_exif("./test_data/2.jpg", &format!("{}/a/2.jpg", p));
+    fs::copy("./test_data/3.jpg", format!("{}/a/3.jpg", p)).unwrap();
+    // A copy has the same hash as its original, but is a separate photo on the map.
+    fs::copy(format!("{}/a/1.jpg", p), format!("{}/a/copy.jpg", p)).unwrap();
+
+    cmd!("./target/debug/spg", "--config-path", ".spg", "init")
+        .dir(&p)
+        .run()
+        .expect("spg init");
+    cmd!("./target/debug/spg", "--config-path", ".spg", "sync", "a")
+        .dir(&p)
+        .run()
+        .expect("sync a/");
+
+    let image_table = ImageTable::open(format!("{}/.spg/image_table.bincode", p));
+    let query = |bbox: &str, zoom: u32| MapQuery {
+        bbox: Some(bbox.to_string()),
+        zoom: Some(zoom),
+        gallery: None,
+        from: None,
+        to: None,
+    };
+
+    // The three geotagged photos are clustered at low zoom, and separate at high zoom.
+    let clustered = map::features(&image_table, &query("-72,42,-71,43", 3)).unwrap();
+    assert_eq!(clustered.features.len(), 1);
+    assert_eq!(clustered.features[0].properties.count, 3);
+    let coordinates = clustered.features[0].geometry.coordinates;
+    assert!((coordinates[0] + 71.05).abs() < 1e-9 && (coordinates[1] - 42.35).abs() < 1e-9);
+    let separate = map::features(&image_table, &query("-72,42,-71,43", 20)).unwrap();
+    assert_eq!(separate.features.len(), 3);
+
+    let elsewhere = map::features(&image_table, &query("0,0,1,1", 3)).unwrap();
+    assert_eq!(elsewhere.features.len(), 0);
+    assert!(map::features(&image_table, &query("0,0,1", 3)).is_none());
+}
+
+#[test]
+fn xmp_sidecar() {
+    let d = tempfile::tempdir_in(".").expect("creating temp directory");
+    let p = d.path().to_str().unwrap();
+    fs::create_dir(format!("{}/a", p)).unwrap();
+    fs::copy("./test_data/1.jpg", format!("{}/a/1.jpg", p)).unwrap();
+
+    cmd!("./target/debug/spg", "--config-path", ".spg", "init")
+        .dir(&p)
+        .run()
+        .expect("spg init");
+    cmd!("./target/debug/spg", "--config-path", ".spg", "sync", "a")
+        .dir(&p)
+        .run()
+        .expect("sync a/");
+
+    // Adding a sidecar updates the image on the next sync, even though the image is unchanged.
+    fs::write(
+        format!("{}/a/1.jpg.xmp", p),
+        r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""
+    xmlns:xmp="http://ns.adobe.com/xap/1.0/"
+    xmlns:dc="http://purl.org/dc/elements/1.1/"
+   xmp:Rating="4">
+   <dc:title><rdf:Alt><rdf:li xml:lang="x-default">Sunset</rdf:li></rdf:Alt></dc:title>
+   <dc:subject><rdf:Bag><rdf:li>beach</rdf:li><rdf:li>family</rdf:li></rdf:Bag></dc:subject>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>"#,
+    )
+    .unwrap();
+    cmd!("./target/debug/spg", "--config-path", ".spg", "sync", "a")
+        .dir(&p)
+        .run()
+        .expect("sync a/");
+
+    assert_eq!(
+        cmd!(
+            "./target/debug/spg",
+            "--config-path",
+            ".spg",
+            "stat",
+            "a/1.jpg"
+        )
+        .dir(&p)
+        .read()
+        .expect("stat 1.jpg"),
+        "The image is in the gallery.\nTitle: Sunset\nKeywords: beach, family\nRating: 4"
+    );
+}
+
+#[test]
+fn heic_embedded_xmp() {
+    use super::xmp;
+
+    // A HEIF box, with its size and type in front of its contents.
+    let heif_box = |kind: &str, contents: Vec<u8>| {
+        let mut bytes = (8 + contents.len() as u32).to_be_bytes().to_vec();
+        bytes.extend(kind.as_bytes());
+        bytes.extend(contents);
+        return bytes;
+    };
+    let packet = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about="" xmlns:dc="http://purl.org/dc/elements/1.1/">
+   <dc:title><rdf:Alt><rdf:li xml:lang="x-default">Harbor</rdf:li></rdf:Alt></dc:title>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>"#;
+
+    let ftyp = heif_box("ftyp", b"heic\0\0\0\0mif1heic".to_vec());
+    // Version 2 of infe declares item 1 to be XMP.
+    let mut infe = vec![2, 0, 0, 0, 0, 1, 0, 0];
+    infe.extend(b"mime\0application/rdf+xml\0");
+    let mut iinf = vec![0, 0, 0, 0, 0, 1];
+    iinf.extend(heif_box("infe", infe));
+    // Version 0 of iloc, with 4-byte offsets and lengths, and no base offsets. The offset of the
+    // single extent of item 1 is filled in below.
+    let mut iloc = vec![0, 0, 0, 0, 0x44, 0, 0, 1, 0, 1, 0, 0, 0, 1, 0, 0, 0, 0];
+    iloc.extend((packet.len() as u32).to_be_bytes());
+    let meta = |iloc: Vec<u8>| {
+        let mut meta = vec![0, 0, 0, 0];
+        meta.extend(heif_box("iinf", iinf.clone()));
+        meta.extend(heif_box("iloc", iloc));
+        return heif_box("meta", meta);
+    };
+    let offset = (ftyp.len() + meta(iloc.clone()).len() + 8) as u32;
+    iloc[14..18].copy_from_slice(&offset.to_be_bytes());
+    let mut heic = ftyp;
+    heic.extend(meta(iloc));
+    heic.extend(heif_box("mdat", packet.as_bytes().to_vec()));
+
+    let d = tempfile::tempdir_in(".").expect("creating temp directory");
+    let path = d.path().join("1.heic");
+    fs::write(&path, &heic).unwrap();
+    assert_eq!(
+        xmp::read_description(&path).title.as_deref(),
+        Some("Harbor")
+    );
+
+    // A truncated file has no description, rather than a bad one.
+    fs::write(&path, &heic[..heic.len() - 10]).unwrap();
+    assert_eq!(xmp::read_description(&path), xmp::Description::default());
+}
+
+#[test]
+fn annotations_survive_moves() {
+    let d = tempfile::tempdir_in(".").expect("creating temp directory");
+    let p = d.path().to_str().unwrap();
+    fs::create_dir(format!("{}/a", p)).unwrap();
+    fs::create_dir(format!("{}/a/b", p)).unwrap();
+    fs::copy("./test_data/1.jpg", format!("{}/a/1.jpg", p)).unwrap();
+
+    cmd!("./target/debug/spg", "--config-path", ".spg", "init")
+        .dir(&p)
+        .run()
+        .expect("spg init");
+    cmd!("./target/debug/spg", "--config-path", ".spg", "sync", "a")
+        .dir(&p)
+        .run()
+        .expect("sync a/");
+    cmd!(
+        "./target/debug/spg",
+        "--config-path",
+        ".spg",
+        "tag",
+        "a/1.jpg",
+        "beach",
+        "dog",
+        "--caption",
+        "Rex at the beach"
+    )
+    .dir(&p)
+    .run()
+    .expect("tagging 1.jpg");
+    cmd!(
+        "./target/debug/spg",
+        "--config-path",
+        ".spg",
+        "tag",
+        "a/1.jpg",
+        "dog",
+        "--remove"
+    )
+    .dir(&p)
+    .run()
+    .expect("untagging 1.jpg");
+    cmd!(
+        "./target/debug/spg",
+        "--config-path",
+        ".spg",
+        "rate",
+        "a/1.jpg",
+        "4",
+        "--favorite"
+    )
+    .dir(&p)
+    .run()
+    .expect("rating 1.jpg");
+
+    fs::rename(format!("{}/a/1.jpg", p), format!("{}/a/b/1.jpg", p)).unwrap();
+    cmd!("./target/debug/spg", "--config-path", ".spg", "sync", "a")
+        .dir(&p)
+        .run()
+        .expect("sync a/");
+
+    assert_eq!(
+        cmd!(
+            "./target/debug/spg",
+            "--config-path",
+            ".spg",
+            "stat",
+            "a/b/1.jpg"
+        )
+        .dir(&p)
+        .read()
+        .expect("stat 1.jpg"),
+        "The image is in the gallery.\nTags: beach\nStars: 4\nFavorite\nUser caption: Rex at the beach"
+    );
+}
+
+/// A temporary directory with a catalog in `.spg`, in which a test runs `spg`.
+struct Fixture {
+    dir: tempfile::TempDir,
+}
+
+impl Fixture {
+    fn new() -> Fixture {
+        let dir = tempfile::tempdir_in(".").expect("creating temp directory");
+        let fixture = Fixture { dir };
+        fixture.spg(vec!["init"]).run().expect("spg init");
+        return fixture;
+    }
+
+    fn path(&self) -> &str {
+        return self.dir.path().to_str().unwrap();
+    }
+
+    /// The absolute path of the directory, as the catalog records it.
+    fn root(&self) -> std::path::PathBuf {
+        return self.dir.path().canonicalize().unwrap();
+    }
+
+    /// Runs `spg` in the directory, with the catalog in `.spg`.
+    fn spg(&self, args: Vec<&str>) -> duct::Expression {
+        let mut all_args = vec!["--config-path", ".spg"];
+        all_args.extend(args);
+        return cmd("./target/debug/spg", all_args).dir(self.path());
+    }
+
+    fn image_table(&self) -> super::image_table::ImageTable {
+        return super::image_table::ImageTable::open(format!(
+            "{}/.spg/image_table.bincode",
+            self.path()
+        ));
+    }
+
+    /// The URLs of the thumbnail and the original of the first photo in a gallery.
+    fn photo_urls(&self, gallery: &str) -> (String, String) {
+        let image_table = self.image_table();
+        let row = image_table
+            .rows()
+            .iter()
+            .find(|row| row.gallery() == gallery)
+            .unwrap();
+        return (
+            format!("/photos/{}", row.thumbnail_path()),
+            format!("/api/original/{:x}", row.md5()),
+        );
+    }
+
+    /// Starts `spg serve` on a free port, with the other `args`, and waits until it answers.
+    fn serve(&self, args: Vec<&str>) -> Server {
+        let port = free_port().to_string();
+        let mut all_args = vec!["--port", &port];
+        all_args.extend(args);
+        let server = Server::start(self, all_args, format!("http://127.0.0.1:{}", port));
+        assert!(
+            wait_until(|| server.get("/", vec![]).is_empty() == false),
+            "server did not start"
+        );
+        return server;
+    }
+}
+
+/// A port that was free a moment ago, for tests that start the server.
+fn free_port() -> u16 {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("binding a free port");
+    return listener.local_addr().unwrap().port();
+}
+
+/// Checks `condition` every 100 ms, for up to 20 seconds, until it holds.
+fn wait_until(mut condition: impl FnMut() -> bool) -> bool {
+    for _ in 0..200 {
+        if condition() {
+            return true;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    return false;
+}
+
+/// A running `spg serve`, which is stopped when it is dropped. Requests go through curl.
+struct Server {
+    handle: duct::Handle,
+    /// The URL of the server, without a trailing slash.
+    base: String,
+}
+
+impl Server {
+    /// Starts `spg serve` with `args`, for a server that answers at `base`.
+    fn start(fixture: &Fixture, args: Vec<&str>, base: String) -> Server {
+        let mut all_args = vec!["serve"];
+        all_args.extend(args);
+        let handle = fixture
+            .spg(all_args)
+            .stderr_null()
+            .start()
+            .expect("starting server");
+        return Server { handle, base };
+    }
+
+    fn url(&self, path: &str) -> String {
+        return format!("{}{}", self.base, path);
+    }
+
+    /// A curl command that requests `path` with the other `args`. It prints the status line,
+    /// headers and body of the response, or nothing if it could not connect.
+    fn request(&self, args: Vec<&str>, path: &str) -> duct::Expression {
+        let url = self.url(path);
+        let mut all_args = vec!["--silent", "--include"];
+        all_args.extend(args);
+        all_args.push(&url);
+        return cmd("curl", all_args).stdout_capture().unchecked();
+    }
+
+    /// The response to a GET of `path`, with the given headers.
+    fn get(&self, path: &str, headers: Vec<&str>) -> String {
+        let mut args = vec![];
+        for header in headers {
+            args.push("--header");
+            args.push(header);
+        }
+        let output = self.request(args, path).run().unwrap();
+        return String::from_utf8_lossy(&output.stdout).to_string();
+    }
+
+    /// The response to a POST of `json` to `path`.
+    fn post(&self, path: &str, json: &str) -> String {
+        let args = vec!["--header", "Content-Type: application/json", "--data", json];
+        let output = self.request(args, path).run().unwrap();
+        return String::from_utf8_lossy(&output.stdout).to_string();
+    }
+
+    /// Logs in, and returns the session cookie.
+    fn login(&self, user: &str, password: &str) -> String {
+        let json = serde_json::json!({"user": user, "password": password}).to_string();
+        let response = self.post("/api/login", &json);
+        return cookie(&response).unwrap_or_else(|| panic!("could not log in: {}", response));
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.handle.kill();
+    }
+}
+
+/// The value of a header of a response.
+fn header<'a>(response: &'a str, name: &str) -> Option<&'a str> {
+    let prefix = format!("{}: ", name);
+    return response
+        .split("\r\n\r\n")
+        .next()
+        .unwrap()
+        .lines()
+        .find_map(|line| line.strip_prefix(&prefix));
+}
+
+/// The cookie that a response sets, without its attributes.
+fn cookie(response: &str) -> Option<String> {
+    let cookie = header(response, "set-cookie")?;
+    return Some(cookie.split(';').next().unwrap().to_string());
+}
+
+/// The body of a successful response, as JSON.
+fn json(response: &str) -> Option<serde_json::Value> {
+    if response.starts_with("HTTP/1.1 200") == false {
+        return None;
+    }
+    let body = response.split_once("\r\n\r\n").unwrap().1;
+    return Some(serde_json::from_str(body).expect("parsing JSON"));
+}
+
+#[test]
+fn albums() {
+    let fixture = Fixture::new();
+    let p = fixture.path();
+    fs::create_dir(format!("{}/a", p)).unwrap();
+    fs::create_dir(format!("{}/a/b", p)).unwrap();
+    fs::copy("./test_data/1.jpg", format!("{}/a/1.jpg", p)).unwrap();
+    fs::copy("./test_data/2.jpg", format!("{}/a/2.jpg", p)).unwrap();
+
+    fixture.spg(vec!["sync", "a"]).run().expect("sync a/");
+    fixture
+        .spg(vec!["album", "create", "trip"])
+        .run()
+        .expect("creating album");
+    fixture
+        .spg(vec!["album", "add", "trip", "a/2.jpg", "a/1.jpg"])
+        .run()
+        .expect("adding to album");
+    fixture
+        .spg(vec!["tag", "a/1.jpg", "beach"])
+        .run()
+        .expect("tagging 1.jpg");
+    fixture
+        .spg(vec!["album", "create", "beach", "--query", "tag:beach"])
+        .run()
+        .expect("creating smart album");
+    assert!(fixture
+        .spg(vec!["album", "add", "beach", "a/2.jpg"])
+        .stderr_null()
+        .run()
+        .is_err());
+
+    // Albums follow photos when they move.
+    fs::rename(format!("{}/a/1.jpg", p), format!("{}/a/b/1.jpg", p)).unwrap();
+    fixture.spg(vec!["sync", "a"]).run().expect("sync a/");
+
+    let root = fixture.root();
+    let path = |name: &str| {
+        return root.join(name).to_string_lossy().to_string();
+    };
+    assert_eq!(
+        fixture.spg(vec!["album", "list", "trip"]).read().unwrap(),
+        format!("{}\n{}", path("a/2.jpg"), path("a/b/1.jpg"))
+    );
+    assert_eq!(
+        fixture.spg(vec!["album", "list", "beach"]).read().unwrap(),
+        path("a/b/1.jpg")
+    );
+}
+
+#[test]
+fn search() {
+    let fixture = Fixture::new();
+    let p = fixture.path();
+    fs::create_dir(format!("{}/a", p)).unwrap();
+    copy_with_exif("./test_data/1.jpg", &format!("{}/a/1.jpg", p));
+    fs::copy("./test_data/2.jpg", format!("{}/a/2.jpg", p)).unwrap();
+    fs::copy("./test_data/3.jpg", format!("{}/a/3.jpg", p)).unwrap();
+    // Lightroom rated 1.jpg, which spg has not.
+    fs::write(
+        format!("{}/a/1.xmp", p),
+        r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about="" xmlns:xmp="http://ns.adobe.com/xap/1.0/" xmp:Rating="5"/>
+ </rdf:RDF>
+</x:xmpmeta>"#,
+    )
+    .unwrap();
+
+    fixture.spg(vec!["sync", "a"]).run().expect("sync a/");
+    fixture
+        .spg(vec!["rate", "a/2.jpg", "4"])
+        .run()
+        .expect("rating 2.jpg");
+    fixture
+        .spg(vec!["rate", "a/3.jpg", "2"])
+        .run()
+        .expect("rating 3.jpg");
+
+    let root = fixture.root();
+    let path = |name: &str| {
+        return root.join(name).to_string_lossy().to_string();
+    };
+    let search = |args: Vec<&str>| {
+        let mut all_args = vec!["search"];
+        all_args.extend(args);
+        return fixture.spg(all_args).read().unwrap();
+    };
+    assert_eq!(search(vec!["camera:iphone"]), path("a/1.jpg"));
+    assert_eq!(
+        search(vec!["rating:>=2", "--sort", "rating"]),
+        format!(
+            "{}\n{}\n{}",
+            path("a/1.jpg"),
+            path("a/2.jpg"),
+            path("a/3.jpg")
+        )
+    );
+    assert_eq!(
+        search(vec![
+            "--sort",
+            "name",
+            "--order",
+            "asc",
+            "--",
+            "-camera:iphone type:jpeg"
+        ]),
+        format!("{}\n{}", path("a/2.jpg"), path("a/3.jpg"))
+    );
+    assert_eq!(
+        search(vec![
+            "path:a/", "--sort", "name", "--offset", "1", "--limit", "1"
+        ]),
+        path("a/2.jpg")
+    );
+    assert!(fixture
+        .spg(vec!["search", "date:2021-13"])
+        .stderr_null()
+        .run()
+        .is_err());
+}
+
+#[test]
+fn dupes() {
+    let fixture = Fixture::new();
+    let p = fixture.path();
+    fs::create_dir(format!("{}/a", p)).unwrap();
+    fs::copy("./test_data/1.jpg", format!("{}/a/1.jpg", p)).unwrap();
+    fs::copy("./test_data/2.jpg", format!("{}/a/2.jpg", p)).unwrap();
+    // A smaller re-export of 1.jpg, which has a different MD5.
+    image::open("./test_data/1.jpg")
+        .unwrap()
+        .thumbnail(400, 400)
+        .save(format!("{}/a/small.jpg", p))
+        .unwrap();
+
+    fixture.spg(vec!["sync", "a"]).run().expect("sync a/");
+
+    let root = fixture.root();
+    let output = fixture.spg(vec!["dupes"]).read().unwrap();
+    let lines: Vec<&str> = output.lines().collect();
+    assert_eq!(lines.len(), 2, "{}", output);
+    assert!(lines[0].starts_with(&root.join("a/1.jpg").to_string_lossy().to_string()));
+    assert!(lines[1].starts_with(&root.join("a/small.jpg").to_string_lossy().to_string()));
+}
+
+#[test]
+fn stacks() {
+    use super::sort::{Direction, SortKey};
+    use super::stacks;
+
+    let fixture = Fixture::new();
+    let p = fixture.path();
+    fs::create_dir(format!("{}/a", p)).unwrap();
+    fs::copy("./test_data/1.jpg", format!("{}/a/1.jpg", p)).unwrap();
+    fs::copy("./test_data/2.jpg", format!("{}/a/2.jpg", p)).unwrap();
+    // Stands in for the second shot of a burst.
+    image::open("./test_data/1.jpg")
+        .unwrap()
+        .thumbnail(400, 400)
+        .save(format!("{}/a/1b.jpg", p))
+        .unwrap();
+    // The test images have no EXIF data, so their modified times are their capture times.
+    for (name, time) in [("1.jpg", "@1000"), ("1b.jpg", "@1001"), ("2.jpg", "@1002")].iter() {
+        cmd!("touch", "-d", time, format!("{}/a/{}", p, name))
+            .run()
+            .unwrap();
+    }
+
+    fixture.spg(vec!["sync", "a"]).run().expect("sync a/");
+
+    let root = fixture.root();
+    let path = |name: &str| root.join(name).to_string_lossy().to_string();
+    let covers = || {
+        let image_table = fixture.image_table();
+        return stacks::stacks(&image_table, "a", SortKey::Name, Direction::Asc)
+            .iter()
+            .map(|stack| (stack.cover.original_path.to_string(), stack.members.len()))
+            .collect::<Vec<_>>();
+    };
+    assert_eq!(covers(), vec![(path("a/1.jpg"), 2), (path("a/2.jpg"), 0)]);
+
+    fixture
+        .spg(vec!["cover", "a/1b.jpg"])
+        .run()
+        .expect("choosing cover");
+    assert_eq!(covers(), vec![(path("a/1b.jpg"), 2), (path("a/2.jpg"), 0)]);
+    assert!(fixture
+        .spg(vec!["cover", "a/2.jpg"])
+        .stderr_null()
+        .run()
+        .is_err());
+}
+
+#[test]
+fn sort_order() {
+    let fixture = Fixture::new();
+    let p = fixture.path();
+    fs::create_dir(format!("{}/a", p)).unwrap();
+    for (src, dst, time) in [
+        ("1", "10", "@1000"),
+        ("2", "2", "@1002"),
+        ("3", "1", "@1001"),
+    ]
+    .iter()
+    {
+        let dst = format!("{}/a/{}.jpg", p, dst);
+        fs::copy(format!("./test_data/{}.jpg", src), &dst).unwrap();
+        cmd!("touch", "-d", time, dst).run().unwrap();
+    }
+
+    fixture.spg(vec!["sync", "a"]).run().expect("sync a/");
+
+    let root = fixture.root();
+    let paths = |names: &[&str]| {
+        return names
+            .iter()
+            .map(|name| root.join("a").join(name).to_string_lossy().to_string())
+            .collect::<Vec<_>>()
+            .join("\n");
+    };
+    assert_eq!(
+        fixture.spg(vec!["list", "a"]).read().unwrap(),
+        paths(&["1.jpg", "2.jpg", "10.jpg"])
+    );
+    assert_eq!(
+        fixture
+            .spg(vec!["list", "a", "--sort", "modified", "--order", "desc"])
+            .read()
+            .unwrap(),
+        paths(&["2.jpg", "1.jpg", "10.jpg"])
+    );
+
+    // Re-adding a photo moves it to the end of the catalog, but not of the listing.
+    fixture
+        .spg(vec!["rm", "a/1.jpg"])
+        .run()
+        .expect("removing 1.jpg");
+    fixture
+        .spg(vec!["add", "a/1.jpg"])
+        .run()
+        .expect("adding 1.jpg");
+    assert_eq!(
+        fixture.spg(vec!["list", "a"]).read().unwrap(),
+        paths(&["1.jpg", "2.jpg", "10.jpg"])
+    );
+
+    // Pages of two photos. The cursor remains valid after the last photo on the page is removed.
+    use super::image_table::ImageTable;
+    use super::sort::{self, Direction, Position, SortKey};
+    let mut image_table = fixture.image_table();
+    let page = |image_table: &ImageTable, cursor: Option<&Position>| {
+        let mut rows = image_table.gallery_rows("a");
+        sort::sort_rows(image_table, &mut rows, SortKey::Name, Direction::Asc);
+        let page = sort::paginate(
+            image_table,
+            rows,
+            |row| row,
+            SortKey::Name,
+            Direction::Asc,
+            cursor,
+            2,
+        );
+        let titles: Vec<String> = page
+            .photos
+            .iter()
+            .map(|row| row.title().to_string())
+            .collect();
+        return (page.total, titles, page.next_cursor);
+    };
+    let (total, titles, cursor) = page(&image_table, None);
+    assert_eq!((total, titles), (3, vec!["1".to_string(), "2".to_string()]));
+    let cursor = Position::from_cursor(&cursor.expect("next cursor")).expect("parsing cursor");
+    let index = image_table
+        .rows()
+        .iter()
+        .position(|row| row.title() == "2")
+        .unwrap();
+    image_table.remove_row(index);
+    // Removing a row moves another one, which must still be indexed.
+    for row in image_table.rows() {
+        let by_path = image_table
+            .get_by_original_path(&row.original_path)
+            .unwrap();
+        assert_eq!(by_path.md5(), row.md5());
+        assert_eq!(
+            image_table.get_by_hash(row.md5()).unwrap().original_path,
+            row.original_path
+        );
+    }
+    assert!(image_table
+        .get_by_original_path(&paths(&["2.jpg"]))
+        .is_none());
+    let (total, titles, cursor) = page(&image_table, Some(&cursor));
+    assert_eq!((total, titles, cursor), (2, vec!["10".to_string()], None));
+}
+
+/// `test_data/baseline_image_table.bincode` is a catalog that the first version of SPG wrote, when
+/// it synced `/tmp/spg-legacy-catalog/a`, which held `1.jpg`, `2.jpg` and `copy.jpg`, a copy of
+/// `1.jpg`.
+#[test]
+fn baseline_catalog() {
+    use super::image_table::ImageTable;
+    use super::sort::{Direction, SortKey};
+    use super::stacks;
+
+    let fixture = Fixture::new();
+    let p = fixture.path();
+    let catalog_path = format!("{}/.spg/image_table.bincode", p);
+    fs::copy("./test_data/baseline_image_table.bincode", &catalog_path).unwrap();
+
+    assert_eq!(
+        fixture.spg(vec!["list", "a"]).read().unwrap(),
+        "/tmp/spg-legacy-catalog/a/1.jpg\n\
+         /tmp/spg-legacy-catalog/a/2.jpg\n\
+         /tmp/spg-legacy-catalog/a/copy.jpg"
+    );
+    let image_table = ImageTable::open(&catalog_path);
+    assert_eq!(image_table.rows().len(), 3);
+    let row = image_table
+        .get_by_original_path("/tmp/spg-legacy-catalog/a/copy.jpg")
+        .unwrap();
+    assert_eq!(row.gallery(), "a");
+    assert_eq!(row.title(), "copy");
+    assert_eq!(
+        row.thumbnail_path(),
+        format!("{:x}-thumbnail.jpg", row.md5())
+    );
+
+    // The upgrade is saved in the current format.
+    image_table.save(&catalog_path);
+    assert!(fs::read(&catalog_path).unwrap().starts_with(b"spgtable"));
+    assert_eq!(ImageTable::open(&catalog_path).rows().len(), 3);
+
+    // The first format did not record dimensions or perceptual hashes, so we cannot tell which
+    // photos look alike until sync reads them.
+    assert_eq!(fixture.spg(vec!["dupes"]).read().unwrap(), "");
+    let stacks = stacks::stacks(&image_table, "a", SortKey::Name, Direction::Asc);
+    assert_eq!(stacks.len(), 3);
+    assert!(stacks.iter().all(|stack| stack.members.is_empty()));
+    let originals = "/tmp/spg-legacy-catalog/a";
+    let _ = fs::remove_dir_all(originals);
+    fs::create_dir_all(originals).unwrap();
+    for (src, dst) in &[("1", "1"), ("1", "copy"), ("2", "2")] {
+        fs::copy(
+            format!("./test_data/{}.jpg", src),
+            format!("{}/{}.jpg", originals, dst),
+        )
+        .unwrap();
+    }
+    fixture
+        .spg(vec!["sync", originals])
+        .run()
+        .expect("syncing originals");
+    fs::remove_dir_all(originals).unwrap();
+    let dupes = fixture.spg(vec!["dupes"]).read().unwrap();
+    assert_eq!(dupes.lines().count(), 2, "{}", dupes);
+    assert!(dupes.contains("(0x0)") == false, "{}", dupes);
+    let image_table = ImageTable::open(&catalog_path);
+    assert!(image_table.rows().iter().all(|row| row.width() > 0));
+
+    // A catalog that we cannot read is an error, rather than a crash.
+    let mut newer = b"spgtable".to_vec();
+    newer.extend(&99u32.to_le_bytes());
+    for bytes in [newer, b"spgtable".to_vec(), vec![1]].iter() {
+        fs::write(&catalog_path, bytes).unwrap();
+        let output = fixture
+            .spg(vec!["list", "a"])
+            .stderr_capture()
+            .unchecked()
+            .run()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(output.status.code(), Some(1), "{}", stderr);
+        assert!(stderr.contains("Could not read"), "{}", stderr);
+    }
+}
+
+#[test]
+fn reload_catalog() {
+    let fixture = Fixture::new();
+    let p = fixture.path();
+    fs::create_dir(format!("{}/a", p)).unwrap();
+    fs::create_dir(format!("{}/b", p)).unwrap();
+    fs::copy("./test_data/1.jpg", format!("{}/a/1.jpg", p)).unwrap();
+    fs::copy("./test_data/2.jpg", format!("{}/b/2.jpg", p)).unwrap();
+
+    fixture.spg(vec!["sync", "a"]).run().expect("sync a/");
+
+    let server = fixture.serve(vec![]);
+    let galleries = server.get("/api/list_galleries", vec![]);
+    assert!(galleries.contains("\"a\""), "{}", galleries);
+    let events_path = format!("{}/events.txt", p);
+    let events = cmd!("curl", "--silent", "--no-buffer", server.url("/api/events"))
+        .stdout_path(&events_path)
+        .unchecked()
+        .start()
+        .expect("listening for events");
+    // Gives curl time to connect.
+    std::thread::sleep(std::time::Duration::from_millis(500));
+
+    fixture.spg(vec!["sync", "b"]).run().expect("sync b/");
+    assert!(
+        wait_until(|| server.get("/api/list_galleries", vec![]).contains("\"b\"")),
+        "server did not reload the catalog"
+    );
+    assert!(server.handle.try_wait().unwrap().is_none(), "server exited");
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    events.kill().unwrap();
+    drop(server);
+
+    let events = fs::read_to_string(&events_path).unwrap();
+    assert!(events.contains(r#""type":"added""#), "{}", events);
+    assert!(
+        events.contains(r#"{"type":"gallery_changed","name":"b"}"#),
+        "{}",
+        events
+    );
+}
+
+#[test]
+fn timeline() {
+    let fixture = Fixture::new();
+    let p = fixture.path();
+    fs::create_dir(format!("{}/a", p)).unwrap();
+    // The test photos do not record when they were taken, so the timeline uses their modified
+    // times.
+    for (name, time) in &[
+        ("1", "2019-06-15 12:00:00 UTC"),
+        ("2", "2020-06-15 12:00:00 UTC"),
+        ("3", "2021-01-02 12:00:00 UTC"),
+        ("4", "2021-06-15 12:00:00 UTC"),
+    ] {
+        let path = format!("{}/a/{}.jpg", p, name);
+        fs::copy(format!("./test_data/{}.jpg", name), &path).unwrap();
+        cmd!("touch", "-d", time, path).run().unwrap();
+    }
+
+    fixture.spg(vec!["sync", "a"]).run().expect("sync a/");
+
+    let server = fixture.serve(vec![]);
+    let get = |path: &str| json(&server.get(path, vec![]));
+    let titles = |photos: &serde_json::Value| {
+        return photos
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|photo| {
+                let path = photo["original_path"].as_str().unwrap();
+                return path.rsplit('/').next().unwrap().to_string();
+            })
+            .collect::<Vec<_>>();
+    };
+
+    // Newest first, two at a time.
+    let mut timeline = vec![];
+    let mut path = "/api/timeline?limit=2".to_string();
+    loop {
+        let page = get(&path).expect("getting a page of the timeline");
+        timeline.extend(titles(&page["photos"]));
+        match page["next_cursor"].as_str() {
+            None => break,
+            Some(cursor) => path = format!("/api/timeline?limit=2&cursor={}", cursor),
+        }
+    }
+    assert_eq!(timeline, vec!["4.jpg", "3.jpg", "2.jpg", "1.jpg"]);
+    let page = get("/api/timeline?limit=1").unwrap();
+    assert_eq!(page["photos"][0]["date"], "2021-06-15");
+    assert!(get("/api/timeline?cursor=bogus").is_none());
+
+    let groups = get("/api/timeline/groups").unwrap();
+    assert_eq!(
+        groups,
+        serde_json::json!([
+            {"year": 2021, "count": 2, "months": [
+                {"month": 6, "count": 1, "days": [{"day": 15, "count": 1}]},
+                {"month": 1, "count": 1, "days": [{"day": 2, "count": 1}]},
+            ]},
+            {"year": 2020, "count": 1, "months": [
+                {"month": 6, "count": 1, "days": [{"day": 15, "count": 1}]},
+            ]},
+            {"year": 2019, "count": 1, "months": [
+                {"month": 6, "count": 1, "days": [{"day": 15, "count": 1}]},
+            ]},
+        ])
+    );
+
+    // Only previous years, most recent first.
+    let years = get("/api/timeline/on_this_day?date=2021-06-15").unwrap();
+    let years = years.as_array().unwrap();
+    assert_eq!(years.len(), 2);
+    assert_eq!(years[0]["year"], 2020);
+    assert_eq!(titles(&years[0]["photos"]), vec!["2.jpg"]);
+    assert_eq!(years[1]["year"], 2019);
+    assert_eq!(titles(&years[1]["photos"]), vec!["1.jpg"]);
+    assert_eq!(
+        get("/api/timeline/on_this_day?date=2022-01-02").unwrap()[0]["year"],
+        2021
+    );
+}
+
+/// A walk through the pages of a gallery sees every photo once, even when photos are added
+/// while it is under way.
+#[test]
+fn gallery_pages() {
+    let fixture = Fixture::new();
+    let p = fixture.path();
+    fs::create_dir(format!("{}/a", p)).unwrap();
+    for name in &["1", "2", "3", "4"] {
+        fs::copy(
+            format!("./test_data/{}.jpg", name),
+            format!("{}/a/{}.jpg", p, name),
+        )
+        .unwrap();
+    }
+
+    fixture.spg(vec!["sync", "a"]).run().expect("sync a/");
+
+    let server = fixture.serve(vec![]);
+    // Returns a page of gallery a, or None if the request fails.
+    let page = |cursor: Option<&str>| {
+        let mut path = "/api/gallery_contents?limit=2".to_string();
+        if let Some(cursor) = cursor {
+            path = format!("{}&cursor={}", path, cursor);
+        }
+        return json(&server.post(&path, r#""a""#));
+    };
+    let wait_for_total = |total: u64| {
+        return wait_until(|| page(None).is_some_and(|page| page["total"] == total));
+    };
+    assert!(wait_for_total(4), "server did not start");
+
+    let mut seen = vec![];
+    let mut cursor: Option<String> = None;
+    loop {
+        let current = page(cursor.as_deref()).expect("getting a page");
+        for photo in current["photos"].as_array().unwrap() {
+            let path = photo["original_path"].as_str().unwrap();
+            seen.push(path.rsplit('/').next().unwrap().to_string());
+        }
+        if seen.len() == 2 {
+            // One photo sorts before the cursor, and the other after it.
+            fs::copy("./test_data/1.jpg", format!("{}/a/0.jpg", p)).unwrap();
+            fs::copy("./test_data/2.jpg", format!("{}/a/5.jpg", p)).unwrap();
+            fixture.spg(vec!["sync", "a"]).run().expect("sync a/");
+            assert!(wait_for_total(6), "server did not reload the catalog");
+        }
+        match current["next_cursor"].as_str() {
+            None => break,
+            Some(next) => cursor = Some(next.to_string()),
+        }
+    }
+    assert_eq!(seen, vec!["1.jpg", "2.jpg", "3.jpg", "4.jpg", "5.jpg"]);
+    assert!(page(Some("bogus")).is_none());
+}
+
+/// The command line and the server take turns changing the catalog, so neither loses the
+/// changes of the other.
+#[test]
+fn catalog_lock() {
+    let fixture = Fixture::new();
+    let p = fixture.path();
+    fs::create_dir(format!("{}/a", p)).unwrap();
+    fs::copy("./test_data/1.jpg", format!("{}/a/1.jpg", p)).unwrap();
+    fs::copy("./test_data/2.jpg", format!("{}/a/2.jpg", p)).unwrap();
+
+    fixture.spg(vec!["sync", "a"]).run().expect("sync a/");
+    let root = fixture.root();
+    let hash_of = |name: &str| {
+        let path = root.join("a").join(name);
+        let image_table = fixture.image_table();
+        return image_table
+            .get_by_original_path(&path.to_string_lossy())
+            .unwrap()
+            .md5();
+    };
+    let (hash_1, hash_2) = (hash_of("1.jpg"), hash_of("2.jpg"));
+
+    let server = fixture.serve(vec![]);
+    let annotate = || {
+        return server.request(
+            vec![
+                "--header",
+                "Content-Type: application/json",
+                "--data",
+                r#"{"add_tags":["web"]}"#,
+            ],
+            &format!("/api/annotations/{:x}", hash_2),
+        );
+    };
+
+    // While something else holds the lock, both wait for it.
+    let lock = fs::OpenOptions::new()
+        .write(true)
+        .open(format!("{}/.spg/image_table.lock", p))
+        .unwrap();
+    lock.lock().unwrap();
+    let cli = fixture
+        .spg(vec!["tag", "a/1.jpg", "cli"])
+        .stderr_null()
+        .start()
+        .unwrap();
+    let web = annotate().start().unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(1000));
+    assert!(cli.try_wait().unwrap().is_none(), "tag did not wait");
+    assert!(web.try_wait().unwrap().is_none(), "server did not wait");
+    lock.unlock().unwrap();
+    cli.wait().expect("tagging 1.jpg");
+    let web = web.wait().unwrap();
+    let web = String::from_utf8_lossy(&web.stdout);
+    assert!(web.starts_with("HTTP/1.1 200"), "{}", web);
+    drop(server);
+
+    let image_table = fixture.image_table();
+    assert_eq!(image_table.annotations(hash_1).tags, vec!["cli"]);
+    assert_eq!(image_table.annotations(hash_2).tags, vec!["web"]);
+}
+
+#[test]
+fn watch() {
+    let fixture = Fixture::new();
+    let p = fixture.path();
+    fs::create_dir(format!("{}/a", p)).unwrap();
+    fs::copy("./test_data/1.jpg", format!("{}/a/1.jpg", p)).unwrap();
+    fs::copy("./test_data/2.jpg", format!("{}/a/2.jpg", p)).unwrap();
+
+    let watcher = fixture
+        .spg(vec!["watch", "a"])
+        .stdout_null()
+        .stderr_null()
+        .start()
+        .expect("starting watcher");
+
+    let root = fixture.root();
+    let paths = |names: &[&str]| {
+        return names
+            .iter()
+            .map(|name| root.join(name).to_string_lossy().to_string())
+            .collect::<Vec<_>>()
+            .join("\n");
+    };
+    let wait_for = |gallery: &str, expected: &str| {
+        return wait_until(|| fixture.spg(vec!["list", gallery]).read().unwrap() == expected);
+    };
+    assert!(
+        wait_for("a", &paths(&["a/1.jpg", "a/2.jpg"])),
+        "initial sync"
+    );
+
+    fs::copy("./test_data/3.jpg", format!("{}/a/3.jpg", p)).unwrap();
+    assert!(
+        wait_for("a", &paths(&["a/1.jpg", "a/2.jpg", "a/3.jpg"])),
+        "new photo"
+    );
+
+    // A sidecar that Lightroom writes for a photo changes its description.
+    fs::write(
+        format!("{}/a/3.xmp", p),
+        r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about="" xmlns:dc="http://purl.org/dc/elements/1.1/">
+   <dc:title><rdf:Alt><rdf:li xml:lang="x-default">Sunset</rdf:li></rdf:Alt></dc:title>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>"#,
+    )
+    .unwrap();
+    assert!(
+        wait_until(|| fixture
+            .spg(vec!["stat", "a/3.jpg"])
+            .read()
+            .unwrap()
+            .contains("Title: Sunset")),
+        "sidecar"
+    );
+
+    // An editor that saves atomically deletes a photo and writes it again, which we may see in a
+    // single batch of events. The watcher waits for the catalog lock with the first photo below
+    // while we do so.
+    let lock = fs::OpenOptions::new()
+        .write(true)
+        .open(format!("{}/.spg/image_table.lock", p))
+        .unwrap();
+    lock.lock().unwrap();
+    fs::copy("./test_data/4.jpg", format!("{}/a/4.jpg", p)).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    fs::remove_file(format!("{}/a/3.jpg", p)).unwrap();
+    fs::copy("./test_data/1.jpg", format!("{}/a/3.jpg", p)).unwrap();
+    lock.unlock().unwrap();
+    let md5 = |name: &str| {
+        let image_table = fixture.image_table();
+        return image_table
+            .get_by_original_path(&paths(&[name]))
+            .map(|row| row.md5());
+    };
+    let rewritten = md5("a/1.jpg");
+    assert!(
+        wait_until(|| md5("a/4.jpg").is_some() && md5("a/3.jpg") == rewritten),
+        "rewritten photo"
+    );
+
+    fs::create_dir(format!("{}/a/c", p)).unwrap();
+    fs::rename(format!("{}/a/1.jpg", p), format!("{}/a/c/1.jpg", p)).unwrap();
+    fs::remove_file(format!("{}/a/2.jpg", p)).unwrap();
+    assert!(
+        wait_for("a", &paths(&["a/3.jpg", "a/4.jpg"])),
+        "moved and deleted photos"
+    );
+    assert!(wait_for("c", &paths(&["a/c/1.jpg"])), "moved photo");
+
+    fs::remove_dir_all(format!("{}/a/c", p)).unwrap();
+    assert!(wait_for("c", ""), "deleted directory");
+    watcher.kill().unwrap();
+}
+
+#[test]
+fn login() {
+    let fixture = Fixture::new();
+    let p = fixture.path();
+    fs::create_dir(format!("{}/a", p)).unwrap();
+    fs::copy("./test_data/1.jpg", format!("{}/a/1.jpg", p)).unwrap();
+
+    fixture.spg(vec!["sync", "a"]).run().expect("sync a/");
+    fixture
+        .spg(vec!["user", "add", "alice"])
+        .stdin_bytes("hunter2\n")
+        .run()
+        .expect("adding user");
+
+    let server = fixture.serve(vec!["--trust-proxy"]);
+    // Returns the status code of a request.
+    let status = |path: &str, cookie: &str| {
+        let response = server.get(path, vec![&format!("Cookie: {}", cookie)]);
+        return response.split(' ').nth(1).unwrap_or_default().to_string();
+    };
+    let thumbnail = fixture.image_table().rows()[0].thumbnail_path().to_string();
+    assert_eq!(status("/api/list_galleries", ""), "401");
+    assert_eq!(status(&format!("/photos/{}", thumbnail), ""), "401");
+    assert_eq!(status("/index.html", ""), "200");
+
+    let response = server.post("/api/login", r#"{"user":"alice","password":"wrong"}"#);
+    assert!(response.starts_with("HTTP/1.1 401"), "{}", response);
+    // Someone who keeps guessing is turned away, whether or not the user exists.
+    for _ in 0..5 {
+        let response = server.post("/api/login", r#"{"user":"bob","password":"guess"}"#);
+        assert!(response.starts_with("HTTP/1.1 401"), "{}", response);
+    }
+    let response = server.post("/api/login", r#"{"user":"bob","password":"guess"}"#);
+    assert!(response.starts_with("HTTP/1.1 429"), "{}", response);
+    // Guessing from one address does not lock the user out elsewhere. The proxy appends the address
+    // of the client to X-Forwarded-For, after any that the client sent.
+    let from = |forwarded_for: &str| {
+        let args = vec![
+            "--header",
+            "Content-Type: application/json",
+            "--header",
+            forwarded_for,
+            "--data",
+            r#"{"user":"bob","password":"guess"}"#,
+        ];
+        return server.request(args, "/api/login").read().unwrap();
+    };
+    let response = from("X-Forwarded-For: 10.0.0.2");
+    assert!(response.starts_with("HTTP/1.1 401"), "{}", response);
+    let response = from("X-Forwarded-For: 10.0.0.2, 127.0.0.1");
+    assert!(response.starts_with("HTTP/1.1 429"), "{}", response);
+    let response = server.post("/api/login", r#"{"user":"alice","password":"hunter2"}"#);
+    let session = cookie(&response).expect("no session cookie");
+    assert!(response.contains("HttpOnly"), "{}", response);
+    assert_eq!(status("/api/list_galleries", &session), "200");
+    assert_eq!(status(&format!("/photos/{}", thumbnail), &session), "200");
+
+    let sessions = fixture
+        .spg(vec!["session", "list"])
+        .read()
+        .expect("listing sessions");
+    assert!(sessions.contains("alice"), "{}", sessions);
+    // The command line takes turns with the server, which holds the same lock to log users in.
+    let lock = fs::OpenOptions::new()
+        .write(true)
+        .open(format!("{}/.spg/sessions.json.lock", p))
+        .unwrap();
+    lock.lock().unwrap();
+    let revoke = fixture
+        .spg(vec!["session", "revoke", "--user", "alice"])
+        .start()
+        .unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    assert!(
+        revoke.try_wait().unwrap().is_none(),
+        "did not wait for lock"
+    );
+    lock.unlock().unwrap();
+    revoke.wait().expect("revoking sessions");
+    assert!(
+        wait_until(|| status("/api/list_galleries", &session) == "401"),
+        "session was not revoked"
+    );
+}
+
+#[test]
+fn share() {
+    let fixture = Fixture::new();
+    let p = fixture.path();
+    fs::create_dir(format!("{}/a", p)).unwrap();
+    fs::create_dir(format!("{}/b", p)).unwrap();
+    fs::copy("./test_data/1.jpg", format!("{}/a/1.jpg", p)).unwrap();
+    fs::copy("./test_data/2.jpg", format!("{}/b/2.jpg", p)).unwrap();
+
+    fixture.spg(vec!["sync", "a"]).run().expect("sync a/");
+    fixture.spg(vec!["sync", "b"]).run().expect("sync b/");
+    fixture
+        .spg(vec!["user", "add", "alice", "--password", "hunter2"])
+        .run()
+        .expect("adding user");
+    let share = fixture
+        .spg(vec!["share", "a", "--expires", "1d"])
+        .read()
+        .expect("sharing a/");
+    let (id, path) = share.split_once('\t').expect("share ID and link");
+    for expires in &["1é", "é", "0d", "9223372036854775807w", "101000d"] {
+        let output = fixture
+            .spg(vec!["share", "a", "--expires", expires])
+            .stderr_capture()
+            .unchecked()
+            .run()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(output.status.code(), Some(1), "{}", stderr);
+        assert!(stderr.contains("invalid duration"), "{}", stderr);
+    }
+
+    let server = fixture.serve(vec![]);
+    let get = |path: &str, cookie: &str| {
+        return server.get(path, vec![&format!("Cookie: {}", cookie)]);
+    };
+    assert!(get("/api/list_galleries", "").starts_with("HTTP/1.1 401"));
+
+    let response = get(path, "");
+    assert!(response.starts_with("HTTP/1.1 307"), "{}", response);
+    let share_cookie = cookie(&response).expect("no share cookie");
+    assert!(get("/share/forged.token", "").starts_with("HTTP/1.1 403"));
+
+    let galleries = get("/api/list_galleries", &share_cookie);
+    assert!(galleries.starts_with("HTTP/1.1 200"), "{}", galleries);
+    assert!(galleries.contains(r#""name":"a""#), "{}", galleries);
+    assert!(
+        galleries.contains(r#""name":"b""#) == false,
+        "{}",
+        galleries
+    );
+
+    let (shared, private) = (fixture.photo_urls("a"), fixture.photo_urls("b"));
+    assert!(get(&shared.0, &share_cookie).starts_with("HTTP/1.1 200"));
+    assert!(get(&private.0, &share_cookie).starts_with("HTTP/1.1 403"));
+    // Originals are not shared without --originals.
+    assert!(get(&shared.1, &share_cookie).starts_with("HTTP/1.1 403"));
+    assert!(get("/api/search?q=1", &share_cookie).starts_with("HTTP/1.1 403"));
+
+    let shares = fixture
+        .spg(vec!["share", "--list"])
+        .read()
+        .expect("listing shares");
+    assert!(shares.contains("gallery a"), "{}", shares);
+    fixture
+        .spg(vec!["share", "--revoke", id])
+        .run()
+        .expect("revoking share");
+    assert!(
+        wait_until(|| get("/api/list_galleries", &share_cookie).starts_with("HTTP/1.1 401")),
+        "share was not revoked"
+    );
+}
+
+#[test]
+fn acls() {
+    let fixture = Fixture::new();
+    let p = fixture.path();
+    fs::create_dir(format!("{}/a", p)).unwrap();
+    fs::create_dir(format!("{}/b", p)).unwrap();
+    fs::copy("./test_data/1.jpg", format!("{}/a/1.jpg", p)).unwrap();
+    fs::copy("./test_data/2.jpg", format!("{}/b/2.jpg", p)).unwrap();
+
+    fixture.spg(vec!["sync", "a"]).run().expect("sync a/");
+    fixture.spg(vec!["sync", "b"]).run().expect("sync b/");
+    for user in ["alice", "bob"].iter() {
+        fixture
+            .spg(vec!["user", "add", user, "--password", "hunter2"])
+            .run()
+            .expect("adding user");
+    }
+    fixture
+        .spg(vec!["group", "add", "family", "alice", "bob"])
+        .run()
+        .expect("adding group");
+    // Only alice may see everything, but the family may see a/.
+    fixture
+        .spg(vec!["acl", "add", ".", "alice"])
+        .run()
+        .expect("adding ACL");
+    fixture
+        .spg(vec!["acl", "add", "a", "@family"])
+        .run()
+        .expect("adding ACL");
+    let acls = fixture
+        .spg(vec!["acl", "list"])
+        .read()
+        .expect("listing ACLs");
+    assert!(acls.contains("@family"), "{}", acls);
+
+    let server = fixture.serve(vec![]);
+    let get = |path: &str, cookie: &str| {
+        return server.get(path, vec![&format!("Cookie: {}", cookie)]);
+    };
+    let alice = server.login("alice", "hunter2");
+    let bob = server.login("bob", "hunter2");
+    let (a, b) = (fixture.photo_urls("a"), fixture.photo_urls("b"));
+
+    let galleries = get("/api/list_galleries", &alice);
+    assert!(galleries.contains(r#""name":"b""#), "{}", galleries);
+    assert!(get("/api/search?q=1", &alice).starts_with("HTTP/1.1 200"));
+    assert!(get(&b.0, &alice).starts_with("HTTP/1.1 200"));
+
+    let galleries = get("/api/list_galleries", &bob);
+    assert!(galleries.contains(r#""name":"a""#), "{}", galleries);
+    assert!(
+        galleries.contains(r#""name":"b""#) == false,
+        "{}",
+        galleries
+    );
+    assert!(get(&a.0, &bob).starts_with("HTTP/1.1 200"));
+    assert!(get(&a.1, &bob).starts_with("HTTP/1.1 200"));
+    assert!(get(&b.0, &bob).starts_with("HTTP/1.1 403"));
+    assert!(get(&b.1, &bob).starts_with("HTTP/1.1 403"));
+    assert!(get("/api/search?q=1", &bob).starts_with("HTTP/1.1 403"));
+}
+
+#[test]
+fn tls() {
+    let fixture = Fixture::new();
+    let p = fixture.path();
+    fs::create_dir(format!("{}/a", p)).unwrap();
+    fs::copy("./test_data/1.jpg", format!("{}/a/1.jpg", p)).unwrap();
+
+    fixture.spg(vec!["sync", "a"]).run().expect("sync a/");
+
+    // Writes a new self-signed certificate and key, and keeps a copy of the certificate for
+    // curl to trust.
+    let generate = |trusted: &str| {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])
+            .expect("generating certificate");
+        let cert_pem = cert.serialize_pem().unwrap();
+        fs::write(format!("{}/{}", p, trusted), &cert_pem).unwrap();
+        fs::write(format!("{}/key.pem", p), cert.serialize_private_key_pem()).unwrap();
+        fs::write(format!("{}/cert.pem", p), &cert_pem).unwrap();
+    };
+    generate("first.pem");
+
+    let port = free_port().to_string();
+    let http_port = free_port().to_string();
+    let server = Server::start(
+        &fixture,
+        vec![
+            "--port",
+            &port,
+            "--tls-cert",
+            "cert.pem",
+            "--tls-key",
+            "key.pem",
+            "--redirect-http",
+            &http_port,
+        ],
+        format!("https://localhost:{}", port),
+    );
+    let galleries = |trusted: &str| {
+        let cacert = format!("{}/{}", p, trusted);
+        return server
+            .request(vec!["--cacert", &cacert], "/api/list_galleries")
+            .read()
+            .unwrap();
+    };
+    assert!(
+        wait_until(|| galleries("first.pem").contains("\"a\"")),
+        "server did not start"
+    );
+
+    let redirect = cmd!(
+        "curl",
+        "--silent",
+        "--include",
+        format!("http://localhost:{}/api/list_galleries?x=1", http_port)
+    )
+    .read()
+    .unwrap();
+    assert!(
+        redirect.contains(&format!(
+            "location: https://localhost:{}/api/list_galleries?x=1",
+            port
+        )),
+        "{}",
+        redirect
+    );
+
+    generate("second.pem");
+    assert!(
+        wait_until(|| galleries("second.pem").contains("\"a\"")),
+        "server did not reload the certificate"
+    );
+    assert_eq!(galleries("first.pem"), "");
+}
+
+#[test]
+fn bind() {
+    let fixture = Fixture::new();
+    let p = fixture.path();
+    fs::create_dir(format!("{}/a", p)).unwrap();
+    fs::copy("./test_data/1.jpg", format!("{}/a/1.jpg", p)).unwrap();
+
+    fixture.spg(vec!["sync", "a"]).run().expect("sync a/");
+
+    let error = fixture
+        .spg(vec!["serve"])
+        .stderr_capture()
+        .unchecked()
+        .run()
+        .unwrap();
+    assert!(
+        error.status.success() == false,
+        "serve started without an address"
+    );
+
+    // A socket left behind by a previous server does not stop us from binding.
+    std::os::unix::net::UnixListener::bind(format!("{}/spg.sock", p)).unwrap();
+    let tcp = format!("127.0.0.1:{}", free_port());
+    let server = Server::start(
+        &fixture,
+        vec!["--bind", &tcp, "--bind", "unix:spg.sock"],
+        format!("http://{}", tcp),
+    );
+    assert!(
+        wait_until(|| server.get("/api/list_galleries", vec![]).contains("\"a\"")),
+        "server did not start"
+    );
+    let socket = format!("{}/spg.sock", p);
+    let unix = server
+        .request(vec!["--unix-socket", &socket], "/api/list_galleries")
+        .read()
+        .unwrap();
+    assert!(unix.contains("\"a\""), "{}", unix);
+
+    // Nor does it take the socket of a server that is still running.
+    let error = fixture
+        .spg(vec!["serve", "--bind", "unix:spg.sock"])
+        .stderr_capture()
+        .unchecked()
+        .run()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&error.stderr);
+    assert!(stderr.contains("another server is listening"), "{}", stderr);
+    let unix = server
+        .request(vec!["--unix-socket", &socket], "/api/list_galleries")
+        .read()
+        .unwrap();
+    assert!(unix.contains("\"a\""), "{}", unix);
+}
+
+#[test]
+fn base_path() {
+    let fixture = Fixture::new();
+    let p = fixture.path();
+    fs::create_dir(format!("{}/a", p)).unwrap();
+    fs::copy("./test_data/1.jpg", format!("{}/a/1.jpg", p)).unwrap();
+
+    fixture.spg(vec!["sync", "a"]).run().expect("sync a/");
+
+    let create_share = |server: &Server, headers: Vec<&str>| {
+        let mut args = vec!["--header", "Content-Type: application/json"];
+        for header in headers {
+            args.extend(vec!["--header", header]);
+        }
+        args.extend(vec!["--data", r#"{"gallery": "a", "expires": "1d"}"#]);
+        return server
+            .request(args, "/photos/api/create_share")
+            .read()
+            .unwrap();
+    };
+    let forwarded = vec![
+        "X-Forwarded-Proto: https",
+        "X-Forwarded-Host: home.example, proxy.internal",
+    ];
+
+    // Without --trust-proxy, anyone could forge the headers, so we ignore them.
+    let server = fixture.serve(vec!["--base-path", "/photos/"]);
+    let created = json(&create_share(&server, forwarded.clone())).expect("creating share");
+    let url = created["url"].as_str().unwrap();
+    assert!(url.starts_with(&server.url("/photos/share/")), "{}", url);
+    drop(server);
+
+    let server = fixture.serve(vec!["--base-path", "/photos/", "--trust-proxy"]);
+    let galleries = server.get("/photos/api/list_galleries", vec![]);
+    assert!(galleries.contains(r#""name":"a""#), "{}", galleries);
+    assert!(server
+        .get("/api/list_galleries", vec![])
+        .starts_with("HTTP/1.1 404"));
+    let index = server.get("/photos/", vec![]);
+    assert!(index.contains("index.bundle.js"), "{}", index);
+    let redirect = server.get("/photos", vec![]);
+    assert!(redirect.starts_with("HTTP/1.1 301"), "{}", redirect);
+    assert!(redirect.contains("location: photos/\r\n"), "{}", redirect);
+
+    let created = json(&create_share(&server, forwarded)).expect("creating share");
+    let path = created["path"].as_str().unwrap();
+    assert!(path.starts_with("share/"), "{}", path);
+    assert_eq!(
+        created["url"].as_str().unwrap(),
+        format!("https://home.example/photos/{}", path)
+    );
+
+    let opened = server.get(&format!("/photos/{}", path), vec![]);
+    assert!(opened.starts_with("HTTP/1.1 307"), "{}", opened);
+    assert!(opened.contains("location: ../\r\n"), "{}", opened);
+    assert!(opened.contains("; Path=/photos/;"), "{}", opened);
+
+    // A prefix that would smuggle attributes into the cookie is refused.
+    let smuggled = create_share(&server, vec!["X-Forwarded-Prefix: /x; Domain=evil.example"]);
+    assert!(smuggled.starts_with("HTTP/1.1 400"), "{}", smuggled);
+}
+
+#[test]
+fn caching() {
+    let fixture = Fixture::new();
+    let p = fixture.path();
+    fs::create_dir(format!("{}/a", p)).unwrap();
+    fs::create_dir(format!("{}/b", p)).unwrap();
+    fs::copy("./test_data/1.jpg", format!("{}/a/1.jpg", p)).unwrap();
+    fs::copy("./test_data/2.jpg", format!("{}/b/2.jpg", p)).unwrap();
+
+    fixture.spg(vec!["sync", "a"]).run().expect("sync a/");
+    fixture.spg(vec!["album", "create", "trip"]).run().unwrap();
+    let photo_path = format!("{}/a/1.jpg", p);
+    fixture
+        .spg(vec!["album", "add", "trip", &photo_path])
+        .run()
+        .unwrap();
+
+    let server = fixture.serve(vec![]);
+    // Returns the status line and headers of a request.
+    let head = |path: &str, if_none_match: &str| {
+        let response = server.get(path, vec![&format!("If-None-Match: {}", if_none_match)]);
+        return response.split("\r\n\r\n").next().unwrap().to_string();
+    };
+    let etag = |response: &str| header(response, "etag").expect("no ETag").to_string();
+
+    let listing = head("/api/list_galleries", "");
+    assert!(
+        listing.contains("cache-control: private, no-cache"),
+        "{}",
+        listing
+    );
+    let listing_etag = etag(&listing);
+    let cached = head("/api/list_galleries", &listing_etag);
+    assert!(cached.starts_with("HTTP/1.1 304"), "{}", cached);
+    assert_eq!(etag(&cached), listing_etag);
+
+    // Listings that are posted depend on their query string and body as well.
+    let post = |path: &str, json: &str, if_none_match: &str| {
+        let if_none_match = format!("If-None-Match: {}", if_none_match);
+        let args = vec![
+            "--header",
+            "Content-Type: application/json",
+            "--header",
+            &if_none_match,
+            "--data",
+            json,
+        ];
+        let output = server.request(args, path).run().unwrap();
+        let response = String::from_utf8_lossy(&output.stdout).to_string();
+        return response.split("\r\n\r\n").next().unwrap().to_string();
+    };
+    let contents = post("/api/gallery_contents", r#""a""#, "");
+    assert!(contents.starts_with("HTTP/1.1 200"), "{}", contents);
+    let contents_etag = etag(&contents);
+    let cached = post("/api/gallery_contents", r#""a""#, &contents_etag);
+    assert!(cached.starts_with("HTTP/1.1 304"), "{}", cached);
+    assert_eq!(etag(&cached), contents_etag);
+    let page = post("/api/gallery_contents?limit=1", r#""a""#, &contents_etag);
+    assert!(page.starts_with("HTTP/1.1 200"), "{}", page);
+    let other = post("/api/gallery_contents", r#""b""#, &contents_etag);
+    assert!(other.starts_with("HTTP/1.1 304") == false, "{}", other);
+    let album = post("/api/album_contents", r#""trip""#, "");
+    assert!(album.starts_with("HTTP/1.1 200"), "{}", album);
+    let cached = post("/api/album_contents", r#""trip""#, &etag(&album));
+    assert!(cached.starts_with("HTTP/1.1 304"), "{}", cached);
+
+    let thumbnail = {
+        let image_table = fixture.image_table();
+        format!("/photos/{}", image_table.rows()[0].thumbnail_path())
+    };
+    let photo = head(&thumbnail, "");
+    assert!(photo.starts_with("HTTP/1.1 200"), "{}", photo);
+    assert!(photo.contains("immutable"), "{}", photo);
+    let photo_etag = etag(&photo);
+    assert!(head(&thumbnail, &photo_etag).starts_with("HTTP/1.1 304"));
+    assert!(head(&thumbnail, &listing_etag).starts_with("HTTP/1.1 200"));
+
+    fixture.spg(vec!["sync", "b"]).run().expect("sync b/");
+    assert!(
+        wait_until(|| head("/api/list_galleries", &listing_etag).starts_with("HTTP/1.1 200")),
+        "ETag did not change with the catalog"
+    );
+
+    // An edit from the web interface changes the ETag as soon as it is answered.
+    let listing_etag = etag(&head("/api/list_galleries", ""));
+    let md5 = fixture.image_table().rows()[0].md5();
+    let annotated = server.post(
+        &format!("/api/annotations/{:x}", md5),
+        r#"{"add_tags":["web"]}"#,
+    );
+    assert!(annotated.starts_with("HTTP/1.1 200"), "{}", annotated);
+    let listing = head("/api/list_galleries", &listing_etag);
+    assert!(listing.starts_with("HTTP/1.1 200"), "{}", listing);
+
+    // Photos taken on this day change with the date, and not only with the catalog.
+    let today = head("/api/timeline/on_this_day", "");
+    assert_ne!(etag(&today), etag(&listing));
+}
+
+#[test]
+fn download_original() {
+    let fixture = Fixture::new();
+    let p = fixture.path();
+    fs::create_dir(format!("{}/a", p)).unwrap();
+    let path = format!("{}/a/my photo, 1.jpg", p);
+    copy_with_exif("./test_data/1.jpg", &path);
+    let original = fs::read(&path).unwrap();
+
+    fixture.spg(vec!["sync", "a"]).run().expect("sync a/");
+    let md5 = fixture.image_table().rows()[0].md5();
+    // Originals are stripped unless the settings say otherwise.
+    let settings_path = format!("{}/.spg/settings.json", p);
+    let settings = fs::read_to_string(&settings_path).unwrap();
+    assert!(
+        settings.contains(r#""strip_originals": true"#),
+        "{}",
+        settings
+    );
+    fs::write(&settings_path, r#"{"strip_originals": false}"#).unwrap();
+
+    let server = fixture.serve(vec![]);
+    let url = format!("/api/original/{:x}", md5);
+    // Returns the headers and body of a download.
+    let download = |headers: Vec<&str>| {
+        let mut args = vec![];
+        for header in headers {
+            args.push("--header");
+            args.push(header);
+        }
+        let output = server.request(args, &url).run().unwrap();
+        let response = output.stdout;
+        let end = response
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .expect("no headers")
+            + 4;
+        let headers = String::from_utf8_lossy(&response[..end]).to_string();
+        return (headers, response[end..].to_vec());
+    };
+
+    let (headers, body) = download(vec![]);
+    assert_eq!(body, original);
+    assert!(headers.contains("content-type: image/jpeg"), "{}", headers);
+    assert!(headers.contains("accept-ranges: bytes"), "{}", headers);
+    assert!(
+        headers.contains("filename*=UTF-8''my%20photo%2C%201.jpg\r\n"),
+        "{}",
+        headers
+    );
+    assert!(headers.contains(p) == false, "{}", headers);
+    let etag = header(&headers, "etag").expect("no ETag").to_string();
+
+    let (headers, body) = download(vec!["Range: bytes=10-19"]);
+    assert!(headers.starts_with("HTTP/1.1 206"), "{}", headers);
+    assert!(
+        headers.contains(&format!("content-range: bytes 10-19/{}", original.len())),
+        "{}",
+        headers
+    );
+    assert_eq!(body, &original[10..20]);
+    let (_, body) = download(vec!["Range: bytes=-5"]);
+    assert_eq!(body, &original[original.len() - 5..]);
+    let if_range = format!("If-Range: {}", etag);
+    let (headers, _) = download(vec!["Range: bytes=10-19", &if_range]);
+    assert!(headers.starts_with("HTTP/1.1 206"), "{}", headers);
+    let (headers, body) = download(vec!["Range: bytes=10-19", "If-Range: \"stale\""]);
+    assert!(headers.starts_with("HTTP/1.1 200"), "{}", headers);
+    assert_eq!(body, original);
+    let past_end = format!("Range: bytes={}-", original.len());
+    let (headers, _) = download(vec![&past_end]);
+    assert!(headers.starts_with("HTTP/1.1 416"), "{}", headers);
+
+    // A stripped copy is sent from memory, and may also be downloaded in parts.
+    fs::write(
+        &settings_path,
+        r#"{"strip_originals": true, "metadata_policy": "strip_all"}"#,
+    )
+    .unwrap();
+    let mut stripped = vec![];
+    let reloaded = wait_until(|| {
+        let (headers, body) = download(vec![]);
+        stripped = body;
+        return headers.contains(&etag) == false;
+    });
+    assert!(reloaded, "server did not reload the settings");
+    assert!(stripped != original);
+    let (headers, body) = download(vec!["Range: bytes=0-99"]);
+    assert!(headers.contains("content-type: image/jpeg"), "{}", headers);
+    assert_eq!(body, &stripped[..100]);
+}
+
+#[test]
+fn map_route() {
+    let fixture = Fixture::new();
+    let p = fixture.path();
+    fs::create_dir(format!("{}/a", p)).unwrap();
+    copy_with_exif("./test_data/1.jpg", &format!("{}/a/1.jpg", p));
+
+    fixture.spg(vec!["sync", "a"]).run().expect("sync a/");
+
+    // The default metadata policy strips GPS positions, so there is no map, and we say why.
+    let server = fixture.serve(vec![]);
+    let disabled = server.get("/api/map", vec![]);
+    assert!(disabled.starts_with("HTTP/1.1 403"), "{}", disabled);
+    assert!(disabled.contains("keep_all"), "{}", disabled);
+
+    fs::write(
+        format!("{}/.spg/settings.json", p),
+        r#"{"metadata_policy": "keep_all"}"#,
+    )
+    .unwrap();
+    assert!(
+        wait_until(|| json(&server.get("/api/map", vec![]))
+            .is_some_and(|map| map["features"].as_array().is_some_and(|f| f.len() == 1))),
+        "map did not appear"
+    );
+}
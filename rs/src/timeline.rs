@@ -0,0 +1,169 @@
+use super::image_table::{ImageTable, Row, RowView};
+use chrono::{Datelike, NaiveDate};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+#[derive(Serialize)]
+pub struct TimelineEntry<'a> {
+    pub date: NaiveDate,
+    pub timestamp: i64,
+    #[serde(flatten)]
+    pub row: RowView<'a>,
+}
+
+/// A page of the timeline. To get the next page, pass `next_cursor` back as the cursor. The cursor
+/// refers to the last photo on this page, not to an offset, so pages do not shift when photos are
+/// added or removed while someone is scrolling.
+#[derive(Serialize)]
+pub struct TimelinePage<'a> {
+    pub photos: Vec<TimelineEntry<'a>>,
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct DayCount {
+    pub day: u32,
+    pub count: usize,
+}
+
+#[derive(Serialize)]
+pub struct MonthCount {
+    pub month: u32,
+    pub count: usize,
+    pub days: Vec<DayCount>,
+}
+
+#[derive(Serialize)]
+pub struct YearCount {
+    pub year: i32,
+    pub count: usize,
+    pub months: Vec<MonthCount>,
+}
+
+#[derive(Serialize)]
+pub struct YearPhotos<'a> {
+    pub year: i32,
+    pub photos: Vec<TimelineEntry<'a>>,
+}
+
+/// The timeline is ordered newest first. Photos taken at the same second are ordered by hash, so
+/// that the order is total and a cursor always identifies a unique position.
+fn sort_key(row: &Row) -> (i64, u128) {
+    return (row.timestamp(), row.md5());
+}
+
+fn entry(row: &Row) -> TimelineEntry<'_> {
+    return TimelineEntry {
+        date: row.date(),
+        timestamp: row.timestamp(),
+        row: row.view(),
+    };
+}
+
+fn format_cursor(key: (i64, u128)) -> String {
+    return format!("{}_{:x}", key.0, key.1);
+}
+
+fn parse_cursor(cursor: &str) -> Option<(i64, u128)> {
+    let (timestamp, hash) = cursor.split_once('_')?;
+    let timestamp = timestamp.parse().ok()?;
+    let hash = u128::from_str_radix(hash, 16).ok()?;
+    return Some((timestamp, hash));
+}
+
+/// Returns up to `limit` photos that follow `cursor` in the timeline, or the first `limit` photos
+/// if there is no cursor. Returns `None` if the cursor is malformed.
+pub fn page<'a>(
+    image_table: &'a ImageTable,
+    cursor: Option<&str>,
+    limit: usize,
+) -> Option<TimelinePage<'a>> {
+    let after = match cursor {
+        None => None,
+        Some(cursor) => Some(parse_cursor(cursor)?),
+    };
+    let mut rows: Vec<&Row> = image_table
+        .rows()
+        .iter()
+        .filter(|row| after.is_none_or(|after| sort_key(row) < after))
+        .collect();
+    rows.sort_by_key(|row| std::cmp::Reverse(sort_key(row)));
+    let has_more = rows.len() > limit;
+    rows.truncate(limit);
+    let next_cursor = match rows.last() {
+        Some(row) if has_more => Some(format_cursor(sort_key(row))),
+        _ => None,
+    };
+    return Some(TimelinePage {
+        photos: rows.into_iter().map(entry).collect(),
+        next_cursor,
+    });
+}
+
+/// Counts photos by year, month and day, newest first.
+pub fn groups(image_table: &ImageTable) -> Vec<YearCount> {
+    let mut counts: BTreeMap<i32, BTreeMap<u32, BTreeMap<u32, usize>>> = BTreeMap::new();
+    for row in image_table.rows().iter() {
+        let date = row.date();
+        *counts
+            .entry(date.year())
+            .or_default()
+            .entry(date.month())
+            .or_default()
+            .entry(date.day())
+            .or_default() += 1;
+    }
+    return counts
+        .into_iter()
+        .rev()
+        .map(|(year, months)| {
+            let months: Vec<MonthCount> = months
+                .into_iter()
+                .rev()
+                .map(|(month, days)| {
+                    let days: Vec<DayCount> = days
+                        .into_iter()
+                        .rev()
+                        .map(|(day, count)| DayCount { day, count })
+                        .collect();
+                    MonthCount {
+                        month,
+                        count: days.iter().map(|day| day.count).sum(),
+                        days,
+                    }
+                })
+                .collect();
+            YearCount {
+                year,
+                count: months.iter().map(|month| month.count).sum(),
+                months,
+            }
+        })
+        .collect();
+}
+
+/// Photos taken on the same day of the year as `date`, in previous years, grouped by year with
+/// the most recent year first.
+pub fn on_this_day(image_table: &ImageTable, date: NaiveDate) -> Vec<YearPhotos<'_>> {
+    let mut years: BTreeMap<i32, Vec<&Row>> = BTreeMap::new();
+    for row in image_table.rows().iter() {
+        let row_date = row.date();
+        if row_date.year() < date.year()
+            && row_date.month() == date.month()
+            && row_date.day() == date.day()
+        {
+            years.entry(row_date.year()).or_default().push(row);
+        }
+    }
+    return years
+        .into_iter()
+        .rev()
+        .map(|(year, mut rows)| {
+            rows.sort_by_key(|row| sort_key(row));
+            YearPhotos {
+                year,
+                photos: rows.into_iter().map(entry).collect(),
+            }
+        })
+        .collect();
+}
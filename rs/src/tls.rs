@@ -0,0 +1,159 @@
+use super::error::*;
+use super::listen::{Accepted, Incoming, Listener};
+use super::monitor_fs;
+use super::server::Swappable;
+use futures::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{NoClientAuth, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use warp::Filter;
+
+/// PEM files with a certificate chain and its private key.
+#[derive(Clone)]
+pub struct TlsFiles {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+/// How long the certificate and key must be left alone before we reload them, since they are
+/// usually renewed together.
+const RELOAD_DELAY: Duration = Duration::from_millis(500);
+
+/// The longest that a client may take to complete a TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+impl TlsFiles {
+    /// Reads the certificate chain and key. The key may be in PKCS #8 or PKCS #1 format.
+    pub fn load(&self) -> Result<TlsAcceptor, CommandError> {
+        let cert_path = self.cert_path.to_string_lossy();
+        let key_path = self.key_path.to_string_lossy();
+        let certs =
+            std::fs::read(&self.cert_path).map_err(trace(format!("reading {}", cert_path)))?;
+        let certs = pemfile::certs(&mut certs.as_slice())
+            .ok()
+            .filter(|certs| certs.is_empty() == false)
+            .ok_or_else(|| error(format!("no certificates in {}", cert_path)))?;
+        let key = std::fs::read(&self.key_path).map_err(trace(format!("reading {}", key_path)))?;
+        let key = pemfile::pkcs8_private_keys(&mut key.as_slice())
+            .ok()
+            .filter(|keys| keys.is_empty() == false)
+            .or_else(|| pemfile::rsa_private_keys(&mut key.as_slice()).ok())
+            .and_then(|keys| keys.into_iter().next())
+            .ok_or_else(|| error(format!("no private key in {}", key_path)))?;
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        config
+            .set_single_cert(certs, key)
+            .map_err(|err| error(format!("invalid certificate or key: {}", err)))?;
+        return Ok(TlsAcceptor::from(Arc::new(config)));
+    }
+
+    /// Yields whenever the certificate or the key is written. Certificates are often renewed
+    /// by replacing a symbolic link, so we watch the directories that contain the files.
+    fn changes(&self) -> impl Stream<Item = String> + Unpin {
+        let watch = |path: &Path| {
+            let directory = match path.parent() {
+                Some(parent) if parent != Path::new("") => parent.to_path_buf(),
+                _ => PathBuf::from("."),
+            };
+            let name = path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string();
+            return monitor_fs::monitor_files(&directory, vec![name]);
+        };
+        return stream::select(watch(&self.cert_path), watch(&self.key_path));
+    }
+}
+
+/// Reloads the certificate and key whenever they change on disk. If they cannot be read, e.g.,
+/// because only one of them has been replaced so far, we keep using the old ones.
+async fn reload_on_change(files: TlsFiles, acceptor: Arc<Swappable<TlsAcceptor>>) {
+    let mut changes = files.changes();
+    while changes.next().await.is_some() {
+        while let Ok(Some(_)) = tokio::time::timeout(RELOAD_DELAY, changes.next()).await {}
+        match files.load() {
+            Ok(new_acceptor) => {
+                acceptor.set(new_acceptor);
+                eprintln!("Reloaded TLS certificate");
+            }
+            Err(err) => eprintln!("{}\n\nKeeping the previous TLS certificate", err),
+        }
+    }
+}
+
+/// Reads the certificate and key, and reloads them whenever they change.
+pub fn acceptor(files: TlsFiles) -> Result<Arc<Swappable<TlsAcceptor>>, CommandError> {
+    let acceptor = Arc::new(Swappable::new(files.load()?));
+    tokio::spawn(reload_on_change(files, acceptor.clone()));
+    return Ok(acceptor);
+}
+
+/// Performs TLS handshakes on incoming connections. Handshakes happen concurrently, so that a
+/// slow client cannot hold up the others, and a failed handshake only drops its own connection.
+pub fn accept(mut incoming: Incoming, acceptor: Arc<Swappable<TlsAcceptor>>) -> Incoming {
+    let (sender, receiver) = mpsc::channel(32);
+    tokio::spawn(async move {
+        while let Some(Ok(accepted)) = incoming.next().await {
+            let acceptor = acceptor.get();
+            let mut sender = sender.clone();
+            tokio::spawn(async move {
+                let handshake =
+                    tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(accepted.connection));
+                if let Ok(Ok(connection)) = handshake.await {
+                    let connection = Accepted {
+                        connection: Box::new(connection),
+                        remote_addr: accepted.remote_addr,
+                    };
+                    let _ = sender.send(Ok(connection)).await;
+                }
+            });
+        }
+    });
+    return Box::pin(receiver);
+}
+
+/// Redirects plain HTTP requests to the same host and path over HTTPS on `https_port`.
+pub async fn redirect_http(listener: Listener, https_port: u16) {
+    let addr = listener.tcp_addr();
+    let redirect = warp::header::optional::<String>("host")
+        .and(warp::path::full())
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .map(
+            move |host: Option<String>, path: warp::path::FullPath, query: String| {
+                let host = host
+                    .or_else(|| addr.map(|addr| addr.ip().to_string()))
+                    .unwrap_or_else(|| "localhost".to_string());
+                // Removes the port, taking care not to split an IPv6 address.
+                let host = match host.rfind(':') {
+                    Some(colon) if host[colon..].contains(']') == false => {
+                        host[..colon].to_string()
+                    }
+                    _ => host,
+                };
+                let port = if https_port == 443 {
+                    String::new()
+                } else {
+                    format!(":{}", https_port)
+                };
+                let query = if query.is_empty() {
+                    query
+                } else {
+                    format!("?{}", query)
+                };
+                let location = format!("https://{}{}{}{}", host, port, path.as_str(), query);
+                return warp::reply::with_header(
+                    warp::reply::with_status(warp::reply(), http::StatusCode::MOVED_PERMANENTLY),
+                    http::header::LOCATION,
+                    location,
+                );
+            },
+        );
+    warp::serve(redirect)
+        .serve_incoming(listener.incoming())
+        .await;
+}
@@ -0,0 +1,211 @@
+use super::error::*;
+use super::image_table::{self, SimplePhotoGallery};
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// A change to a source directory that we must apply to the catalog.
+enum Change {
+    /// A photo was written, or moved into a source directory, or its sidecar changed.
+    Add(PathBuf),
+    /// A directory was created, or moved into a source directory.
+    AddDirectory(PathBuf),
+    Remove(PathBuf),
+    RemoveDirectory(PathBuf),
+    /// The kernel dropped events, so we must compare every source directory to the catalog.
+    Rescan,
+}
+
+/// Watches source directories recursively, and keeps the catalog in sync with them.
+pub struct Watcher {
+    data_dir: String,
+    roots: Vec<PathBuf>,
+    inotify: Inotify,
+    directories: HashMap<WatchDescriptor, PathBuf>,
+}
+
+fn is_sidecar(path: &Path) -> bool {
+    return matches!(
+        path.extension().and_then(|ext| ext.to_str()),
+        Some("xmp") | Some("XMP")
+    );
+}
+
+/// The photos that a sidecar may describe. See `xmp::sidecar_path` for how sidecars are named.
+fn photos_of_sidecar(sidecar: &Path) -> Vec<PathBuf> {
+    let darktable = sidecar.with_extension("");
+    let entries = match sidecar.parent().map(fs::read_dir) {
+        Some(Ok(entries)) => entries,
+        _ => return vec![],
+    };
+    return entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            return image_table::is_recognized_filename(path)
+                && (*path == darktable || path.file_stem() == sidecar.file_stem());
+        })
+        .collect();
+}
+
+fn watch_mask() -> WatchMask {
+    // We add a photo when it is closed after writing, rather than when it is created, so that we
+    // never read a photo that is only partially written.
+    return WatchMask::CLOSE_WRITE
+        | WatchMask::CREATE
+        | WatchMask::MOVED_TO
+        | WatchMask::MOVED_FROM
+        | WatchMask::DELETE;
+}
+
+impl Watcher {
+    pub fn new(data_dir: &str, roots: &[String]) -> Result<Self, CommandError> {
+        let roots = roots
+            .iter()
+            .map(|root| Path::new(root).canonicalize())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(trace("finding source directories"))?;
+        let mut watcher = Watcher {
+            data_dir: data_dir.to_string(),
+            roots,
+            inotify: Inotify::init().map_err(trace("initializing INotify"))?,
+            directories: HashMap::new(),
+        };
+        for root in watcher.roots.clone() {
+            watcher.watch_tree(&root);
+        }
+        return Ok(watcher);
+    }
+
+    /// Watches a directory and every directory in it. Adding a watch for a directory that we
+    /// already watch is harmless.
+    fn watch_tree(&mut self, directory: &Path) {
+        let directories = WalkDir::new(directory)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_dir());
+        for entry in directories {
+            match self.inotify.add_watch(entry.path(), watch_mask()) {
+                Ok(wd) => {
+                    self.directories.insert(wd, entry.path().to_path_buf());
+                }
+                Err(err) => eprintln!("Could not watch {:?}: {}", entry.path(), err),
+            }
+        }
+    }
+
+    fn changes(&mut self, buffer: &mut [u8]) -> Result<Vec<Change>, CommandError> {
+        let mut changes = vec![];
+        let events = self
+            .inotify
+            .read_events_blocking(buffer)
+            .map_err(trace("reading INotify events"))?;
+        for event in events {
+            if event.mask.contains(EventMask::Q_OVERFLOW) {
+                changes.push(Change::Rescan);
+                continue;
+            }
+            if event.mask.contains(EventMask::IGNORED) {
+                // The directory was deleted, so its watch is gone.
+                self.directories.remove(&event.wd);
+                continue;
+            }
+            let path = match (self.directories.get(&event.wd), event.name) {
+                (Some(directory), Some(name)) => directory.join(name),
+                _ => continue,
+            };
+            let is_dir = event.mask.contains(EventMask::ISDIR);
+            let is_added = event
+                .mask
+                .intersects(EventMask::CREATE | EventMask::MOVED_TO);
+            let is_removed = event
+                .mask
+                .intersects(EventMask::DELETE | EventMask::MOVED_FROM);
+            let is_written = event
+                .mask
+                .intersects(EventMask::CLOSE_WRITE | EventMask::MOVED_TO);
+            if is_dir && is_added {
+                changes.push(Change::AddDirectory(path));
+            } else if is_dir && is_removed {
+                changes.push(Change::RemoveDirectory(path));
+            } else if is_sidecar(&path) {
+                // A photo whose sidecar changes has a new description, so we add it again.
+                if is_written || is_removed {
+                    changes.extend(photos_of_sidecar(&path).into_iter().map(Change::Add));
+                }
+            } else if image_table::is_recognized_filename(&path) == false {
+                continue;
+            } else if is_written {
+                changes.push(Change::Add(path));
+            } else if is_removed {
+                changes.push(Change::Remove(path));
+            }
+        }
+        return Ok(changes);
+    }
+
+    fn apply(&mut self, changes: Vec<Change>) {
+        if changes.is_empty() {
+            return;
+        }
+        // We hold the catalog lock while we apply a batch, so that the web server and the command
+        // line do not change the catalog under us, and save the catalog once, at the end.
+        let mut spg = SimplePhotoGallery::lock(&self.data_dir);
+        // When a photo moves within the source directories, we add it at its new path before we
+        // remove it from its old path, so that its derivatives are kept, just as sync does. A path
+        // that was removed and then written again in the same batch, e.g., by an editor that saves
+        // atomically, exists by now, and we must not remove it after we added it.
+        let (removals, additions): (Vec<Change>, Vec<Change>) = changes
+            .into_iter()
+            .partition(|change| matches!(change, Change::Remove(_) | Change::RemoveDirectory(_)));
+        let removals = removals.into_iter().filter(|change| match change {
+            Change::Remove(path) | Change::RemoveDirectory(path) => path.exists() == false,
+            _ => true,
+        });
+        for change in additions.into_iter().chain(removals) {
+            match change {
+                Change::Add(path) => {
+                    if let Err(err) = spg.add_(&path) {
+                        eprintln!("{}\n\nError adding {}", err, path.display());
+                    }
+                }
+                Change::AddDirectory(path) => {
+                    self.watch_tree(&path);
+                    if let Err(err) = spg.sync_(&path) {
+                        eprintln!("{}\n\nError synchronizing {}", err, path.display());
+                    }
+                }
+                Change::Remove(path) => {
+                    if let Err(err) = spg.rm_(&path) {
+                        eprintln!("{}\n\nError removing {}", err, path.display());
+                    }
+                }
+                Change::RemoveDirectory(path) => spg.rm_directory_(&path),
+                Change::Rescan => self.rescan(&mut spg),
+            }
+        }
+        spg.save();
+    }
+
+    fn rescan(&mut self, spg: &mut SimplePhotoGallery) {
+        for root in self.roots.clone() {
+            self.watch_tree(&root);
+            if let Err(err) = spg.sync_(&root) {
+                eprintln!("{}\n\nError synchronizing {}", err, root.display());
+            }
+        }
+    }
+
+    /// Synchronizes the source directories with the catalog, and then applies changes to them
+    /// as they happen. This only returns if we can no longer read events.
+    pub fn run(&mut self) -> Result<(), CommandError> {
+        self.apply(vec![Change::Rescan]);
+        let mut buffer = [0; 4096];
+        loop {
+            let changes = self.changes(&mut buffer)?;
+            self.apply(changes);
+        }
+    }
+}
@@ -0,0 +1,380 @@
+use img_parts::jpeg::{markers, Jpeg};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub static XMP_PREFIX: &'static [u8] = b"http://ns.adobe.com/xap/1.0/\0";
+pub static IPTC_PREFIX: &'static [u8] = b"Photoshop 3.0\0";
+
+const RDF_NS: &str = "http://www.w3.org/1999/02/22-rdf-syntax-ns#";
+const DC_NS: &str = "http://purl.org/dc/elements/1.1/";
+const XMP_NS: &str = "http://ns.adobe.com/xap/1.0/";
+
+/// Keywords, title, caption and rating, as written by darktable, Lightroom and other tools.
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq)]
+pub struct Description {
+    pub keywords: Vec<String>,
+    pub title: Option<String>,
+    pub caption: Option<String>,
+    /// Star rating from 0 to 5. Lightroom uses -1 for rejected photos.
+    pub rating: Option<i8>,
+}
+
+impl Description {
+    /// Fills in the fields that are missing from `self` with those of `other`. Keywords are
+    /// combined.
+    fn merge(&mut self, other: Description) {
+        for keyword in other.keywords {
+            if self.keywords.contains(&keyword) == false {
+                self.keywords.push(keyword);
+            }
+        }
+        self.title = self.title.take().or(other.title);
+        self.caption = self.caption.take().or(other.caption);
+        self.rating = self.rating.or(other.rating);
+    }
+
+    /// Describes the fields that are present, one per line.
+    pub fn describe(&self) -> Vec<String> {
+        let mut lines = vec![];
+        if let Some(title) = &self.title {
+            lines.push(format!("Title: {}", title));
+        }
+        if let Some(caption) = &self.caption {
+            lines.push(format!("Caption: {}", caption));
+        }
+        if self.keywords.is_empty() == false {
+            lines.push(format!("Keywords: {}", self.keywords.join(", ")));
+        }
+        if let Some(rating) = self.rating {
+            lines.push(format!("Rating: {}", rating));
+        }
+        return lines;
+    }
+}
+
+/// The sidecar of an image, if it has one. darktable names the sidecar of `IMG_1.JPG`
+/// `IMG_1.JPG.xmp`, whereas Lightroom names it `IMG_1.xmp`.
+pub fn sidecar_path(original_path: impl AsRef<Path>) -> Option<PathBuf> {
+    let original_path = original_path.as_ref();
+    let mut darktable = original_path.as_os_str().to_owned();
+    darktable.push(".xmp");
+    let candidates = vec![
+        PathBuf::from(darktable),
+        original_path.with_extension("xmp"),
+        original_path.with_extension("XMP"),
+    ];
+    return candidates.into_iter().find(|path| path.is_file());
+}
+
+/// The text of the first `rdf:li` in `node`, which may be a `rdf:Bag`, `rdf:Seq` or `rdf:Alt`.
+/// In an `rdf:Alt`, we prefer the default language.
+fn first_li(node: roxmltree::Node) -> Option<String> {
+    let items: Vec<_> = node
+        .descendants()
+        .filter(|n| n.has_tag_name((RDF_NS, "li")))
+        .collect();
+    let item = items
+        .iter()
+        .find(|n| {
+            n.attribute(("http://www.w3.org/XML/1998/namespace", "lang")) == Some("x-default")
+        })
+        .or_else(|| items.first())?;
+    let text = item.text()?.trim();
+    if text.is_empty() {
+        return None;
+    }
+    return Some(text.to_string());
+}
+
+/// Parses an XMP packet. XMP properties may be written as elements or as attributes of
+/// `rdf:Description`, so we look for both.
+pub fn parse_xmp(text: &str) -> Option<Description> {
+    let document = roxmltree::Document::parse(text).ok()?;
+    let mut description = Description::default();
+    for node in document.descendants().filter(|n| n.is_element()) {
+        if node.has_tag_name((RDF_NS, "Description")) {
+            if let Some(rating) = node.attribute((XMP_NS, "Rating")) {
+                description.rating = description.rating.or(rating.trim().parse().ok());
+            }
+        } else if node.has_tag_name((DC_NS, "subject")) {
+            for keyword in node
+                .descendants()
+                .filter(|n| n.has_tag_name((RDF_NS, "li")))
+                .filter_map(|n| n.text())
+            {
+                let keyword = keyword.trim().to_string();
+                if keyword.is_empty() == false && description.keywords.contains(&keyword) == false {
+                    description.keywords.push(keyword);
+                }
+            }
+        } else if node.has_tag_name((DC_NS, "title")) {
+            description.title = description.title.or(first_li(node));
+        } else if node.has_tag_name((DC_NS, "description")) {
+            description.caption = description.caption.or(first_li(node));
+        } else if node.has_tag_name((XMP_NS, "Rating")) {
+            description.rating = description
+                .rating
+                .or(node.text().and_then(|t| t.trim().parse().ok()));
+        }
+    }
+    return Some(description);
+}
+
+/// Parses the IPTC-IIM records in the Photoshop image resources of a JPEG. The image resource
+/// with ID 0x0404 holds the records; we read keywords (2:25), object name (2:05) and caption
+/// (2:120).
+fn parse_iptc(resources: &[u8]) -> Option<Description> {
+    let mut pos = 0;
+    let mut iim = None;
+    while pos + 12 <= resources.len() && &resources[pos..pos + 4] == b"8BIM" {
+        let id = u16::from_be_bytes([resources[pos + 4], resources[pos + 5]]);
+        // The name is a Pascal string, padded to an even length.
+        let name_len = resources[pos + 6] as usize;
+        let mut cursor = pos + 6 + ((name_len + 2) & !1);
+        if cursor + 4 > resources.len() {
+            return None;
+        }
+        let size = u32::from_be_bytes([
+            resources[cursor],
+            resources[cursor + 1],
+            resources[cursor + 2],
+            resources[cursor + 3],
+        ]) as usize;
+        cursor += 4;
+        if cursor + size > resources.len() {
+            return None;
+        }
+        if id == 0x0404 {
+            iim = Some(&resources[cursor..cursor + size]);
+            break;
+        }
+        pos = cursor + ((size + 1) & !1);
+    }
+
+    let iim = iim?;
+    let mut description = Description::default();
+    let mut pos = 0;
+    while pos + 5 <= iim.len() && iim[pos] == 0x1C {
+        let (record, dataset) = (iim[pos + 1], iim[pos + 2]);
+        let len = u16::from_be_bytes([iim[pos + 3], iim[pos + 4]]) as usize;
+        // Extended datasets (high bit set) are never used for these fields.
+        if len & 0x8000 != 0 || pos + 5 + len > iim.len() {
+            break;
+        }
+        let value = String::from_utf8_lossy(&iim[pos + 5..pos + 5 + len])
+            .trim()
+            .to_string();
+        if record == 2 && value.is_empty() == false {
+            match dataset {
+                25 => description.keywords.push(value),
+                5 => description.title = description.title.or(Some(value)),
+                120 => description.caption = description.caption.or(Some(value)),
+                _ => (),
+            }
+        }
+        pos += 5 + len;
+    }
+    return Some(description);
+}
+
+/// The boxes in `bytes`, which is an ISO base media file, such as HEIF, or the contents of a box,
+/// as pairs of their type and their contents.
+fn boxes(bytes: &[u8]) -> Vec<(&[u8], &[u8])> {
+    let mut boxes = vec![];
+    let mut fields = Fields { bytes, pos: 0 };
+    while let (Some(size), Some(kind)) = (fields.uint(4), fields.take(4)) {
+        let size = match size {
+            // The last box may extend to the end of the file.
+            0 => (bytes.len() - fields.pos + 8) as u64,
+            1 => match fields.uint(8) {
+                Some(size) => size.saturating_sub(8),
+                None => break,
+            },
+            size => size,
+        };
+        match size
+            .checked_sub(8)
+            .and_then(|len| fields.take(len as usize))
+        {
+            Some(contents) => boxes.push((kind, contents)),
+            None => break,
+        }
+    }
+    return boxes;
+}
+
+/// Reads the big-endian fields of a box in order.
+struct Fields<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let end = self.pos.checked_add(len)?;
+        let field = self.bytes.get(self.pos..end)?;
+        self.pos = end;
+        return Some(field);
+    }
+
+    /// An unsigned integer of `size` bytes, which may be 0.
+    fn uint(&mut self, size: usize) -> Option<u64> {
+        let field = self.take(size)?;
+        return Some(field.iter().fold(0, |n, byte| n << 8 | *byte as u64));
+    }
+
+    /// A string that ends with a NUL.
+    fn string(&mut self) -> Option<&'a [u8]> {
+        let len = self
+            .bytes
+            .get(self.pos..)?
+            .iter()
+            .position(|byte| *byte == 0)?;
+        let string = self.take(len)?;
+        self.pos += 1;
+        return Some(string);
+    }
+
+    /// The version of a full box, after which are its flags.
+    fn version(&mut self) -> Option<u64> {
+        let version = self.uint(1)?;
+        self.take(3)?;
+        return Some(version);
+    }
+}
+
+/// The contents of the first box of type `kind`.
+fn find<'a>(boxes: &[(&[u8], &'a [u8])], kind: &[u8]) -> Option<&'a [u8]> {
+    return boxes
+        .iter()
+        .find(|(other, _)| *other == kind)
+        .map(|(_, contents)| *contents);
+}
+
+/// The XMP packet in a HEIF file. It is an item of type `mime` and content type
+/// `application/rdf+xml`, which the `iinf` box of the `meta` box declares, and the `iloc` box
+/// locates in the file. HEIF has no place for IPTC.
+fn heif_xmp(bytes: &[u8]) -> Option<Vec<u8>> {
+    let meta = find(&boxes(bytes), b"meta")?;
+    let meta = boxes(meta.get(4..)?);
+
+    let mut iinf = Fields {
+        bytes: find(&meta, b"iinf")?,
+        pos: 0,
+    };
+    let version = iinf.version()?;
+    iinf.uint(if version == 0 { 2 } else { 4 })?;
+    let mut xmp_items = vec![];
+    for (kind, infe) in boxes(&iinf.bytes[iinf.pos..]) {
+        if kind != b"infe" {
+            continue;
+        }
+        let mut infe = Fields {
+            bytes: infe,
+            pos: 0,
+        };
+        let version = infe.version()?;
+        if version < 2 {
+            continue;
+        }
+        let id = infe.uint(if version == 2 { 2 } else { 4 })?;
+        infe.uint(2)?;
+        let item_type = infe.take(4)?;
+        infe.string()?;
+        if item_type == b"mime" && infe.string() == Some(b"application/rdf+xml") {
+            xmp_items.push(id);
+        }
+    }
+
+    let mut iloc = Fields {
+        bytes: find(&meta, b"iloc")?,
+        pos: 0,
+    };
+    let version = iloc.version()?;
+    if version > 2 {
+        return None;
+    }
+    let sizes = iloc.uint(1)?;
+    let (offset_size, length_size) = ((sizes >> 4) as usize, (sizes & 15) as usize);
+    let sizes = iloc.uint(1)?;
+    let base_offset_size = (sizes >> 4) as usize;
+    let index_size = if version == 0 {
+        0
+    } else {
+        (sizes & 15) as usize
+    };
+    let id_size = if version < 2 { 2 } else { 4 };
+    for _ in 0..iloc.uint(id_size)? {
+        let id = iloc.uint(id_size)?;
+        // Items may also be constructed from the `idat` box or other items, which XMP never is.
+        let in_file = version == 0 || iloc.uint(2)? & 15 == 0;
+        iloc.uint(2)?;
+        let base_offset = iloc.uint(base_offset_size)?;
+        let mut item = vec![];
+        for _ in 0..iloc.uint(2)? {
+            iloc.uint(index_size)?;
+            let offset = base_offset.checked_add(iloc.uint(offset_size)?)? as usize;
+            let length = iloc.uint(length_size)? as usize;
+            item.extend_from_slice(bytes.get(offset..offset.checked_add(length)?)?);
+        }
+        if in_file && xmp_items.contains(&id) {
+            return Some(item);
+        }
+    }
+    return None;
+}
+
+/// Reads the XMP and IPTC metadata embedded in a JPEG, or the XMP embedded in a HEIC file.
+fn read_embedded(original_path: impl AsRef<Path>) -> Description {
+    let mut description = Description::default();
+    let bytes = match fs::read(original_path) {
+        Ok(bytes) => bytes,
+        Err(_) => return description,
+    };
+    // A HEIF file starts with its `ftyp` box.
+    if bytes.get(4..8) == Some(b"ftyp") {
+        let xmp = heif_xmp(&bytes).map(|xmp| String::from_utf8_lossy(&xmp).to_string());
+        return xmp.as_deref().and_then(parse_xmp).unwrap_or_default();
+    }
+    let jpeg = match Jpeg::from_bytes(bytes.into()) {
+        Ok(jpeg) => jpeg,
+        Err(_) => return description,
+    };
+    for segment in jpeg.segments_by_marker(markers::APP1) {
+        let contents = segment.contents();
+        if contents.starts_with(XMP_PREFIX) {
+            let text = String::from_utf8_lossy(&contents[XMP_PREFIX.len()..]);
+            if let Some(xmp) = parse_xmp(&text) {
+                description.merge(xmp);
+            }
+        }
+    }
+    for segment in jpeg.segments_by_marker(markers::APP13) {
+        let contents = segment.contents();
+        if contents.starts_with(IPTC_PREFIX) {
+            if let Some(iptc) = parse_iptc(&contents[IPTC_PREFIX.len()..]) {
+                description.merge(iptc);
+            }
+        }
+    }
+    return description;
+}
+
+/// Reads the description of an image. The sidecar takes precedence over embedded XMP, which takes
+/// precedence over IPTC, since that is the order in which editors update them.
+pub fn read_description(original_path: impl AsRef<Path>) -> Description {
+    let mut description = Description::default();
+    if let Some(sidecar) = sidecar_path(&original_path) {
+        match fs::read_to_string(&sidecar)
+            .ok()
+            .as_deref()
+            .and_then(parse_xmp)
+        {
+            Some(xmp) => description.merge(xmp),
+            None => eprintln!("Could not read XMP sidecar {}", sidecar.display()),
+        }
+    }
+    description.merge(read_embedded(original_path));
+    return description;
+}
@@ -6,26 +6,45 @@ type GalleryImage = {
     'webview_path': string,
     'original_path': string
     'md5': string,
+    // Present when the image is the cover of a stack of burst shots
+    'members'?: GalleryImage[],
+}
+
+// A directory gallery or an album
+type Gallery = {
+    'name': string,
+    'kind': 'directory' | 'album' | 'smart_album'
 }
 
 // Viewing an entire gallery
 type GalleryView = {
     'kind': 'gallery',
-    'name': string,
-    'images': GalleryImage[]
+    'gallery': Gallery,
+    'images': GalleryImage[],
+    // True when showing the members of a single stack
+    'expanded'?: boolean,
+    // Present when there are more images to load
+    'next_cursor'?: string
+}
+
+// A page of a directory gallery
+type GalleryPage = {
+    'total': number,
+    'next_cursor': string | null,
+    'photos': GalleryImage[]
 }
 
 // Viewing a single image in a particular gallery
 type ImageView = {
     kind: 'image',
-    gallery: string,
+    gallery: Gallery,
     image: GalleryImage
 }
 
 // Viewing the list of all galleries
 type HomeView = {
     kind: 'home',
-    galleries: string[]
+    galleries: Gallery[]
 };
 
 // Initial view, before the list of galleries is loaded
@@ -33,12 +52,36 @@ type InitView = {
     kind: 'init'
 }
 
+// Shown when the server requires logging in
+type LoginView = {
+    kind: 'login',
+    failed: boolean
+}
+
 type ErrorView = {
     kind: 'error',
     message: string
 }
 
-type View = InitView | HomeView | GalleryView | ImageView | ErrorView;
+// A change to the catalog, pushed by the server. We only need some fields of photo events.
+type CatalogEvent =
+    { 'type': 'added' | 'updated' | 'removed', 'gallery': string, 'md5': string } |
+    { 'type': 'gallery_changed', 'name': string } |
+    { 'type': 'resync' };
+
+type View = InitView | HomeView | GalleryView | ImageView | LoginView | ErrorView;
+
+// Thrown when the server requires logging in.
+class Unauthorized extends Error { }
+
+// Like fetch, but throws Unauthorized when the server requires logging in.
+async function apiFetch(url: string, init?: RequestInit): Promise<Response> {
+    let resp = await fetch(url, init);
+    if (resp.status === 401) {
+        throw new Unauthorized();
+    }
+    return resp;
+}
 
 type State = {
     view: View
@@ -56,51 +99,193 @@ class Index extends React.Component<{}, State> {
         this.handleAsyncError(this.fetchGalleryList());
     }
 
+    events: EventSource | undefined = undefined;
+
+    // Listens for changes to the catalog. We wait until we can list the galleries, since the
+    // browser gives up on an event stream that the server refuses.
+    listenForEvents() {
+        if (this.events !== undefined) {
+            return;
+        }
+        this.events = new EventSource('api/events');
+        this.events.onmessage = (event: MessageEvent) => this.onCatalogEvent(JSON.parse(event.data));
+    }
+
+    async login(user: string, password: string): Promise<void> {
+        let resp = await fetch('api/login', {
+            method: 'POST',
+            headers: { 'Content-Type': 'application/json' },
+            body: JSON.stringify({ user: user, password: password })
+        });
+        if (resp.status === 401) {
+            this.setState({ view: { kind: 'login', failed: true } });
+            return;
+        }
+        await this.fetchGalleryList();
+    }
+
+    // Refreshes the current view when the catalog changes on the server, e.g., while
+    // `spg sync` is adding photos.
+    onCatalogEvent(event: CatalogEvent) {
+        if (event.type !== 'gallery_changed' && event.type !== 'resync') {
+            return;
+        }
+        let view = this.state.view;
+        if (view.kind === 'home') {
+            this.handleAsyncError(this.refreshGalleryList());
+        }
+        else if (view.kind === 'gallery' && view.expanded !== true &&
+            (event.type === 'resync' || event.name === view.gallery.name)) {
+            this.handleAsyncError(this.refreshGallery(view));
+        }
+    }
+
+    async refreshGalleryList(): Promise<void> {
+        let resp = await apiFetch('api/list_galleries');
+        let body: Gallery[] = await resp.json();
+        this.setState({ view: { kind: 'home', galleries: body } });
+    }
+
+    // Fetches the first page of a gallery again, without adding to the browser history.
+    async refreshGallery(view: GalleryView): Promise<void> {
+        if (view.gallery.kind !== 'directory') {
+            return;
+        }
+        let page = await this.fetchGalleryPage(view.gallery, undefined);
+        this.setState({ view: {
+            kind: 'gallery',
+            gallery: view.gallery,
+            images: page.photos,
+            next_cursor: page.next_cursor || undefined
+        } });
+    }
+
     // Shows an error message to the user when an asynchronous operation goes
     // wrong. The user can use the Back button to revert to the previous state.
     handleAsyncError(f: Promise<void>) {
         f.then(() => { })
         .catch(exn => {
+            if (exn instanceof Unauthorized) {
+                this.setState({ view: { kind: 'login', failed: false } });
+                return;
+            }
             window.history.pushState(this.state, '');
             this.setState({ view: { kind: 'error', message: String(exn) } }); 
         });
     }
 
     async fetchGalleryList(): Promise<void> {
-        let resp = await fetch('api/list_galleries');
-        let body: string[] = await resp.json();
+        let resp = await apiFetch('api/list_galleries');
+        let body: Gallery[] = await resp.json();
+        this.listenForEvents();
         window.history.pushState(this.state, '');
         this.setState({ view: { kind: 'home', galleries: body } });
     }
 
-    async fetchGallery(name: string): Promise<void> {
-        let resp = await fetch('api/gallery_contents', {
+    async fetchGallery(gallery: Gallery): Promise<void> {
+        if (gallery.kind !== 'directory') {
+            let resp = await apiFetch('api/album_contents', {
+                method: 'POST',
+                headers: {
+                    'Content-Type': 'application/json'
+                },
+                body: JSON.stringify(gallery.name)
+            });
+            let body: GalleryImage[] = await resp.json();
+            window.history.pushState(this.state, '');
+            this.setState({ view: { kind: 'gallery', gallery: gallery, images: body } });
+            return;
+        }
+        let page = await this.fetchGalleryPage(gallery, undefined);
+        window.history.pushState(this.state, '');
+        this.setState({ view: {
+            kind: 'gallery',
+            gallery: gallery,
+            images: page.photos,
+            next_cursor: page.next_cursor || undefined
+        } });
+    }
+
+    async fetchGalleryPage(gallery: Gallery, cursor: string | undefined): Promise<GalleryPage> {
+        let url = 'api/gallery_contents?stack=true';
+        if (cursor !== undefined) {
+            url += '&cursor=' + encodeURIComponent(cursor);
+        }
+        let resp = await apiFetch(url, {
             method: 'POST',
             headers: {
                 'Content-Type': 'application/json'
             },
-            body: JSON.stringify(name)
+            body: JSON.stringify(gallery.name)
         });
-        let body: GalleryImage[] = await resp.json();
-        window.history.pushState(this.state, '');
-        this.setState({ view: { kind: 'gallery', name: name, images: body } });
+        return await resp.json();
+    }
+
+    // Appends the next page of a directory gallery to the current view.
+    async loadMore(view: GalleryView): Promise<void> {
+        let page = await this.fetchGalleryPage(view.gallery, view.next_cursor);
+        this.setState({ view: {
+            kind: 'gallery',
+            gallery: view.gallery,
+            images: view.images.concat(page.photos),
+            next_cursor: page.next_cursor || undefined
+        } });
+    }
+
+    // Shows a link that lets anyone see a directory gallery for a week, without logging in.
+    async shareGallery(gallery: Gallery): Promise<void> {
+        let resp = await apiFetch('api/create_share', {
+            method: 'POST',
+            headers: { 'Content-Type': 'application/json' },
+            body: JSON.stringify({ gallery: gallery.name, expires: '7d' })
+        });
+        let body: { url: string } = await resp.json();
+        window.prompt('Anyone with this link can see the gallery for a week:', body.url);
     }
 
-    onViewImage(image: GalleryImage, gallery: string) {
+    onViewImage(image: GalleryImage, gallery: Gallery) {
         window.history.pushState(this.state, '');
         this.setState({ view: { kind: 'image', gallery: gallery, image: image } });
     }
 
-    makeThumbnail(gallery: string, image: GalleryImage) {
+    onExpandStack(stack: GalleryImage[], gallery: Gallery) {
+        window.history.pushState(this.state, '');
+        this.setState({ view: { kind: 'gallery', gallery: gallery, images: stack, expanded: true } });
+    }
+
+    async chooseCover(image: GalleryImage, gallery: Gallery): Promise<void> {
+        await apiFetch('api/stack_cover', {
+            method: 'POST',
+            headers: {
+                'Content-Type': 'application/json'
+            },
+            body: JSON.stringify(image.md5)
+        });
+        await this.fetchGallery(gallery);
+    }
+
+    makeThumbnail(gallery: Gallery, image: GalleryImage, expanded: boolean) {
+        let members = image.members;
+        if (members !== undefined) {
+            let stack = members;
+            return <div className="thumbnail stack">
+                <img src={`photos/${image.thumbnail_path}`}
+                    onClick={() => this.onExpandStack(stack, gallery)}></img>
+                <div>{stack.length} photos</div>
+                </div>;
+        }
         return <div className="thumbnail">
             <img src={`photos/${image.thumbnail_path}`}
                 onClick={() => this.onViewImage(image, gallery)}></img>
+            {expanded ?
+                <div><a href="#" onClick={() => this.handleAsyncError(this.chooseCover(image, gallery))}>Use as cover</a></div> :
+                ''}
             </div>;
     }
 
     renderImage(image: ImageView) {
         return (<div>
-            <h1>{image.gallery}</h1>
+            <h1>{image.gallery.name}</h1>
                 <div>
                     <a href="#" onClick={() => this.handleAsyncError(this.fetchGallery(image.gallery))}>Return to gallery</a>
                 </div>
@@ -113,20 +298,46 @@ class Index extends React.Component<{}, State> {
     }
 
     renderGallery(gallery: GalleryView) {
-        let thumbnails = gallery.images.map(image => this.makeThumbnail(gallery.name, image));
+        let thumbnails = gallery.images.map(image =>
+            this.makeThumbnail(gallery.gallery, image, gallery.expanded === true));
         return (<div>
-            <h1>{gallery.name}</h1>
+            <h1>{gallery.gallery.name}</h1>
             <a href="#" onClick={() => this.handleAsyncError(this.fetchGalleryList())}>Home</a>
+            {gallery.gallery.kind === 'directory' ?
+                <span> | <a href="#" onClick={() => this.handleAsyncError(this.shareGallery(gallery.gallery))}>Share</a></span> :
+                ''}
             <br/>
             {thumbnails}
+            {gallery.next_cursor !== undefined ?
+                <div><a href="#" onClick={() => this.handleAsyncError(this.loadMore(gallery))}>Load more</a></div> :
+                ''}
             </div>
             );
     }
 
+    renderLogin(view: LoginView) {
+        let user: HTMLInputElement | null = null;
+        let password: HTMLInputElement | null = null;
+        let submit = (event: React.FormEvent) => {
+            event.preventDefault();
+            this.handleAsyncError(this.login(user!.value, password!.value));
+        };
+        return (<form onSubmit={submit}>
+            <h1>Log In</h1>
+            {view.failed ? <div>Wrong user name or password.</div> : ''}
+            <div><input type="text" placeholder="User" ref={elt => user = elt}/></div>
+            <div><input type="password" placeholder="Password" ref={elt => password = elt}/></div>
+            <button type="submit">Log in</button>
+            </form>);
+    }
+
     render() {
         if (this.state.view.kind === 'init') {
             return (<div>Loading ...</div>);
         }
+        else if (this.state.view.kind === 'login') {
+            return this.renderLogin(this.state.view);
+        }
         else if (this.state.view.kind === 'error') {
             return (<div>
                 <h1>Something Went Wrong</h1>
@@ -135,8 +346,9 @@ class Index extends React.Component<{}, State> {
                 </div>);
         }
         else if (this.state.view.kind === 'home') {
-            let links = this.state.view.galleries.map(name => 
-                <li> <a href="#" onClick={() => this.handleAsyncError(this.fetchGallery(name))}>{name}</a></li>);
+            let links = this.state.view.galleries.map(gallery => 
+                <li> <a href="#" onClick={() => this.handleAsyncError(this.fetchGallery(gallery))}>{gallery.name}</a>
+                    {gallery.kind === 'directory' ? '' : ' (album)'}</li>);
             return (<div>
                 <h1>Home</h1>
                 <ul>{links}</ul>